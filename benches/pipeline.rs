@@ -0,0 +1,141 @@
+//! Benchmarks for the CSV reader, the client account operations and the
+//! whole pipeline.
+//!
+//! The 10M rows pipeline case is only run when `TOY_PAYMENTS_BENCH_FULL` is set,
+//! e.g. `TOY_PAYMENTS_BENCH_FULL=1 cargo bench`.
+use std::env;
+use std::hint::black_box;
+use std::io::Write;
+use std::sync::mpsc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rust_decimal::Decimal;
+use tempfile::NamedTempFile;
+use tokio::runtime::Runtime;
+use toy_payments::config::{EngineConfig, Policies};
+use toy_payments::csv::reader::{self, ReadOptions};
+use toy_payments::processors::pipeline::{self, Ledgers};
+use toy_payments::structs::clients::ClientAccount;
+
+const CLIENTS: u16 = 1000;
+
+// Writes a transactions CSV, `dispute_rate` is the share of rows that are
+// disputes or resolves of earlier deposits
+fn workload(rows: u64, dispute_rate: f64) -> NamedTempFile {
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut file = NamedTempFile::new().unwrap();
+    let mut deposits: Vec<(u32, u16)> = vec![];
+    let mut disputed: Vec<(u32, u16)> = vec![];
+    writeln!(file, "type,client,tx,amount").unwrap();
+    for tx in 1..=rows as u32 {
+        let client = rng.random_range(1..=CLIENTS);
+        let amount = Decimal::new(rng.random_range(1..=10_000_000), 4);
+        if !deposits.is_empty() && rng.random_bool(dispute_rate) {
+            if !disputed.is_empty() && rng.random_bool(0.5) {
+                let (id, owner) = disputed.swap_remove(rng.random_range(0..disputed.len()));
+                writeln!(file, "resolve,{owner},{id},").unwrap();
+            } else {
+                let (id, owner) = deposits.swap_remove(rng.random_range(0..deposits.len()));
+                disputed.push((id, owner));
+                writeln!(file, "dispute,{owner},{id},").unwrap();
+            }
+        } else if rng.random_bool(0.3) {
+            writeln!(file, "withdrawal,{client},{tx},{amount}").unwrap();
+        } else {
+            deposits.push((tx, client));
+            writeln!(file, "deposit,{client},{tx},{amount}").unwrap();
+        }
+    }
+    file.flush().unwrap();
+    file
+}
+
+fn path(file: &NamedTempFile) -> String {
+    file.path().to_string_lossy().into_owned()
+}
+
+fn bench_reader(c: &mut Criterion) {
+    let rows = 100_000;
+    let file = workload(rows, 0.05);
+    let mut group = c.benchmark_group("reader");
+    group.throughput(Throughput::Elements(rows));
+    group.bench_function("parse_100k", |b| {
+        b.iter(|| {
+            let (tx_channel, rx_channel) = mpsc::channel();
+            reader::read(tx_channel, &[path(&file)], &ReadOptions::default()).unwrap();
+            black_box(rx_channel.try_iter().count())
+        })
+    });
+    group.finish();
+}
+
+fn bench_client_account(c: &mut Criterion) {
+    let amount = Decimal::new(12_3456, 4);
+    let policies = Policies::default();
+    let mut group = c.benchmark_group("client_account");
+    group.bench_function("deposit", |b| {
+        let mut account = ClientAccount::new(1);
+        b.iter(|| account.deposit(black_box(amount), &policies))
+    });
+    group.bench_function("withdrawal", |b| {
+        let mut account = ClientAccount::new(1);
+        account
+            .deposit(Decimal::MAX / Decimal::TWO, &policies)
+            .unwrap();
+        b.iter(|| account.withdrawal(black_box(amount), &policies))
+    });
+    group.bench_function("dispute_resolve", |b| {
+        let mut account = ClientAccount::new(1);
+        account.deposit(amount, &policies).unwrap();
+        b.iter(|| {
+            account.dispute(black_box(amount), &policies).unwrap();
+            account.resolve(black_box(amount))
+        })
+    });
+    group.finish();
+}
+
+fn bench_pipeline(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut sizes = vec![1_000_000];
+    if env::var_os("TOY_PAYMENTS_BENCH_FULL").is_some() {
+        sizes.push(10_000_000);
+    }
+
+    let mut group = c.benchmark_group("pipeline");
+    group.sample_size(10);
+    for rows in sizes {
+        let file = workload(rows, 0.05);
+        group.throughput(Throughput::Elements(rows));
+        group.bench_with_input(BenchmarkId::new("end_to_end", rows), &file, |b, file| {
+            b.iter(|| {
+                runtime.block_on(pipeline::run(
+                    vec![path(file)],
+                    &Ledgers::new(false),
+                    ReadOptions::default(),
+                    &EngineConfig::default(),
+                ))
+            })
+        });
+    }
+
+    let rows = 1_000_000;
+    let file = workload(rows, 0.4);
+    group.throughput(Throughput::Elements(rows));
+    group.bench_with_input(BenchmarkId::new("high_dispute", rows), &file, |b, file| {
+        b.iter(|| {
+            runtime.block_on(pipeline::run(
+                vec![path(file)],
+                &Ledgers::new(false),
+                ReadOptions::default(),
+                &EngineConfig::default(),
+            ))
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_reader, bench_client_account, bench_pipeline);
+criterion_main!(benches);
@@ -0,0 +1,454 @@
+//! Synthetic transaction generator.
+//!
+//! Writes a transaction stream in the CSV format consumed by the engine,
+//! and, optionally, the final client balances expected for that stream,
+//! computed by a reference model that does not share code with the engine.
+//! Both outputs can be compared with `toy_payments reconcile`.
+//! Malformed rows are only generated on request: the engine rejects the bad
+//! amounts, has to skip the rows missing columns (`--on-error skip`), and
+//! fails its run on an unknown type.
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Write};
+
+use clap::Parser;
+use csv::WriterBuilder;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Generates synthetic transaction streams")]
+struct Args {
+    /// Number of rows to generate
+    #[arg(long, default_value_t = 10_000)]
+    rows: u64,
+    /// Number of distinct clients
+    #[arg(long, default_value_t = 100)]
+    clients: u16,
+    /// Share of money movements that are withdrawals, the rest are deposits
+    #[arg(long, default_value_t = 0.3)]
+    withdrawal_ratio: f64,
+    /// Share of rows that are disputes, resolves or chargebacks
+    #[arg(long, default_value_t = 0.05)]
+    dispute_rate: f64,
+    /// Share of settled disputes that are resolved, the rest are charged back
+    #[arg(long, default_value_t = 0.95)]
+    resolve_ratio: f64,
+    /// Share of rows unlocking an account locked by a chargeback, while
+    /// there is one
+    #[arg(long, default_value_t = 0.01)]
+    unlock_rate: f64,
+    /// Share of rows the business rules reject: references to unknown
+    /// transactions, disputes by the wrong client and overdrafts
+    #[arg(long, default_value_t = 0.01)]
+    rejected_rate: f64,
+    /// Share of malformed rows: bad amounts, missing columns and unknown
+    /// transaction types
+    #[arg(long, default_value_t = 0.0)]
+    invalid_rate: f64,
+    /// Seed of the random generator, the same seed always yields the same stream
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Transactions CSV output path, defaults to STDOUT
+    #[arg(long)]
+    output: Option<String>,
+    /// Path where the expected client balances are written
+    #[arg(long)]
+    expected: Option<String>,
+}
+
+// Columns of the rows
+const HEADER: [&str; 6] = ["type", "client", "tx", "amount", "reason_code", "operator"];
+
+// Row in the format read by the engine
+#[derive(Serialize, Debug)]
+struct Row {
+    #[serde(rename = "type")]
+    tx_type: &'static str,
+    client: u16,
+    tx: u32,
+    amount: Option<Decimal>,
+    // Reason code and operator of the account unlocks
+    reason_code: Option<&'static str>,
+    operator: Option<&'static str>,
+}
+
+// Line of the stream, a row or a record the engine can't parse
+enum Line {
+    Row(Row),
+    Malformed(Vec<String>),
+}
+
+// Expected balances, in the format written by the engine
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+struct Balance {
+    client: u16,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    #[serde(rename = "status", serialize_with = "status")]
+    locked: bool,
+}
+
+// Account status column of the engine, the model only locks accounts
+fn status<S: serde::Serializer>(locked: &bool, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(if *locked { "locked" } else { "active" })
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Movement {
+    client: u16,
+    amount: Decimal,
+    // A disputed movement can't be disputed again until it is resolved
+    disputed: bool,
+    // A charged back movement can't be disputed again
+    charged_back: bool,
+}
+
+// Reference model of the engine rules
+#[derive(Default)]
+struct Model {
+    accounts: BTreeMap<u16, Balance>,
+    movements: HashMap<u32, Movement>,
+}
+
+impl Model {
+    fn apply(&mut self, row: &Row) {
+        let amount = row.amount.unwrap_or_default();
+        match row.tx_type {
+            "deposit" | "withdrawal" => {
+                // A withdrawal can't succeed on an empty account, so it opens none
+                if row.tx_type == "withdrawal" && !self.accounts.contains_key(&row.client) {
+                    return;
+                }
+                let account = self.accounts.entry(row.client).or_insert(Balance {
+                    client: row.client,
+                    ..Balance::default()
+                });
+                let applied = match row.tx_type {
+                    _ if account.locked => false,
+                    "deposit" => {
+                        account.available += amount;
+                        true
+                    }
+                    _ if account.available >= amount => {
+                        account.available -= amount;
+                        true
+                    }
+                    _ => false,
+                };
+                // Only the applied movements are kept, a rejected one can't be disputed
+                if applied {
+                    self.movements.insert(
+                        row.tx,
+                        Movement {
+                            client: row.client,
+                            amount,
+                            disputed: false,
+                            charged_back: false,
+                        },
+                    );
+                }
+            }
+            "unlock" => {
+                if let Some(account) = self.accounts.get_mut(&row.client) {
+                    account.locked = false;
+                }
+            }
+            kind => {
+                let Some(movement) = self.movements.get_mut(&row.tx) else {
+                    return;
+                };
+                let Some(account) = self.accounts.get_mut(&row.client) else {
+                    return;
+                };
+                if movement.client != row.client || account.locked {
+                    return;
+                }
+                match kind {
+                    "dispute"
+                        if !movement.disputed
+                            && !movement.charged_back
+                            && account.available >= movement.amount =>
+                    {
+                        account.available -= movement.amount;
+                        account.held += movement.amount;
+                        movement.disputed = true;
+                    }
+                    "resolve" if movement.disputed && account.held >= movement.amount => {
+                        account.available += movement.amount;
+                        account.held -= movement.amount;
+                        movement.disputed = false;
+                    }
+                    "chargeback" if movement.disputed && account.held >= movement.amount => {
+                        account.held -= movement.amount;
+                        account.locked = true;
+                        movement.disputed = false;
+                        movement.charged_back = true;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn balances(&self) -> impl Iterator<Item = Balance> + '_ {
+        self.accounts.values().map(|balance| Balance {
+            total: balance.available + balance.held,
+            ..*balance
+        })
+    }
+}
+
+// Produces the rows, keeping track of the deposits that can be disputed
+struct Generator {
+    args: Args,
+    rng: StdRng,
+    next_tx: u32,
+    deposits: Vec<(u32, u16)>,
+    open_disputes: Vec<(u32, u16)>,
+    // Clients whose account a chargeback locked, until they are unlocked
+    locked: Vec<u16>,
+}
+
+impl Generator {
+    fn new(args: Args) -> Generator {
+        Generator {
+            rng: StdRng::seed_from_u64(args.seed),
+            args,
+            next_tx: 1,
+            deposits: vec![],
+            open_disputes: vec![],
+            locked: vec![],
+        }
+    }
+
+    fn next_line(&mut self) -> Line {
+        let roll: f64 = self.rng.random();
+        if roll < self.args.invalid_rate {
+            return Line::Malformed(self.malformed());
+        }
+        Line::Row(self.next_row(roll - self.args.invalid_rate))
+    }
+
+    fn next_row(&mut self, roll: f64) -> Row {
+        if !self.locked.is_empty() && self.rng.random_bool(self.args.unlock_rate) {
+            let client = self
+                .locked
+                .swap_remove(self.rng.random_range(0..self.locked.len()));
+            return Row {
+                reason_code: Some("review"),
+                operator: Some("ops"),
+                ..row("unlock", client, 0, None)
+            };
+        }
+        if (0.0..self.args.rejected_rate).contains(&roll) {
+            self.rejected()
+        } else if roll < self.args.rejected_rate + self.args.dispute_rate
+            && !self.deposits.is_empty()
+        {
+            self.dispute_flow()
+        } else {
+            self.movement()
+        }
+    }
+
+    fn movement(&mut self) -> Row {
+        let client = self.rng.random_range(1..=self.args.clients);
+        let tx = self.new_tx();
+        // Amounts with four decimal places, withdrawals are smaller than deposits
+        if self.rng.random_bool(self.args.withdrawal_ratio) {
+            let amount = Decimal::new(self.rng.random_range(1..=5_000_000), 4);
+            return row("withdrawal", client, tx, Some(amount));
+        }
+        let amount = Decimal::new(self.rng.random_range(1..=10_000_000), 4);
+        self.deposits.push((tx, client));
+        row("deposit", client, tx, Some(amount))
+    }
+
+    fn dispute_flow(&mut self) -> Row {
+        if !self.open_disputes.is_empty() && self.rng.random_bool(0.5) {
+            let index = self.rng.random_range(0..self.open_disputes.len());
+            let (tx, client) = self.open_disputes.swap_remove(index);
+            let kind = if self.rng.random_bool(self.args.resolve_ratio) {
+                "resolve"
+            } else {
+                if !self.locked.contains(&client) {
+                    self.locked.push(client);
+                }
+                "chargeback"
+            };
+            return row(kind, client, tx, None);
+        }
+        let index = self.rng.random_range(0..self.deposits.len());
+        let (tx, client) = self.deposits.swap_remove(index);
+        self.open_disputes.push((tx, client));
+        row("dispute", client, tx, None)
+    }
+
+    fn rejected(&mut self) -> Row {
+        let client = self.rng.random_range(1..=self.args.clients);
+        match self.rng.random_range(0..3) {
+            // Reference to a transaction that does not exist
+            0 => {
+                let kind = ["dispute", "resolve", "chargeback"][self.rng.random_range(0..3)];
+                row(
+                    kind,
+                    client,
+                    u32::MAX - self.rng.random_range(0..1000),
+                    None,
+                )
+            }
+            // Dispute of a transaction of another client
+            1 if !self.deposits.is_empty() => {
+                let (tx, owner) = self.deposits[self.rng.random_range(0..self.deposits.len())];
+                row("dispute", owner.wrapping_add(1).max(1), tx, None)
+            }
+            // Withdrawal above any possible balance
+            _ => {
+                let tx = self.new_tx();
+                row("withdrawal", client, tx, Some(Decimal::new(i64::MAX, 4)))
+            }
+        }
+    }
+
+    fn malformed(&mut self) -> Vec<String> {
+        let client = self.rng.random_range(1..=self.args.clients).to_string();
+        let tx = self.new_tx().to_string();
+        let fields: Vec<&str> = match self.rng.random_range(0..3) {
+            // Amount that isn't a number
+            0 => vec!["deposit", &client, &tx, "12.3.4", "", ""],
+            // Missing columns
+            1 => vec!["withdrawal", &client],
+            // Unknown transaction type
+            _ => vec!["transfer", &client, &tx, "10.0", "", ""],
+        };
+        fields.into_iter().map(str::to_string).collect()
+    }
+
+    fn new_tx(&mut self) -> u32 {
+        let tx = self.next_tx;
+        self.next_tx += 1;
+        tx
+    }
+}
+
+fn row(tx_type: &'static str, client: u16, tx: u32, amount: Option<Decimal>) -> Row {
+    Row {
+        tx_type,
+        client,
+        tx,
+        amount,
+        reason_code: None,
+        operator: None,
+    }
+}
+
+fn writer(path: &Option<String>) -> io::Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    })
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let rows = args.rows;
+    let output = args.output.clone();
+    let expected = args.expected.clone();
+
+    let mut generator = Generator::new(args);
+    let mut model = Model::default();
+    // Malformed records have fewer columns, so the header is written apart
+    let mut wtr = WriterBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_writer(writer(&output)?);
+    wtr.write_record(HEADER)?;
+    for _ in 0..rows {
+        match generator.next_line() {
+            Line::Row(row) => {
+                model.apply(&row);
+                wtr.serialize(&row)?;
+            }
+            // The engine skips them, they don't change the balances
+            Line::Malformed(fields) => wtr.write_record(&fields)?,
+        }
+    }
+    wtr.flush()?;
+
+    if expected.is_some() {
+        let mut wtr = csv::Writer::from_writer(writer(&expected)?);
+        for balance in model.balances() {
+            wtr.serialize(balance)?;
+        }
+        wtr.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_model_dispute_flow() {
+        let mut model = Model::default();
+        model.apply(&row("deposit", 1, 1, Some(Decimal::new(10, 0))));
+        model.apply(&row("deposit", 1, 2, Some(Decimal::new(5, 0))));
+        model.apply(&row("withdrawal", 1, 3, Some(Decimal::new(20, 0))));
+        model.apply(&row("dispute", 2, 1, None));
+        model.apply(&row("dispute", 1, 1, None));
+        model.apply(&row("chargeback", 1, 1, None));
+        model.apply(&row("deposit", 1, 4, Some(Decimal::new(1, 0))));
+        let balance = model.balances().next().unwrap();
+        assert_eq!(balance.available, Decimal::new(5, 0));
+        assert_eq!(balance.held, Decimal::ZERO);
+        assert_eq!(balance.total, Decimal::new(5, 0));
+        assert!(balance.locked);
+        model.apply(&row("unlock", 1, 0, None));
+        model.apply(&row("dispute", 1, 1, None));
+        let balance = model.balances().next().unwrap();
+        assert_eq!(balance.available, Decimal::new(5, 0));
+        assert!(!balance.locked);
+    }
+
+    #[test]
+    fn test_generator_mix() {
+        let args = Args::parse_from(["generate", "--invalid-rate", "0.05"]);
+        let mut generator = Generator::new(args);
+        let mut model = Model::default();
+        let mut malformed = vec![];
+        for _ in 0..10_000 {
+            match generator.next_line() {
+                Line::Row(row) => model.apply(&row),
+                Line::Malformed(fields) => malformed.push(fields),
+            }
+        }
+        assert!(malformed.iter().any(|fields| fields[0] == "transfer"));
+        assert!(malformed.iter().any(|fields| fields.len() < HEADER.len()));
+        assert!(malformed
+            .iter()
+            .any(|fields| fields.get(3).is_some_and(|amount| amount == "12.3.4")));
+        let locked = model.balances().filter(|balance| balance.locked).count();
+        assert!(locked * 10 < model.accounts.len());
+    }
+
+    #[test]
+    fn test_generator_is_deterministic() {
+        let args = || Args::parse_from(["generate", "--rows", "200", "--seed", "7"]);
+        let (mut first, mut second) = (Generator::new(args()), Generator::new(args()));
+        for _ in 0..200 {
+            let (Line::Row(a), Line::Row(b)) = (first.next_line(), second.next_line()) else {
+                panic!("malformed row generated by default");
+            };
+            assert_eq!(
+                (a.tx_type, a.client, a.tx, a.amount),
+                (b.tx_type, b.client, b.tx, b.amount)
+            );
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use toy_payments::config::InvariantCheck;
+use toy_payments::csv::{
+    reader::{CSVReaderError, ErrorPolicy, ReadOptions},
+    writer::{OutputFormat, SortKey},
+};
+
+const EXIT_CODES: &str = "\
+Exit codes:
+  0  Success
+  1  Completed, but transactions were rejected by the business rules,
+     or the reconciled files differ
+  2  Invalid command line or configuration file
+  3  Invalid input: malformed rows, unknown transaction types
+  4  I/O error: an input can't be read or an output can't be written";
+
+// Command line interface definition
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Toy payments engine",
+    after_help = EXIT_CODES,
+    args_conflicts_with_subcommands = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// CSV files, directories or glob patterns holding the transactions,
+    /// same as `process` with the default options
+    pub inputs: Vec<String>,
+
+    #[command(flatten)]
+    pub read: ReadArgs,
+
+    /// TOML file with the engine policies, rounding, limits, fraud rules,
+    /// client registry and output defaults
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub logging: LoggingArgs,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Processes the transactions and writes the client balances
+    #[command(after_help = EXIT_CODES)]
+    Process {
+        /// CSV files, directories or glob patterns holding the transactions
+        #[arg(required = true)]
+        inputs: Vec<String>,
+        #[command(flatten)]
+        read: ReadArgs,
+        #[command(flatten)]
+        output: OutputArgs,
+        /// Database file keeping the transactions on disk instead of in memory,
+        /// along with the accounts, so the next run resumes from them
+        #[arg(long)]
+        tx_store: Option<PathBuf>,
+        /// File where the transactions that triggered a fraud rule are written
+        #[arg(long)]
+        flagged: Option<PathBuf>,
+        /// File where the audit report of the manual adjustments is written,
+        /// with their reason code, maker, checker and status
+        #[arg(long)]
+        adjustments: Option<PathBuf>,
+        /// File where the double-entry journal is written, with the debited
+        /// and credited ledger accounts of every posting
+        #[arg(long)]
+        journal: Option<PathBuf>,
+        /// Checks the conservation invariants of the ledgers after every
+        /// transaction or at the end of the run, stopping at the first violation
+        #[arg(long, value_enum, value_name = "WHEN")]
+        check_invariants: Option<InvariantCheck>,
+        /// SQLite database keeping the accounts, the transactions and
+        /// their history, to be queried once the run finished
+        #[cfg(feature = "sqlite")]
+        #[arg(long, conflicts_with = "tx_store")]
+        sqlite: Option<PathBuf>,
+    },
+    /// Processes the transactions on a throwaway engine and writes a summary
+    /// of the row kinds, rejections, unknown references and duplicate ids,
+    /// without writing any balances
+    #[command(after_help = EXIT_CODES)]
+    Validate {
+        /// CSV files, directories or glob patterns holding the transactions
+        #[arg(required = true)]
+        inputs: Vec<String>,
+        #[command(flatten)]
+        read: ReadArgs,
+        /// State file of the watch mode the engine starts from, it is not modified
+        #[arg(long)]
+        snapshot: Option<PathBuf>,
+        /// Output format
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Writes the chronological list of transactions of a client,
+    /// with the running balances after each one
+    Statement {
+        /// CSV files, directories or glob patterns holding the transactions
+        #[arg(required = true)]
+        inputs: Vec<String>,
+        /// Only lists the transactions of this client
+        #[arg(long)]
+        client: Option<u16>,
+        /// Only lists the rows referencing this transaction id: the original
+        /// deposit or withdrawal with its refunds, disputes and captures
+        #[arg(long)]
+        tx: Option<u32>,
+        #[command(flatten)]
+        read: ReadArgs,
+        /// Output format
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Compares two client balance files by client id,
+    /// exits with a nonzero code on any discrepancy
+    Reconcile {
+        /// First client balances CSV file
+        left: String,
+        /// Second client balances CSV file
+        right: String,
+        /// Output format
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Watches a landing directory, applying every new file as a batch
+    /// against the persisted engine state
+    Watch {
+        /// Directory where the transaction files are dropped
+        dir: PathBuf,
+        /// Client balances file, rewritten after each batch in the
+        /// configuration's output format
+        #[arg(long)]
+        output: PathBuf,
+        /// Engine state file, defaults to `<output>.state.json`
+        #[arg(long)]
+        state: Option<PathBuf>,
+        /// Database file keeping the transactions, instead of the state file
+        #[arg(long)]
+        tx_store: Option<PathBuf>,
+        /// Seconds between two scans of the directory
+        #[arg(long, default_value_t = 2)]
+        interval: u64,
+        /// Processes the files already in the directory, then exits
+        #[arg(long)]
+        once: bool,
+        #[command(flatten)]
+        read: ReadArgs,
+    },
+    /// Listens for TCP connections, applying the CSV transactions sent on
+    /// each one as a batch against the persisted engine state, and replying
+    /// with the client balances, or an `error:` line if the batch was not applied
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:7878")]
+        listen: String,
+        /// Engine state file, loaded at start and saved after each batch
+        #[arg(long)]
+        state: PathBuf,
+        /// Database file keeping the transactions, instead of the state file
+        #[arg(long)]
+        tx_store: Option<PathBuf>,
+        #[command(flatten)]
+        read: ReadArgs,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct ReadArgs {
+    /// What to do with rows that can't be parsed
+    #[arg(long, value_enum, default_value_t)]
+    pub on_error: ErrorPolicy,
+    /// File where malformed rows are written, with their line number and error
+    #[arg(long, required_if_eq("on_error", "quarantine"))]
+    pub quarantine: Option<String>,
+    /// Stops reading once more rows than this are malformed
+    #[arg(long)]
+    pub max_bad_rows: Option<u64>,
+    /// Merges the input files by this column (e.g. a timestamp or sequence),
+    /// instead of reading them one after the other
+    #[arg(long, value_name = "COLUMN")]
+    pub merge_by: Option<String>,
+}
+
+impl From<ReadArgs> for ReadOptions {
+    fn from(args: ReadArgs) -> Self {
+        ReadOptions {
+            policy: args.on_error,
+            quarantine_path: args.quarantine,
+            max_bad_rows: args.max_bad_rows,
+            merge_key: args.merge_by,
+        }
+    }
+}
+
+#[derive(Args, Debug, Default)]
+pub struct OutputArgs {
+    /// Balances file, replaced atomically, defaults to the configuration's
+    /// output path, or STDOUT
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+    /// Output format, defaults to the configuration's output format, or CSV
+    #[arg(long, value_enum)]
+    pub format: Option<OutputFormat>,
+    /// Order of the balances, defaults to the configuration's order, or client id
+    #[arg(long, value_enum)]
+    pub sort_by: Option<SortKey>,
+}
+
+#[derive(Args, Debug)]
+pub struct LoggingArgs {
+    /// Log filter directives (e.g. `info`), defaults to `TOY_PAYMENTS_LOG`
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+    /// Log format, defaults to `TOY_PAYMENTS_LOG_FORMAT`
+    #[arg(long, value_enum, global = true)]
+    pub log_format: Option<LogFormat>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Human,
+    Json,
+}
+
+// Process exit statuses, see `EXIT_CODES`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitStatus {
+    Success = 0,
+    Rejections = 1,
+    Usage = 2,
+    InvalidInput = 3,
+    IoError = 4,
+}
+
+impl From<ExitStatus> for ExitCode {
+    fn from(status: ExitStatus) -> Self {
+        ExitCode::from(status as u8)
+    }
+}
+
+impl From<&CSVReaderError> for ExitStatus {
+    fn from(error: &CSVReaderError) -> Self {
+        match error {
+            CSVReaderError::ReadingError
+            | CSVReaderError::TooManyBadRowsError(_)
+            | CSVReaderError::MissingMergeKeyError(_) => ExitStatus::InvalidInput,
+            // Undecodable compressed files are invalid input, not I/O errors
+            CSVReaderError::FileOpeningError(e) => match e.kind() {
+                csv::ErrorKind::Io(e) if e.kind() != ErrorKind::InvalidData => ExitStatus::IoError,
+                _ => ExitStatus::InvalidInput,
+            },
+            CSVReaderError::MissingQuarantineError => ExitStatus::Usage,
+            CSVReaderError::TxFailError(_)
+            | CSVReaderError::QuarantineWritingError(_)
+            | CSVReaderError::InputPatternError(_) => ExitStatus::IoError,
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+        let cli = Cli::parse_from(["toy_payments", "process", "in.csv", "--format", "json"]);
+        assert!(matches!(
+            cli.command,
+            Some(Command::Process { output, .. }) if output.format == Some(OutputFormat::Json)
+        ));
+    }
+
+    #[test]
+    fn test_exit_status() {
+        let missing = csv::Reader::from_path("missing.csv").unwrap_err();
+        assert_eq!(
+            ExitStatus::from(&CSVReaderError::FileOpeningError(missing)),
+            ExitStatus::IoError
+        );
+        assert_eq!(
+            ExitStatus::from(&CSVReaderError::ReadingError),
+            ExitStatus::InvalidInput
+        );
+        assert_eq!(ExitCode::from(ExitStatus::IoError), ExitCode::from(4));
+    }
+}
@@ -0,0 +1,525 @@
+use crate::csv::writer::{OutputFormat, SortKey};
+use rust_decimal::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use tracing::{info, instrument};
+
+// Configuration Error definition
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Error reading the configuration file")]
+    FileError(#[from] io::Error),
+    #[error("Error parsing the configuration file: {0}")]
+    ParseError(#[from] toml::de::Error),
+    #[error("Invalid configuration value `{field}`: {reason}")]
+    InvalidValueError { field: &'static str, reason: String },
+}
+
+// Engine configuration, read from a TOML file.
+// Every section and value is optional, the defaults are the engine's
+// historical rules
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineConfig {
+    pub policies: Policies,
+    pub rounding: Rounding,
+    pub limits: Limits,
+    pub tiers: BTreeMap<String, TierLimits>,
+    pub fraud: FraudConfig,
+    pub registry: RegistryConfig,
+    pub invariants: InvariantConfig,
+    pub output: OutputConfig,
+}
+
+// Business rules applied to the client accounts
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policies {
+    // What a locked account still accepts
+    pub locked_accounts: LockedPolicy,
+    // How far below zero a withdrawal can take the available funds
+    pub overdraft_limit: Decimal,
+    // Disputes are rejected when the available funds don't cover the amount
+    pub dispute_requires_available: bool,
+    // Number of transactions after a deposit or withdrawal during which it
+    // can be disputed, there is no limit when `None`.
+    // The input has no timestamps, so the window is counted in transactions
+    pub dispute_window: Option<u64>,
+    // Number of transactions after a deposit during which its funds are
+    // pending and can't be withdrawn, deposits are available at once if `None`
+    pub deposit_hold: Option<u64>,
+    // Number of transactions after an authorization during which it can be
+    // captured or voided, after which its funds are released.
+    // Authorizations don't expire if `None`
+    pub authorization_expiry: Option<u64>,
+    // What happens to the transactions of clients without an account
+    pub unknown_clients: UnknownClientPolicy,
+    // Tier of the clients the registry doesn't give one, they have no
+    // tier limits if `None`
+    pub default_tier: Option<String>,
+}
+
+impl Default for Policies {
+    fn default() -> Self {
+        Policies {
+            locked_accounts: LockedPolicy::default(),
+            overdraft_limit: Decimal::ZERO,
+            dispute_requires_available: true,
+            dispute_window: None,
+            deposit_hold: None,
+            authorization_expiry: None,
+            unknown_clients: UnknownClientPolicy::default(),
+            default_tier: None,
+        }
+    }
+}
+
+// What a locked account accepts
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LockedPolicy {
+    // Every transaction is refused
+    #[default]
+    RefuseAll,
+    // Deposits are still credited, everything else is refused
+    AcceptDeposits,
+}
+
+// What happens to the transactions of clients without an account,
+// neither registered nor opened by an earlier transaction
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownClientPolicy {
+    // The account is opened by the first transaction applied to it
+    #[default]
+    Create,
+    // The transactions are refused, accounts must be registered
+    Reject,
+}
+
+// Rounding of the transaction amounts
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rounding {
+    pub decimal_places: u32,
+    pub mode: RoundingMode,
+}
+
+impl Default for Rounding {
+    fn default() -> Self {
+        Rounding {
+            decimal_places: 4,
+            mode: RoundingMode::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoundingMode {
+    // Midpoints are rounded to the even neighbour (banker's rounding)
+    #[default]
+    HalfEven,
+    // Midpoints are rounded away from zero
+    HalfUp,
+    // Extra decimal places are dropped
+    Truncate,
+}
+
+// Per transaction amount limits, transactions above them are rejected
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_deposit: Option<Decimal>,
+    pub max_withdrawal: Option<Decimal>,
+    // Lengths of the short and long windows of the tier withdrawal limits.
+    // The input has no timestamps, so like the dispute window they are
+    // counted in transactions, of every client, not in days: the n-th
+    // window spans the positions `n * length` to `(n + 1) * length - 1`
+    // of the processing order
+    pub short_window: Option<u64>,
+    pub long_window: Option<u64>,
+}
+
+// Limits of a client tier, on top of the per transaction ones
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TierLimits {
+    // Largest single withdrawal
+    pub max_withdrawal: Option<Decimal>,
+    // Largest amount withdrawn within a short or a long window, they
+    // require the length of their window in the limits
+    pub short_window_withdrawal: Option<Decimal>,
+    pub long_window_withdrawal: Option<Decimal>,
+    // Largest total funds of the account a deposit can reach
+    pub max_balance: Option<Decimal>,
+}
+
+// Velocity rules screening the deposits and withdrawals before they are
+// applied. Windows are counted in transactions, like the dispute window
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FraudConfig {
+    pub rules: Vec<FraudRule>,
+}
+
+// A fraud rule, the condition and its parameters are given by the `rule` key
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct FraudRule {
+    // Name of the rule, in the rejection reasons and the flagged report
+    pub name: String,
+    // What happens to the transaction triggering the rule
+    pub action: FraudAction,
+    #[serde(flatten)]
+    pub condition: FraudCondition,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum FraudCondition {
+    // More than `max` withdrawals of a client within `window` transactions
+    WithdrawalCount {
+        max: u32,
+        window: u64,
+    },
+    // A withdrawal within `within` transactions of a deposit of the same client
+    WithdrawalAfterDeposit {
+        within: u64,
+    },
+    // The amount of a client's deposits or withdrawals adds up to more than
+    // `max`, within `window` transactions or since the first one if `None`
+    CumulativeAmount {
+        max: Decimal,
+        #[serde(default)]
+        flow: Flow,
+        window: Option<u64>,
+    },
+}
+
+// Direction of the money screened by a rule
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Flow {
+    Deposit,
+    #[default]
+    Withdrawal,
+}
+
+// Ordered by severity, the most severe action of the triggered rules is taken
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum FraudAction {
+    // The transaction is applied and reported
+    Flag,
+    // The transaction is rejected
+    Reject,
+    // The transaction is rejected and the account locked
+    Lock,
+}
+
+// Client registry, the accounts opened before any transaction
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistryConfig {
+    // CSV file with the `client`, `status`, `tier` and `opened` columns,
+    // read at the start of every run
+    pub path: Option<PathBuf>,
+}
+
+// Conservation checks of the ledgers, a run stops at the first violation.
+// Debug builds check them at the end of every run unless configured
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct InvariantConfig {
+    pub check: Option<InvariantCheck>,
+}
+
+// When the invariants are checked
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum InvariantCheck {
+    // After every applied transaction, reporting the first violating one
+    Transaction,
+    // Once every transaction was processed
+    End,
+}
+
+// Defaults of the balances output, the command line options take precedence
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    pub format: OutputFormat,
+    pub sort: SortKey,
+    pub path: Option<PathBuf>,
+}
+
+impl EngineConfig {
+    /// Loads and validates a TOML configuration file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the configuration file
+    #[instrument(name = "config")]
+    pub fn load(path: &Path) -> Result<EngineConfig, ConfigError> {
+        let config: EngineConfig = toml::from_str(&fs::read_to_string(path)?)?;
+        config.validate()?;
+        info!(?config, "configuration loaded");
+        Ok(config)
+    }
+
+    /// Checks the values that can be parsed but make no sense
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |field, reason: &str| {
+            Err(ConfigError::InvalidValueError {
+                field,
+                reason: reason.to_string(),
+            })
+        };
+        if self.rounding.decimal_places > Decimal::MAX_SCALE {
+            return invalid(
+                "rounding.decimal_places",
+                &format!("must be at most {}", Decimal::MAX_SCALE),
+            );
+        }
+        if self.policies.overdraft_limit.is_sign_negative() {
+            return invalid("policies.overdraft_limit", "must not be negative");
+        }
+        if self.policies.dispute_window == Some(0) {
+            return invalid("policies.dispute_window", "must be greater than zero");
+        }
+        if self.policies.deposit_hold == Some(0) {
+            return invalid("policies.deposit_hold", "must be greater than zero");
+        }
+        if self.policies.authorization_expiry == Some(0) {
+            return invalid("policies.authorization_expiry", "must be greater than zero");
+        }
+        for (field, limit) in [
+            ("limits.max_deposit", self.limits.max_deposit),
+            ("limits.max_withdrawal", self.limits.max_withdrawal),
+        ] {
+            if limit.is_some_and(|limit| limit <= Decimal::ZERO) {
+                return invalid(field, "must be greater than zero");
+            }
+        }
+        for (field, window) in [
+            ("limits.short_window", self.limits.short_window),
+            ("limits.long_window", self.limits.long_window),
+        ] {
+            if window == Some(0) {
+                return invalid(field, "must be greater than zero");
+            }
+        }
+        for (name, tier) in &self.tiers {
+            let limits = [
+                tier.max_withdrawal,
+                tier.short_window_withdrawal,
+                tier.long_window_withdrawal,
+                tier.max_balance,
+            ];
+            if limits.iter().flatten().any(|limit| *limit <= Decimal::ZERO) {
+                return invalid(
+                    "tiers",
+                    &format!("the limits of tier `{name}` must be greater than zero"),
+                );
+            }
+            for (field, window, limit) in [
+                (
+                    "limits.short_window",
+                    self.limits.short_window,
+                    tier.short_window_withdrawal,
+                ),
+                (
+                    "limits.long_window",
+                    self.limits.long_window,
+                    tier.long_window_withdrawal,
+                ),
+            ] {
+                if limit.is_some() && window.is_none() {
+                    return invalid(
+                        field,
+                        &format!("is required by the window limits of tier `{name}`"),
+                    );
+                }
+            }
+        }
+        if let Some(tier) = &self.policies.default_tier {
+            if !self.tiers.contains_key(tier) {
+                return invalid("policies.default_tier", &format!("unknown tier `{tier}`"));
+            }
+        }
+        for rule in &self.fraud.rules {
+            let positive = match rule.condition {
+                FraudCondition::WithdrawalCount { window, .. } => window > 0,
+                FraudCondition::WithdrawalAfterDeposit { within } => within > 0,
+                FraudCondition::CumulativeAmount { max, window, .. } => {
+                    max > Decimal::ZERO && window != Some(0)
+                }
+            };
+            if !positive {
+                return invalid(
+                    "fraud.rules",
+                    &format!(
+                        "the limits of rule `{}` must be greater than zero",
+                        rule.name
+                    ),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the name and limits of the tier of a client, its own or the
+    /// default one, if it is configured
+    ///
+    /// # Arguments
+    ///
+    /// * `tier` - Tier given to the client by the registry
+    pub fn tier<'a>(&'a self, tier: Option<&'a str>) -> Option<(&'a str, &'a TierLimits)> {
+        let name = tier.or(self.policies.default_tier.as_deref())?;
+        self.tiers.get(name).map(|limits| (name, limits))
+    }
+
+    /// Rounds an amount to the configured decimal places
+    ///
+    /// # Arguments
+    ///
+    /// * `amount` - Amount read from the input
+    pub fn round(&self, amount: Decimal) -> Decimal {
+        let strategy = match self.rounding.mode {
+            RoundingMode::HalfEven => RoundingStrategy::MidpointNearestEven,
+            RoundingMode::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            RoundingMode::Truncate => RoundingStrategy::ToZero,
+        };
+        amount.round_dp_with_strategy(self.rounding.decimal_places, strategy)
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config: EngineConfig = toml::from_str(
+            r#"
+            [policies]
+            locked_accounts = "accept_deposits"
+            overdraft_limit = "25.5"
+            dispute_window = 100
+
+            [rounding]
+            decimal_places = 2
+            mode = "truncate"
+
+            [output]
+            format = "json"
+            sort = "total"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.policies.locked_accounts,
+            LockedPolicy::AcceptDeposits
+        );
+        assert_eq!(config.policies.overdraft_limit, Decimal::new(255, 1));
+        assert!(config.policies.dispute_requires_available);
+        assert_eq!(config.output.format, OutputFormat::Json);
+        assert_eq!(config.output.sort, SortKey::Total);
+        assert_eq!(config.round(Decimal::new(12349, 3)), Decimal::new(1234, 2));
+    }
+
+    #[test]
+    fn test_default_rounding() {
+        let config = EngineConfig::default();
+        assert_eq!(
+            config.round(Decimal::new(123455, 5)),
+            Decimal::new(12346, 4)
+        );
+        assert_eq!(
+            config.round(Decimal::new(123465, 5)),
+            Decimal::new(12346, 4)
+        );
+    }
+
+    #[test]
+    fn test_fraud_rules() {
+        let config: EngineConfig = toml::from_str(
+            r#"
+            [[fraud.rules]]
+            name = "burst"
+            rule = "withdrawal_count"
+            max = 3
+            window = 10
+            action = "reject"
+
+            [[fraud.rules]]
+            name = "large_deposits"
+            rule = "cumulative_amount"
+            flow = "deposit"
+            max = "10000"
+            action = "flag"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.fraud.rules[0].condition,
+            FraudCondition::WithdrawalCount { max: 3, window: 10 }
+        );
+        assert_eq!(config.fraud.rules[1].action, FraudAction::Flag);
+        assert!(toml::from_str::<EngineConfig>(
+            "[[fraud.rules]]\nname = \"x\"\nrule = \"unknown\"\naction = \"flag\"\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_tiers() {
+        let config: EngineConfig = toml::from_str(
+            r#"
+            [policies]
+            default_tier = "basic"
+
+            [limits]
+            short_window = 100
+
+            [tiers.basic]
+            max_withdrawal = "500"
+            short_window_withdrawal = "1000"
+
+            [tiers.business]
+            max_balance = "1000000"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.tier(None).map(|(name, _)| name), Some("basic"));
+        let (_, business) = config.tier(Some("business")).unwrap();
+        assert_eq!(business.max_balance, Some(Decimal::new(1000000, 0)));
+        assert!(config.tier(Some("unknown")).is_none());
+        let config: EngineConfig =
+            toml::from_str("[tiers.basic]\nlong_window_withdrawal = \"10\"\n").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(toml::from_str::<EngineConfig>("[policies]\nlocked = true\n").is_err());
+        let config: EngineConfig = toml::from_str("[limits]\nmax_deposit = \"-1\"\n").unwrap();
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidValueError {
+                field: "limits.max_deposit",
+                ..
+            })
+        ));
+    }
+}
@@ -1,11 +1,29 @@
 extern crate csv;
 
-use crate::structs::transaction::Transaction;
-use csv::{ReaderBuilder, Trim};
-use std::sync::mpsc::{SendError, Sender};
+use crate::structs::{
+    clients::ClientAccount,
+    registry::RegisteredClient,
+    transaction::{Provenance, Transaction},
+};
+use csv::{ByteRecord, ReaderBuilder, Trim};
+use flate2::read::MultiGzDecoder;
+use rust_decimal::prelude::*;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::{
+    mpsc::{SendError, Sender},
+    Arc,
+};
 use thiserror::Error;
+use tracing::{debug, error, info, instrument, warn};
 
 // CSV Reader Error definition
+#[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 pub enum CSVReaderError {
     #[error("Error reading the input file")]
@@ -13,28 +31,670 @@ pub enum CSVReaderError {
     #[error("Error opening the input file")]
     FileOpeningError(#[from] ECSV::Error),
     #[error("Failed sending the transaction")]
-    TxFailError(#[from] SendError<Transaction>),
+    TxFailError(#[source] Box<SendError<Transaction>>),
+    #[error("Too many malformed rows, the maximum is {0}")]
+    TooManyBadRowsError(u64),
+    #[error("A quarantine file is required by the quarantine policy")]
+    MissingQuarantineError,
+    #[error("Error writing the quarantine file")]
+    QuarantineWritingError(#[source] ECSV::Error),
+    #[error("Invalid input: {0}")]
+    InputPatternError(String),
+    #[error("The merge column is missing in {0}")]
+    MissingMergeKeyError(String),
 }
 
-/// Reads a CSV entry from csv_file_path and send it to the Sender
+// The unsent transaction is boxed, it is much larger than the other errors
+impl From<SendError<Transaction>> for CSVReaderError {
+    fn from(e: SendError<Transaction>) -> Self {
+        CSVReaderError::TxFailError(Box::new(e))
+    }
+}
+
+// What to do with a row that can't be parsed into a Transaction
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ErrorPolicy {
+    // Stops reading at the first malformed row
+    #[default]
+    Abort,
+    // Logs the malformed row and keeps reading
+    Skip,
+    // Writes the malformed row to the quarantine file and keeps reading
+    Quarantine,
+}
+
+// Reader options
+#[derive(Clone, Debug, Default)]
+pub struct ReadOptions {
+    pub policy: ErrorPolicy,
+    pub quarantine_path: Option<String>,
+    // Stops reading once more rows than this are malformed
+    pub max_bad_rows: Option<u64>,
+    // Column the input files are merged by, in ascending order.
+    // Files are read one after the other, in the given order, when `None`
+    pub merge_key: Option<String>,
+}
+
+// Row counts of a finished read
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReadReport {
+    pub files: u64,
+    pub rows: u64,
+    pub bad_rows: u64,
+}
+
+// Malformed row, as written to the quarantine file
+#[derive(Serialize, Debug)]
+struct QuarantinedRow<'a> {
+    file: &'a str,
+    line: u64,
+    error: String,
+    raw: String,
+}
+
+// Value of the merge column, numbers are sorted before text
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum OrderKey {
+    Number(Decimal),
+    Text(String),
+}
+
+impl OrderKey {
+    fn parse(value: &str) -> OrderKey {
+        match Decimal::from_str(value) {
+            Ok(number) => OrderKey::Number(number),
+            Err(_) => OrderKey::Text(value.to_string()),
+        }
+    }
+}
+
+/// Reads the CSV entries from the input files and send them to the Sender.
+/// Inputs can be files, directories or glob patterns, see `expand_inputs`.
+/// Every transaction keeps the file and line it was read from.
+/// Malformed rows are handled according to the options' error policy.
 ///
 /// # Arguments
 ///
 /// * `tx_channel` - A Sender channel that the entries will be sent
+/// * `inputs` - Paths or patterns of the transactions CSV files
+/// * `options` - Error policy, limits for malformed rows and merge column
+#[instrument(name = "reader", skip(tx_channel, options))]
+pub fn read(
+    tx_channel: Sender<Transaction>,
+    inputs: &[String],
+    options: &ReadOptions,
+) -> Result<ReadReport, CSVReaderError> {
+    let mut bad_rows = BadRows::new(options)?;
+    let files = expand_inputs(inputs)?;
+    let mut rows: u64 = 0;
+    let mut send = |transaction: Transaction| -> Result<(), CSVReaderError> {
+        debug!(
+            tx = transaction.tx(),
+            client = transaction.client(),
+            "transaction read"
+        );
+        tx_channel.send(transaction)?;
+        rows += 1;
+        Ok(())
+    };
+
+    match &options.merge_key {
+        None => {
+            for file in &files {
+                let mut source = Source::open(file, None)?;
+                while let Some((_, transaction)) = source.next(&mut bad_rows)? {
+                    send(transaction)?;
+                }
+            }
+        }
+        Some(key) => {
+            let mut sources = files
+                .iter()
+                .map(|file| Source::open(file, Some(key)))
+                .collect::<Result<Vec<Source>, CSVReaderError>>()?;
+            // Each source has, at most, its next transaction waiting in the heap
+            let mut pending: Vec<Option<Transaction>> = vec![None; sources.len()];
+            let mut heap = BinaryHeap::new();
+            for (index, source) in sources.iter_mut().enumerate() {
+                if let Some((key, transaction)) = source.next(&mut bad_rows)? {
+                    heap.push(Reverse((key, index)));
+                    pending[index] = Some(transaction);
+                }
+            }
+            while let Some(Reverse((key, index))) = heap.pop() {
+                if let Some(transaction) = pending[index].take() {
+                    send(transaction)?;
+                }
+                if let Some((next_key, transaction)) = sources[index].next(&mut bad_rows)? {
+                    if next_key < key {
+                        warn!(
+                            file = %sources[index].file,
+                            line = transaction.provenance().map_or(0, |p| p.line),
+                            "input file is not sorted by the merge column"
+                        );
+                    }
+                    heap.push(Reverse((next_key, index)));
+                    pending[index] = Some(transaction);
+                }
+            }
+        }
+    }
+
+    let report = ReadReport {
+        files: files.len() as u64,
+        rows,
+        bad_rows: bad_rows.finish()?,
+    };
+    info!(
+        files = report.files,
+        rows = report.rows,
+        bad_rows = report.bad_rows,
+        "finished reading input"
+    );
+    Ok(report)
+}
+
+/// Expands the inputs into the list of files to read.
+/// Directories are replaced by the files they hold and glob patterns by the
+/// files they match, both sorted by name. Other inputs are kept as they are.
+///
+/// # Arguments
+///
+/// * `inputs` - Paths, directories or glob patterns
+pub fn expand_inputs(inputs: &[String]) -> Result<Vec<String>, CSVReaderError> {
+    let mut files = vec![];
+    for input in inputs {
+        let mut matched: Vec<PathBuf> = if Path::new(input).is_dir() {
+            fs::read_dir(input)
+                .map_err(ECSV::Error::from)?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.is_file())
+                .collect()
+        } else if input.contains(['*', '?', '[']) {
+            glob::glob(input)
+                .map_err(|e| CSVReaderError::InputPatternError(e.to_string()))?
+                .filter_map(Result::ok)
+                .filter(|path| path.is_file())
+                .collect()
+        } else {
+            files.push(input.clone());
+            continue;
+        };
+        if matched.is_empty() {
+            return Err(CSVReaderError::InputPatternError(format!(
+                "no files found for {input}"
+            )));
+        }
+        matched.sort();
+        files.extend(
+            matched
+                .iter()
+                .map(|path| path.to_string_lossy().into_owned()),
+        );
+    }
+    Ok(files)
+}
+
+// Input file being read
+struct Source {
+    file: Arc<str>,
+    rdr: csv::Reader<Box<dyn Read + Send>>,
+    headers: ByteRecord,
+    key_index: Option<usize>,
+    record: ByteRecord,
+}
+
+impl Source {
+    fn open(file: &str, merge_key: Option<&str>) -> Result<Source, CSVReaderError> {
+        debug!(file, "opening input file");
+        // Flexible, so rows with a wrong number of fields are reported as malformed rows
+        let mut rdr = ReaderBuilder::new()
+            .trim(Trim::All)
+            .flexible(true)
+            .from_reader(open_input(file)?);
+        let headers = rdr.byte_headers()?.clone();
+        let key_index = match merge_key {
+            Some(key) => Some(
+                headers
+                    .iter()
+                    .position(|header| header == key.as_bytes())
+                    .ok_or_else(|| CSVReaderError::MissingMergeKeyError(file.to_string()))?,
+            ),
+            None => None,
+        };
+        Ok(Source {
+            file: Arc::from(file),
+            rdr,
+            headers,
+            key_index,
+            record: ByteRecord::new(),
+        })
+    }
+
+    // Returns the next valid transaction, and its merge key if merging,
+    // handing the malformed rows to the error policy
+    fn next(
+        &mut self,
+        bad_rows: &mut BadRows,
+    ) -> Result<Option<(Option<OrderKey>, Transaction)>, CSVReaderError> {
+        while self.rdr.read_byte_record(&mut self.record)? {
+            let line = self.record.position().map_or(0, |p| p.line());
+            match self.parse() {
+                Ok((key, mut transaction)) => {
+                    transaction.set_provenance(Provenance {
+                        file: Arc::clone(&self.file),
+                        line,
+                    });
+                    return Ok(Some((key, transaction)));
+                }
+                Err(reason) => bad_rows.handle(&self.file, line, reason, &self.record)?,
+            }
+        }
+        Ok(None)
+    }
+
+    fn parse(&self) -> Result<(Option<OrderKey>, Transaction), String> {
+        if self.record.len() != self.headers.len() {
+            return Err(format!(
+                "expected {} fields, found {}",
+                self.headers.len(),
+                self.record.len()
+            ));
+        }
+        let transaction = self
+            .record
+            .deserialize::<Transaction>(Some(&self.headers))
+            .map_err(|e| e.to_string())?;
+        let key = match self.key_index {
+            Some(index) => match self.record.get(index).map(str::from_utf8) {
+                Some(Ok(value)) if !value.is_empty() => Some(OrderKey::parse(value)),
+                _ => return Err("missing merge column value".to_string()),
+            },
+            None => None,
+        };
+        Ok((key, transaction))
+    }
+}
+
+// Applies the error policy to the malformed rows and counts them
+struct BadRows<'a> {
+    options: &'a ReadOptions,
+    quarantine: Option<csv::Writer<File>>,
+    count: u64,
+}
+
+impl BadRows<'_> {
+    fn new(options: &ReadOptions) -> Result<BadRows<'_>, CSVReaderError> {
+        let quarantine = match (options.policy, &options.quarantine_path) {
+            (ErrorPolicy::Quarantine, Some(path)) => {
+                Some(csv::Writer::from_path(path).map_err(CSVReaderError::QuarantineWritingError)?)
+            }
+            (ErrorPolicy::Quarantine, None) => return Err(CSVReaderError::MissingQuarantineError),
+            _ => None,
+        };
+        Ok(BadRows {
+            options,
+            quarantine,
+            count: 0,
+        })
+    }
+
+    fn handle(
+        &mut self,
+        file: &str,
+        line: u64,
+        reason: String,
+        record: &ByteRecord,
+    ) -> Result<(), CSVReaderError> {
+        self.count += 1;
+        match self.options.policy {
+            ErrorPolicy::Abort => {
+                error!(file, line, error = %reason, "failed to deserialize row");
+                return Err(CSVReaderError::ReadingError);
+            }
+            ErrorPolicy::Skip => {
+                warn!(file, line, error = %reason, raw = %raw(record), "skipping malformed row");
+            }
+            ErrorPolicy::Quarantine => {
+                warn!(file, line, error = %reason, "quarantining malformed row");
+                if let Some(wtr) = self.quarantine.as_mut() {
+                    wtr.serialize(QuarantinedRow {
+                        file,
+                        line,
+                        error: reason,
+                        raw: raw(record),
+                    })
+                    .map_err(CSVReaderError::QuarantineWritingError)?;
+                }
+            }
+        }
+        if let Some(max) = self.options.max_bad_rows.filter(|max| self.count > *max) {
+            error!(bad_rows = self.count, "too many malformed rows");
+            return Err(CSVReaderError::TooManyBadRowsError(max));
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<u64, CSVReaderError> {
+        if let Some(wtr) = self.quarantine.as_mut() {
+            wtr.flush()
+                .map_err(|e| CSVReaderError::QuarantineWritingError(e.into()))?;
+        }
+        Ok(self.count)
+    }
+}
+
+// Compression formats of the input files
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Opens an input file, decompressing it on the fly when it is gzip or zstd
+/// compressed. The compression is detected by the file extension (`.gz`, `.zst`)
+/// or, failing that, by the magic bytes at the start of the file.
+///
+/// # Arguments
 ///
-pub fn read(tx_channel: Sender<Transaction>, csv_file_path: String) -> Result<(), CSVReaderError> {
+/// * `path` - Path of the input file
+fn open_input(path: &str) -> Result<Box<dyn Read + Send>, CSVReaderError> {
+    let mut file = BufReader::new(File::open(path).map_err(ECSV::Error::from)?);
+    let compression = match Path::new(path).extension().and_then(|e| e.to_str()) {
+        Some("gz") => Compression::Gzip,
+        Some("zst") => Compression::Zstd,
+        _ => {
+            let head = file.fill_buf().map_err(ECSV::Error::from)?;
+            if head.starts_with(GZIP_MAGIC) {
+                Compression::Gzip
+            } else if head.starts_with(ZSTD_MAGIC) {
+                Compression::Zstd
+            } else {
+                Compression::None
+            }
+        }
+    };
+    debug!(?compression, "input opened");
+    Ok(match compression {
+        Compression::None => Box::new(file),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(file)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(file).map_err(ECSV::Error::from)?),
+    })
+}
+
+// Re-encodes a record as a CSV line, as close as possible to the input
+fn raw(record: &ByteRecord) -> String {
+    let mut wtr = csv::WriterBuilder::new()
+        .terminator(csv::Terminator::Any(b'\n'))
+        .from_writer(vec![]);
+    let _ = wtr.write_byte_record(record);
+    let line = wtr.into_inner().unwrap_or_default();
+    String::from_utf8_lossy(&line).trim_end().to_string()
+}
+
+/// Reads a CSV of client balances, in the format written by `writer::write_balances`
+///
+/// # Arguments
+///
+/// * `csv_file_path` - Path of the accounts CSV file
+#[instrument(name = "accounts_reader")]
+pub fn read_accounts(csv_file_path: &str) -> Result<HashMap<u16, ClientAccount>, CSVReaderError> {
     let mut rdr = ReaderBuilder::new()
         .trim(Trim::All)
-        .from_path(csv_file_path)?;
-    for tx in rdr.deserialize() {
-        match tx {
-            Ok(_) => {
-                tx_channel.send(tx?)?;
+        .from_reader(open_input(csv_file_path)?);
+    let mut accounts = HashMap::new();
+    for (row, account) in rdr.deserialize::<ClientAccount>().enumerate() {
+        match account {
+            Ok(account) => {
+                accounts.insert(account.client(), account);
             }
-            Err(_) => {
+            Err(e) => {
+                error!(error = %e, row = row + 1, "failed to deserialize account");
                 return Err(CSVReaderError::ReadingError);
             }
         }
     }
-    Ok(())
+    info!(accounts = accounts.len(), "finished reading accounts");
+    Ok(accounts)
+}
+
+/// Reads the client registry CSV, with a row for every account opened
+/// ahead of its transactions
+///
+/// # Arguments
+///
+/// * `path` - Path of the registry CSV file
+#[instrument(name = "registry_reader")]
+pub fn read_registry(path: &Path) -> Result<Vec<RegisteredClient>, CSVReaderError> {
+    let path = path.to_string_lossy();
+    let mut rdr = ReaderBuilder::new()
+        .trim(Trim::All)
+        .from_reader(open_input(&path)?);
+    let mut clients = Vec::new();
+    for (row, client) in rdr.deserialize::<RegisteredClient>().enumerate() {
+        match client {
+            Ok(client) => clients.push(client),
+            Err(e) => {
+                error!(error = %e, row = row + 1, "failed to deserialize registered client");
+                return Err(CSVReaderError::ReadingError);
+            }
+        }
+    }
+    info!(clients = clients.len(), "finished reading the registry");
+    Ok(clients)
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::structs::clients::AccountStatus;
+    use std::io::Write;
+    use std::sync::mpsc;
+    use tempfile::NamedTempFile;
+
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,one,2,2.0
+withdrawal,1,3
+deposit,2,4,3.0
+";
+
+    fn write_input(content: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file
+    }
+
+    fn path(file: &NamedTempFile) -> String {
+        file.path().to_string_lossy().into_owned()
+    }
+
+    fn read_files(
+        inputs: &[String],
+        options: &ReadOptions,
+    ) -> (Result<ReadReport, CSVReaderError>, Vec<Transaction>) {
+        let (tx_channel, rx_channel) = mpsc::channel();
+        let result = read(tx_channel, inputs, options);
+        (result, rx_channel.try_iter().collect())
+    }
+
+    fn read_with(options: &ReadOptions) -> (Result<ReadReport, CSVReaderError>, usize) {
+        let file = write_input(INPUT);
+        let (result, sent) = read_files(&[path(&file)], options);
+        (result, sent.len())
+    }
+
+    fn skip() -> ReadOptions {
+        ReadOptions {
+            policy: ErrorPolicy::Skip,
+            ..ReadOptions::default()
+        }
+    }
+
+    fn counts(result: Result<ReadReport, CSVReaderError>) -> (u64, u64) {
+        let report = result.unwrap();
+        (report.rows, report.bad_rows)
+    }
+
+    #[test]
+    fn test_read_gzip() {
+        let mut file = NamedTempFile::new().unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(&mut file, flate2::Compression::fast());
+        encoder.write_all(INPUT.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+        let (result, sent) = read_files(&[path(&file)], &skip());
+        assert_eq!(counts(result), (2, 2));
+        assert_eq!(sent.len(), 2);
+    }
+
+    #[test]
+    fn test_read_zstd() {
+        let mut file = tempfile::Builder::new()
+            .suffix(".csv.zst")
+            .tempfile()
+            .unwrap();
+        let compressed = zstd::encode_all(INPUT.as_bytes(), 0).unwrap();
+        file.write_all(&compressed).unwrap();
+        let (result, sent) = read_files(&[path(&file)], &skip());
+        assert_eq!(counts(result), (2, 2));
+        assert_eq!(sent.len(), 2);
+    }
+
+    #[test]
+    fn test_read_corrupt_gzip() {
+        let mut file = tempfile::Builder::new()
+            .suffix(".csv.gz")
+            .tempfile()
+            .unwrap();
+        file.write_all(INPUT.as_bytes()).unwrap();
+        let (result, sent) = read_files(&[path(&file)], &skip());
+        assert!(matches!(result, Err(CSVReaderError::FileOpeningError(_))));
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn test_read_abort() {
+        let (result, sent) = read_with(&ReadOptions::default());
+        assert!(matches!(result, Err(CSVReaderError::ReadingError)));
+        assert_eq!(sent, 1);
+    }
+
+    #[test]
+    fn test_read_skip() {
+        let (result, sent) = read_with(&skip());
+        assert_eq!(counts(result), (2, 2));
+        assert_eq!(sent, 2);
+    }
+
+    #[test]
+    fn test_read_max_bad_rows() {
+        let (result, _) = read_with(&ReadOptions {
+            max_bad_rows: Some(1),
+            ..skip()
+        });
+        assert!(matches!(
+            result,
+            Err(CSVReaderError::TooManyBadRowsError(1))
+        ));
+    }
+
+    #[test]
+    fn test_read_quarantine() {
+        let quarantine = NamedTempFile::new().unwrap();
+        let (result, sent) = read_with(&ReadOptions {
+            policy: ErrorPolicy::Quarantine,
+            quarantine_path: Some(path(&quarantine)),
+            max_bad_rows: Some(2),
+            merge_key: None,
+        });
+        assert_eq!(result.unwrap().bad_rows, 2);
+        assert_eq!(sent, 2);
+        let content = fs::read_to_string(quarantine.path()).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines[0], "file,line,error,raw");
+        assert!(lines[1].contains(",3,"));
+        assert!(lines[1].ends_with("\"deposit,one,2,2.0\""));
+        assert!(lines[2].contains(",4,\"expected 4 fields, found 3\""));
+    }
+
+    #[test]
+    fn test_read_sequential_provenance() {
+        let first = write_input("type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,1.0\n");
+        let second = write_input("type,client,tx,amount\ndeposit,2,3,1.0\n");
+        let (result, sent) = read_files(&[path(&second), path(&first)], &skip());
+        assert_eq!(result.unwrap().files, 2);
+        let ids: Vec<u32> = sent.iter().map(|t| t.tx()).collect();
+        assert_eq!(ids, vec![3, 1, 2]);
+        let provenance = sent[2].provenance().unwrap();
+        assert_eq!(&*provenance.file, path(&first));
+        assert_eq!(provenance.line, 3);
+    }
+
+    #[test]
+    fn test_read_merge() {
+        let first =
+            write_input("type,client,tx,amount,seq\ndeposit,1,1,1.0,1\ndeposit,1,2,1.0,10\n");
+        let second =
+            write_input("type,client,tx,amount,seq\ndeposit,2,3,1.0,2\ndeposit,2,4,1.0,\n");
+        let (result, sent) = read_files(
+            &[path(&first), path(&second)],
+            &ReadOptions {
+                merge_key: Some("seq".to_string()),
+                ..skip()
+            },
+        );
+        assert_eq!(counts(result), (3, 1));
+        let ids: Vec<u32> = sent.iter().map(|t| t.tx()).collect();
+        assert_eq!(ids, vec![1, 3, 2]);
+    }
+
+    #[test]
+    fn test_read_merge_missing_column() {
+        let file = write_input(INPUT);
+        let (result, _) = read_files(
+            &[path(&file)],
+            &ReadOptions {
+                merge_key: Some("timestamp".to_string()),
+                ..skip()
+            },
+        );
+        assert!(matches!(
+            result,
+            Err(CSVReaderError::MissingMergeKeyError(_))
+        ));
+    }
+
+    #[test]
+    fn test_expand_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["b.csv", "a.csv", "c.txt"] {
+            fs::write(dir.path().join(name), INPUT).unwrap();
+        }
+        let root = dir.path().to_string_lossy().into_owned();
+        let files = expand_inputs(&[format!("{root}/*.csv")]).unwrap();
+        assert_eq!(
+            files,
+            vec![format!("{root}/a.csv"), format!("{root}/b.csv")]
+        );
+        assert!(expand_inputs(&[format!("{root}/*.gz")]).is_err());
+        assert_eq!(expand_inputs(&[root]).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_read_registry() {
+        let file = write_input("client,status,tier,opened\n1,active,gold,2024-01-15\n2,frozen,,\n");
+        let registry = read_registry(file.path()).unwrap();
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry[0].tier.as_deref(), Some("gold"));
+        assert_eq!(registry[1].status, AccountStatus::Frozen);
+        assert_eq!(registry[1].opened, None);
+        let file = write_input("client,status,tier,opened\n1,active,gold,15/01/2024\n");
+        assert!(read_registry(file.path()).is_err());
+    }
 }
@@ -1,43 +1,408 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
 use std::{
+    cmp::Reverse,
     collections::HashMap,
-    io,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
-    },
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
 };
 use thiserror::Error;
+use tracing::{info, instrument};
 
-use crate::structs::clients::ClientAccount;
+use crate::processors::{
+    fraud::FlaggedTransaction, reconciler::Discrepancy, validator::ValidationReport,
+};
+use crate::structs::{
+    adjustment::Adjustment,
+    clients::{AccountStatus, ClientAccount},
+    journal::JournalEntry,
+    statement::StatementEntry,
+};
 
 // CSV Writer Error definition
+#[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 pub enum CSVWriterError {
     #[error("Error flushing the output file")]
     FlushingError(#[from] std::io::Error),
     #[error("Error writing in the output file")]
     FileWritingError(#[from] ECSV::Error),
+    #[error("Error writing the JSON output")]
+    JsonWritingError(#[from] serde_json::Error),
+}
+
+// Output formats for reports
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+// Order of the client balances, ties are broken by client id
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    // Ascending client id
+    #[default]
+    Client,
+    // Largest available funds first
+    Available,
+    // Largest held funds first
+    Held,
+    // Largest total funds first
+    Total,
+}
+
+// Decimal places of every amount in the balances output
+const AMOUNT_DECIMAL_PLACES: u32 = 4;
+
+// Client balances, as written to the output. The funds reserved by open
+// authorizations have no column of their own, they are part of the total
+#[derive(Serialize, Debug)]
+struct BalanceRow {
+    client: u16,
+    available: String,
+    held: String,
+    pending: String,
+    total: String,
+    status: AccountStatus,
+}
+
+impl From<&ClientAccount> for BalanceRow {
+    fn from(account: &ClientAccount) -> Self {
+        BalanceRow {
+            client: account.client(),
+            available: format_amount(account.available()),
+            held: format_amount(account.held()),
+            pending: format_amount(account.pending()),
+            total: format_amount(account.total()),
+            status: account.status(),
+        }
+    }
+}
+
+// Formats an amount with a fixed number of decimal places
+fn format_amount(amount: Decimal) -> String {
+    let mut amount = amount.round_dp(AMOUNT_DECIMAL_PLACES);
+    amount.rescale(AMOUNT_DECIMAL_PLACES);
+    amount.to_string()
+}
+
+/// Writes the client balances to the STDOUT or, when a path is given,
+/// to a file that is replaced atomically.
+/// The balances are sorted and every amount has four decimal places,
+/// so the same ledger always yields the same output.
+///
+/// # Arguments
+///
+/// * `clients` - HashMap of Clients, the key is the client's ID
+/// * `format` - Output format
+/// * `sort` - Order of the balances
+/// * `path` - Path of the balances file, `None` for the STDOUT
+#[instrument(name = "balances_writer", skip(clients))]
+pub fn write_balances(
+    clients: &HashMap<u16, ClientAccount>,
+    format: OutputFormat,
+    sort: SortKey,
+    path: Option<&Path>,
+) -> Result<(), CSVWriterError> {
+    let rows = balance_rows(clients, sort);
+    match path {
+        Some(path) => write_atomically(path, |file| write_records(file, &rows, format))?,
+        None => write_records(io::stdout().lock(), &rows, format)?,
+    }
+    info!(clients = rows.len(), "balances written");
+    Ok(())
+}
+
+/// Writes the client balances to a writer, sorted and formatted like
+/// `write_balances`
+///
+/// # Arguments
+///
+/// * `out` - Writer the balances are written to
+/// * `clients` - HashMap of Clients, the key is the client's ID
+/// * `format` - Output format
+/// * `sort` - Order of the balances
+pub fn write_balances_to<W: Write>(
+    out: W,
+    clients: &HashMap<u16, ClientAccount>,
+    format: OutputFormat,
+    sort: SortKey,
+) -> Result<(), CSVWriterError> {
+    write_records(out, &balance_rows(clients, sort), format)
+}
+
+// Rows of the client balances, in the given order
+fn balance_rows(clients: &HashMap<u16, ClientAccount>, sort: SortKey) -> Vec<BalanceRow> {
+    let mut accounts: Vec<&ClientAccount> = clients.values().collect();
+    match sort {
+        SortKey::Client => accounts.sort_by_key(|account| account.client()),
+        SortKey::Available => {
+            accounts.sort_by_key(|account| (Reverse(account.available()), account.client()))
+        }
+        SortKey::Held => {
+            accounts.sort_by_key(|account| (Reverse(account.held()), account.client()))
+        }
+        SortKey::Total => {
+            accounts.sort_by_key(|account| (Reverse(account.total()), account.client()))
+        }
+    }
+    accounts.into_iter().map(BalanceRow::from).collect()
+}
+
+/// Writes a file through a temporary file in the same directory, which is
+/// renamed over the destination once complete, so readers never see a
+/// partially written file.
+///
+/// # Arguments
+///
+/// * `path` - Path of the file
+/// * `write` - Writes the content to the temporary file
+pub fn write_atomically<E, F>(path: &Path, write: F) -> Result<(), E>
+where
+    E: From<io::Error>,
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), E>,
+{
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut file = BufWriter::new(File::create(&tmp_path)?);
+    let result = write(&mut file).and_then(|()| {
+        file.flush()?;
+        file.get_ref().sync_all()?;
+        Ok(())
+    });
+    match result {
+        Ok(()) => Ok(fs::rename(&tmp_path, path)?),
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path);
+            Err(e)
+        }
+    }
+}
+
+/// Writes the statement entries to the STDOUT, in processing order
+///
+/// # Arguments
+///
+/// * `statements` - Statement entries recorded by the process task
+/// * `client` - Only writes the entries of this client, all clients if `None`
+/// * `tx` - Only writes the entries referencing this transaction, all if `None`
+/// * `format` - Output format
+#[instrument(name = "statement_writer", skip(statements))]
+pub fn write_statement(
+    statements: &[StatementEntry],
+    client: Option<u16>,
+    tx: Option<u32>,
+    format: OutputFormat,
+) -> Result<(), CSVWriterError> {
+    let entries: Vec<&StatementEntry> = statements
+        .iter()
+        .filter(|entry| client.is_none_or(|id| entry.client() == id))
+        .filter(|entry| tx.is_none_or(|id| entry.tx() == id))
+        .collect();
+    write_records(io::stdout().lock(), &entries, format)?;
+    info!(entries = entries.len(), "statement written");
+    Ok(())
+}
+
+/// Writes the transactions that triggered a fraud rule to a file,
+/// in processing order, with the rule and the action taken
+///
+/// # Arguments
+///
+/// * `flagged` - Transactions flagged by the fraud rules
+/// * `format` - Output format
+/// * `path` - Path of the report file
+#[instrument(name = "flagged_writer", skip(flagged))]
+pub fn write_flagged(
+    flagged: &[FlaggedTransaction],
+    format: OutputFormat,
+    path: &Path,
+) -> Result<(), CSVWriterError> {
+    write_atomically(path, |file| write_records(file, flagged, format))?;
+    info!(flagged = flagged.len(), "flagged transactions written");
+    Ok(())
+}
+
+/// Writes the audit report of the manual adjustments to a file,
+/// pending and approved, in processing order
+///
+/// # Arguments
+///
+/// * `adjustments` - Manual adjustments recorded by the process task
+/// * `format` - Output format
+/// * `path` - Path of the report file
+#[instrument(name = "adjustments_writer", skip(adjustments))]
+pub fn write_adjustments(
+    adjustments: &[Adjustment],
+    format: OutputFormat,
+    path: &Path,
+) -> Result<(), CSVWriterError> {
+    write_atomically(path, |file| write_records(file, adjustments, format))?;
+    info!(adjustments = adjustments.len(), "adjustments written");
+    Ok(())
+}
+
+/// Writes the entries of the double-entry journal to a file, in processing
+/// order, for finance to reconcile against the bank
+///
+/// # Arguments
+///
+/// * `entries` - Journal entries recorded by the process task
+/// * `format` - Output format
+/// * `path` - Path of the journal file
+#[instrument(name = "journal_writer", skip(entries))]
+pub fn write_journal(
+    entries: &[JournalEntry],
+    format: OutputFormat,
+    path: &Path,
+) -> Result<(), CSVWriterError> {
+    write_atomically(path, |file| write_records(file, entries, format))?;
+    info!(entries = entries.len(), "journal written");
+    Ok(())
+}
+
+/// Writes the reconciliation discrepancies to the STDOUT
+///
+/// # Arguments
+///
+/// * `discrepancies` - Discrepancies found between the two account files
+/// * `format` - Output format
+#[instrument(name = "reconciliation_writer", skip(discrepancies))]
+pub fn write_reconciliation(
+    discrepancies: &[Discrepancy],
+    format: OutputFormat,
+) -> Result<(), CSVWriterError> {
+    write_records(io::stdout().lock(), discrepancies, format)?;
+    info!(
+        discrepancies = discrepancies.len(),
+        "reconciliation written"
+    );
+    Ok(())
+}
+
+// Line of the validation summary, in the CSV format
+#[derive(Serialize, Debug)]
+struct SummaryLine<'a> {
+    metric: &'a str,
+    key: String,
+    value: u64,
 }
 
-/// Writes a SCV to the STDOUT from a HashMap of ClientAccount
-/// This function is designed to run in a thread
+/// Writes the validation summary to the STDOUT, as a JSON document
+/// or as `metric,key,value` CSV rows
 ///
 /// # Arguments
 ///
-/// * `clients_ledger` - A reference HashMap of Clients, protected by a Mutex
-/// * `start_writing` - Atomic bool in order to start the writing
-pub fn write(
-    clients_ledger: Arc<Mutex<HashMap<u16, ClientAccount>>>,
-    start_writing: Arc<AtomicBool>,
+/// * `report` - Summary of the validation run
+/// * `format` - Output format
+#[instrument(name = "validation_writer", skip(report))]
+pub fn write_validation(
+    report: &ValidationReport,
+    format: OutputFormat,
 ) -> Result<(), CSVWriterError> {
-    let mut stop = false;
-    while !stop && start_writing.load(Ordering::Relaxed) {
-        let mut wtr = csv::Writer::from_writer(io::stdout());
-        for (_, value) in clients_ledger.lock().unwrap().iter() {
-            wtr.serialize(value)?;
+    if format == OutputFormat::Json {
+        let mut out = io::stdout().lock();
+        serde_json::to_writer_pretty(&mut out, report)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+    let line = |metric, key: &dyn ToString, value| SummaryLine {
+        metric,
+        key: key.to_string(),
+        value,
+    };
+    let mut lines = vec![
+        line("rows", &"", report.rows),
+        line("bad_rows", &"", report.bad_rows),
+    ];
+    lines.extend(report.kinds.iter().map(|(kind, n)| line("kind", kind, *n)));
+    lines.extend(
+        report
+            .rejections
+            .iter()
+            .map(|(reason, n)| line("rejection", reason, *n)),
+    );
+    lines.extend(
+        report
+            .unknown_references
+            .iter()
+            .map(|tx| line("unknown_reference", tx, 1)),
+    );
+    lines.extend(
+        report
+            .duplicate_ids
+            .iter()
+            .map(|tx| line("duplicate_id", tx, 1)),
+    );
+    write_records(io::stdout().lock(), &lines, format)
+}
+
+// Writes a list of records, as CSV rows or a JSON array
+fn write_records<T: Serialize, W: Write>(
+    mut out: W,
+    records: &[T],
+    format: OutputFormat,
+) -> Result<(), CSVWriterError> {
+    match format {
+        OutputFormat::Csv => {
+            let mut wtr = csv::Writer::from_writer(out);
+            for record in records {
+                wtr.serialize(record)?;
+            }
+            wtr.flush()?;
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, records)?;
+            writeln!(out)?;
         }
-        wtr.flush()?;
-        stop = true;
     }
     Ok(())
 }
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config::Policies;
+
+    #[test]
+    fn test_write_balances() {
+        let mut clients = HashMap::new();
+        for (client, amount) in [
+            (3, Decimal::new(5, 1)),
+            (1, Decimal::new(123456, 5)),
+            (2, Decimal::new(7, 0)),
+        ] {
+            let mut account = ClientAccount::new(client);
+            account.deposit(amount, &Policies::default()).unwrap();
+            clients.insert(client, account);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("balances.csv");
+
+        write_balances(&clients, OutputFormat::Csv, SortKey::Client, Some(&path)).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "client,available,held,pending,total,status\n\
+             1,1.2346,0.0000,0.0000,1.2346,active\n\
+             2,7.0000,0.0000,0.0000,7.0000,active\n\
+             3,0.5000,0.0000,0.0000,0.5000,active\n"
+        );
+
+        write_balances(&clients, OutputFormat::Csv, SortKey::Total, Some(&path)).unwrap();
+        let clients: Vec<String> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|line| line[..1].to_string())
+            .collect();
+        assert_eq!(clients, ["2", "1", "3"]);
+    }
+}
@@ -0,0 +1,8 @@
+extern crate csv as ECSV;
+
+pub mod config;
+pub mod csv;
+pub mod logging;
+pub mod processors;
+pub mod store;
+pub mod structs;
@@ -0,0 +1,46 @@
+use std::env;
+use std::io::{self, IsTerminal};
+use tracing_subscriber::EnvFilter;
+
+/// Environment variable holding the log filter directives (e.g. `info`, `toy_payments=debug`)
+pub const LOG_LEVEL_ENV: &str = "TOY_PAYMENTS_LOG";
+/// Environment variable selecting the log format, `human` (default) or `json`
+pub const LOG_FORMAT_ENV: &str = "TOY_PAYMENTS_LOG_FORMAT";
+
+const DEFAULT_LEVEL: &str = "warn";
+
+/// Initializes the global tracing subscriber.
+/// Logs are always written to STDERR, so STDOUT only carries the CSV output.
+///
+/// # Arguments
+///
+/// * `level` - Filter directives, overrides the `TOY_PAYMENTS_LOG` variable
+/// * `json` - Emits JSON lines instead of the human readable format,
+///   overrides the `TOY_PAYMENTS_LOG_FORMAT` variable
+pub fn init(level: Option<&str>, json: Option<bool>) {
+    let filter = match level {
+        Some(level) => EnvFilter::new(level),
+        None => {
+            EnvFilter::try_from_env(LOG_LEVEL_ENV).unwrap_or_else(|_| EnvFilter::new(DEFAULT_LEVEL))
+        }
+    };
+    let json = json.unwrap_or_else(|| {
+        env::var(LOG_FORMAT_ENV)
+            .map(|format| format.eq_ignore_ascii_case("json"))
+            .unwrap_or(false)
+    });
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal());
+    if json {
+        builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .init();
+    } else {
+        builder.init();
+    }
+}
@@ -1,81 +1,355 @@
-use std::collections::HashMap;
-use std::env;
-use std::sync::{
-    atomic::AtomicBool,
-    mpsc::{self, Receiver, Sender},
-    Arc, Mutex,
-};
-extern crate csv as ECSV;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
 
-use crate::csv::{reader, writer};
-use futures::future::join_all;
-use processors::txprocessor;
-use structs::{
-    clients::ClientAccount,
-    transaction::{Transaction, TransactionRecord},
+use clap::{CommandFactory, Parser};
+use cli::{Cli, Command, ExitStatus, LogFormat, OutputArgs};
+use toy_payments::config::EngineConfig;
+use toy_payments::csv::{
+    reader,
+    reader::ReadOptions,
+    writer::{self, CSVWriterError, OutputFormat},
+};
+use toy_payments::logging;
+use toy_payments::processors::{
+    pipeline::{self, Ledgers, PipelineRun},
+    reconciler,
+    server::{self, ServeOptions},
+    snapshot::{Snapshot, SnapshotError},
+    validator,
+    watcher::{self, WatchOptions},
 };
+#[cfg(feature = "sqlite")]
+use toy_payments::store::sqlite::SqliteStore;
+use toy_payments::store::{disk::DiskStore, TransactionStore};
+use tracing::{error, warn};
 
-mod csv;
-mod processors;
-mod structs;
+mod cli;
 
 #[tokio::main]
-async fn main() {
-    let arguments: Vec<String> = env::args().collect();
-    let csv_file = arguments[1].clone();
-
-    // Client records on a HashMap, the key is the client's ID
-    let clients: HashMap<u16, ClientAccount> = HashMap::new();
-    let clients_ledger = Arc::new(Mutex::new(clients));
-
-    // Tx records on a HashMap, the key is the tx's ID
-    let transactions: HashMap<u32, TransactionRecord> = HashMap::new();
-    let transactions_ledger = Arc::new(Mutex::new(transactions));
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    logging::init(
+        cli.logging.log_level.as_deref(),
+        cli.logging
+            .log_format
+            .map(|format| format == LogFormat::Json),
+    );
 
-    // Atomic flags to write the client's records to STDOUT
-    let start_write = Arc::new(AtomicBool::new(false));
-    let start_writer = Arc::clone(&start_write);
+    let config = match cli.config.as_deref().map(EngineConfig::load) {
+        None => EngineConfig::default(),
+        Some(Ok(config)) => config,
+        Some(Err(e)) => {
+            error!(error = %e, "invalid configuration");
+            return ExitStatus::Usage.into();
+        }
+    };
 
-    // Channels for the task communication
-    let (tx_transactions, rx_transactions): (Sender<Transaction>, Receiver<Transaction>) =
-        mpsc::channel();
-    let (tx_transactions2, rx_transactions2): (Sender<Transaction>, Receiver<Transaction>) =
-        mpsc::channel();
-
-    // Tasks handlers
-    let mut handlers = vec![];
-
-    // Reader task
-    let tx_clone_reader = tx_transactions.clone();
-    handlers.push(tokio::spawn(async {
-        reader::read(tx_clone_reader, csv_file).unwrap()
-    }));
-
-    // task that will store the Transactions to the HashMap
-    let tl_store = Arc::clone(&transactions_ledger);
-    let tx_store = tx_transactions2.clone();
-    handlers.push(tokio::spawn(async {
-        txprocessor::store_transactions(rx_transactions, tx_store, tl_store).unwrap()
-    }));
+    let status = match cli.command {
+        Some(Command::Process {
+            inputs,
+            read,
+            output,
+            tx_store,
+            flagged,
+            adjustments,
+            journal,
+            check_invariants,
+            #[cfg(feature = "sqlite")]
+            sqlite,
+        }) => {
+            let mut config = config;
+            if check_invariants.is_some() {
+                config.invariants.check = check_invariants;
+            }
+            let ledgers = match tx_store.as_deref().map(DiskStore::open).transpose() {
+                Ok(Some(store)) => match resume(store) {
+                    Ok(ledgers) => ledgers,
+                    Err(e) => {
+                        error!(error = %e, "failed restoring the engine state");
+                        return ExitStatus::IoError.into();
+                    }
+                },
+                Ok(None) => Ledgers::new(false),
+                Err(e) => {
+                    error!(error = %e, "failed opening the transaction store");
+                    return ExitStatus::IoError.into();
+                }
+            };
+            #[cfg(feature = "sqlite")]
+            let ledgers = match sqlite.as_deref().map(SqliteStore::open).transpose() {
+                Ok(Some(store)) => match resume(store) {
+                    Ok(ledgers) => ledgers,
+                    Err(e) => {
+                        error!(error = %e, "failed restoring the engine state");
+                        return ExitStatus::IoError.into();
+                    }
+                },
+                Ok(None) => ledgers,
+                Err(e) => {
+                    error!(error = %e, "failed opening the SQLite database");
+                    return ExitStatus::IoError.into();
+                }
+            };
+            if journal.is_some() {
+                ledgers.journal.lock().unwrap().keep_entries();
+            }
+            let format = output.format.unwrap_or(config.output.format);
+            let status = process(inputs, read.into(), output, &config, &ledgers).await;
+            match status {
+                ExitStatus::Success | ExitStatus::Rejections => {
+                    let reports = write_reports(
+                        &ledgers,
+                        format,
+                        flagged.as_deref(),
+                        adjustments.as_deref(),
+                        journal.as_deref(),
+                    );
+                    match reports {
+                        Ok(()) => status,
+                        Err(e) => {
+                            error!(error = %e, "report writer failed");
+                            ExitStatus::IoError
+                        }
+                    }
+                }
+                status => status,
+            }
+        }
+        Some(Command::Validate {
+            inputs,
+            read,
+            snapshot,
+            format,
+        }) => {
+            let seed = match snapshot.as_deref().map(Snapshot::load).transpose() {
+                Ok(seed) => seed,
+                Err(e) => {
+                    error!(error = %e, "failed loading the snapshot");
+                    return ExitStatus::IoError.into();
+                }
+            };
+            let (run, report) =
+                match validator::validate(inputs, read.into(), &config, seed.as_ref()).await {
+                    Ok(validation) => validation,
+                    Err(e) => {
+                        error!(error = %e, "failed seeding the engine");
+                        return ExitStatus::IoError.into();
+                    }
+                };
+            match outcome(&run) {
+                status @ (ExitStatus::Success | ExitStatus::Rejections) => {
+                    match writer::write_validation(&report, format) {
+                        Ok(()) => status,
+                        Err(e) => {
+                            error!(error = %e, "validation writer failed");
+                            ExitStatus::IoError
+                        }
+                    }
+                }
+                status => status,
+            }
+        }
+        Some(Command::Statement {
+            inputs,
+            client,
+            tx,
+            read,
+            format,
+        }) => {
+            let ledgers = Ledgers::new(true);
+            let run = pipeline::run(inputs, &ledgers, read.into(), &config).await;
+            match outcome(&run) {
+                status @ (ExitStatus::Success | ExitStatus::Rejections) => {
+                    let statements = ledgers.statements.unwrap_or_default();
+                    let statements = statements.lock().unwrap();
+                    match writer::write_statement(&statements, client, tx, format) {
+                        Ok(()) => status,
+                        Err(e) => {
+                            error!(error = %e, "statement writer failed");
+                            ExitStatus::IoError
+                        }
+                    }
+                }
+                status => status,
+            }
+        }
+        Some(Command::Reconcile {
+            left,
+            right,
+            format,
+        }) => match (reader::read_accounts(&left), reader::read_accounts(&right)) {
+            (Ok(left), Ok(right)) => {
+                let discrepancies = reconciler::reconcile(&left, &right);
+                match writer::write_reconciliation(&discrepancies, format) {
+                    Ok(()) if discrepancies.is_empty() => ExitStatus::Success,
+                    Ok(()) => ExitStatus::Rejections,
+                    Err(e) => {
+                        error!(error = %e, "reconciliation writer failed");
+                        ExitStatus::IoError
+                    }
+                }
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                error!(error = %e, "failed reading the account files");
+                ExitStatus::from(&e)
+            }
+        },
+        Some(Command::Watch {
+            dir,
+            output,
+            state,
+            tx_store,
+            interval,
+            once,
+            read,
+        }) => {
+            let state = state.unwrap_or_else(|| {
+                let mut state = output.clone().into_os_string();
+                state.push(".state.json");
+                state.into()
+            });
+            let options = WatchOptions {
+                dir,
+                output,
+                state,
+                tx_store,
+                interval: Duration::from_secs(interval),
+                once,
+                read: read.into(),
+                config,
+            };
+            match watcher::watch(options).await {
+                Ok(()) => ExitStatus::Success,
+                Err(e) => {
+                    error!(error = %e, "watcher failed");
+                    ExitStatus::IoError
+                }
+            }
+        }
+        Some(Command::Serve {
+            listen,
+            state,
+            tx_store,
+            read,
+        }) => {
+            let listener = match tokio::net::TcpListener::bind(&listen).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!(error = %e, address = listen, "failed listening");
+                    return ExitStatus::IoError.into();
+                }
+            };
+            let options = ServeOptions {
+                state,
+                tx_store,
+                read: read.into(),
+                config,
+            };
+            match server::serve(listener, options).await {
+                Ok(()) => ExitStatus::Success,
+                Err(e) => {
+                    error!(error = %e, "server failed");
+                    ExitStatus::IoError
+                }
+            }
+        }
+        None => {
+            if cli.inputs.is_empty() {
+                Cli::command().print_help().unwrap_or_default();
+                return ExitStatus::Usage.into();
+            }
+            process(
+                cli.inputs,
+                cli.read.into(),
+                OutputArgs::default(),
+                &config,
+                &Ledgers::new(false),
+            )
+            .await
+        }
+    };
+    status.into()
+}
 
-    // task that will process the Transactions and, by the end,
-    // enable the writer task
-    let tl_process = Arc::clone(&transactions_ledger);
-    let cl_process = Arc::clone(&clients_ledger);
+/// Runs the pipeline over the inputs and writes the client balances
+///
+/// # Arguments
+///
+/// * `inputs` - Paths, directories or glob patterns of the CSV files
+/// * `read_options` - Error policy of the reader for malformed rows
+/// * `output` - Balances file, format and order
+/// * `config` - Engine configuration, also holding the output defaults
+/// * `ledgers` - Ledgers, empty or resumed from the transaction store to use
+async fn process(
+    inputs: Vec<String>,
+    read_options: ReadOptions,
+    output: OutputArgs,
+    config: &EngineConfig,
+    ledgers: &Ledgers,
+) -> ExitStatus {
+    let run = pipeline::run(inputs, ledgers, read_options, config).await;
+    let status = outcome(&run);
+    if let ExitStatus::Success | ExitStatus::Rejections = status {
+        let format = output.format.unwrap_or(config.output.format);
+        let sort = output.sort_by.unwrap_or(config.output.sort);
+        let path = output.output.or_else(|| config.output.path.clone());
+        let clients = ledgers.clients.lock().unwrap();
+        if let Err(e) = writer::write_balances(&clients, format, sort, path.as_deref()) {
+            error!(error = %e, "balances writer failed");
+            return ExitStatus::IoError;
+        }
+    }
+    status
+}
 
-    handlers.push(tokio::spawn(async {
-        txprocessor::process_transactions(rx_transactions2, tl_process, cl_process, start_write)
-            .unwrap()
-    }));
+/// Writes the optional reports of a processing run
+///
+/// # Arguments
+///
+/// * `ledgers` - Ledgers the transactions were applied to
+/// * `format` - Output format of the reports
+/// * `flagged` - Path of the transactions flagged by the fraud rules, if requested
+/// * `adjustments` - Path of the adjustments audit report, if requested
+/// * `journal` - Path of the double-entry journal, if requested
+fn write_reports(
+    ledgers: &Ledgers,
+    format: OutputFormat,
+    flagged: Option<&Path>,
+    adjustments: Option<&Path>,
+    journal: Option<&Path>,
+) -> Result<(), CSVWriterError> {
+    if let Some(path) = flagged {
+        writer::write_flagged(ledgers.fraud.lock().unwrap().flagged(), format, path)?;
+    }
+    if let Some(path) = adjustments {
+        writer::write_adjustments(&ledgers.adjustments.lock().unwrap(), format, path)?;
+    }
+    if let Some(path) = journal {
+        writer::write_journal(ledgers.journal.lock().unwrap().entries(), format, path)?;
+    }
+    Ok(())
+}
 
-    let results = join_all(handlers).await;
+/// Returns ledgers resuming from the engine state a persistent store keeps
+/// with its transactions, empty ones when it has none
+///
+/// # Arguments
+///
+/// * `store` - Transaction store
+fn resume(store: impl TransactionStore + 'static) -> Result<Ledgers, SnapshotError> {
+    Snapshot::stored(&store)?.ledgers(false, Some(Box::new(store)))
+}
 
-    for result in results {
-        result.unwrap();
+// Exit status of a pipeline run
+fn outcome(run: &PipelineRun) -> ExitStatus {
+    match &run.input {
+        Err(e) => ExitStatus::from(e),
+        Ok(_) if !run.succeeded() => ExitStatus::InvalidInput,
+        Ok(_) if run.rejected > 0 => {
+            warn!(rejected = run.rejected, "transactions rejected");
+            ExitStatus::Rejections
+        }
+        Ok(_) => ExitStatus::Success,
     }
-
-    // By last, writer task that will print the client records to STDOUT
-    let handle_writer =
-        tokio::spawn(async { writer::write(clients_ledger, start_writer).unwrap() });
-    handle_writer.await.unwrap();
 }
@@ -0,0 +1,238 @@
+use crate::config::{Flow, FraudAction, FraudCondition, FraudRule};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use tracing::warn;
+
+// Transaction that triggered a fraud rule, whatever the action taken
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct FlaggedTransaction {
+    pub seq: u64,
+    pub client: u16,
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub tx_type: &'static str,
+    pub amount: Decimal,
+    pub rule: String,
+    pub action: FraudAction,
+}
+
+// Deposit or withdrawal applied to an account
+#[derive(Clone, Copy, Debug)]
+struct Activity {
+    seq: u64,
+    flow: Flow,
+    amount: Decimal,
+}
+
+// Activity of a client the rules are evaluated against
+#[derive(Clone, Debug, Default)]
+struct ClientActivity {
+    // Applied transactions within the largest window of the rules
+    recent: VecDeque<Activity>,
+    // Amounts applied since the first transaction
+    deposited: Decimal,
+    withdrawn: Decimal,
+}
+
+// Velocity state of the clients and the transactions that triggered a rule.
+// It lives with the ledgers, so the windows span the batches of the watch
+// mode, but it is not part of the snapshot
+#[derive(Clone, Debug, Default)]
+pub struct FraudMonitor {
+    clients: HashMap<u16, ClientActivity>,
+    flagged: Vec<FlaggedTransaction>,
+}
+
+impl FraudMonitor {
+    /// Evaluates the rules against a deposit or withdrawal about to be
+    /// applied, reporting every rule it triggers, and returns the most
+    /// severe action along with the name of its rule
+    ///
+    /// # Arguments
+    ///
+    /// * `rules` - Fraud rules of the engine configuration
+    /// * `seq` - Position of the transaction in the processing order
+    /// * `client` - Client id of the transaction
+    /// * `tx` - Transaction id
+    /// * `flow` - Whether it is a deposit or a withdrawal
+    /// * `amount` - Rounded amount of the transaction
+    pub fn check<'r>(
+        &mut self,
+        rules: &'r [FraudRule],
+        seq: u64,
+        client: u16,
+        tx: u32,
+        flow: Flow,
+        amount: Decimal,
+    ) -> Option<(FraudAction, &'r str)> {
+        let activity = self.clients.get(&client);
+        let recent = |window: u64| {
+            activity
+                .into_iter()
+                .flat_map(|activity| activity.recent.iter())
+                .filter(move |past| seq.saturating_sub(past.seq) <= window)
+        };
+        let mut verdict: Option<(FraudAction, &'r str)> = None;
+        for rule in rules {
+            let triggered = match rule.condition {
+                FraudCondition::WithdrawalCount { max, window } => {
+                    flow == Flow::Withdrawal
+                        && recent(window)
+                            .filter(|past| past.flow == Flow::Withdrawal)
+                            .count()
+                            >= max as usize
+                }
+                FraudCondition::WithdrawalAfterDeposit { within } => {
+                    flow == Flow::Withdrawal
+                        && recent(within).any(|past| past.flow == Flow::Deposit)
+                }
+                FraudCondition::CumulativeAmount {
+                    max,
+                    flow: screened,
+                    window,
+                } => {
+                    let past = match (window, activity) {
+                        (Some(window), _) => recent(window)
+                            .filter(|past| past.flow == screened)
+                            .map(|past| past.amount)
+                            .sum(),
+                        (None, Some(activity)) if screened == Flow::Deposit => activity.deposited,
+                        (None, Some(activity)) => activity.withdrawn,
+                        (None, None) => Decimal::ZERO,
+                    };
+                    flow == screened && past + amount > max
+                }
+            };
+            if !triggered {
+                continue;
+            }
+            warn!(tx, client, rule = rule.name, action = ?rule.action, "fraud rule triggered");
+            self.flagged.push(FlaggedTransaction {
+                seq,
+                client,
+                tx,
+                tx_type: match flow {
+                    Flow::Deposit => "deposit",
+                    Flow::Withdrawal => "withdrawal",
+                },
+                amount,
+                rule: rule.name.clone(),
+                action: rule.action,
+            });
+            if verdict.is_none_or(|(action, _)| rule.action > action) {
+                verdict = Some((rule.action, &rule.name));
+            }
+        }
+        verdict
+    }
+
+    /// Records a deposit or withdrawal that was applied to the account,
+    /// forgetting the activity older than the largest window of the rules
+    ///
+    /// # Arguments
+    ///
+    /// * `rules` - Fraud rules of the engine configuration
+    /// * `seq` - Position of the transaction in the processing order
+    /// * `client` - Client id of the transaction
+    /// * `flow` - Whether it is a deposit or a withdrawal
+    /// * `amount` - Rounded amount of the transaction
+    pub fn record(
+        &mut self,
+        rules: &[FraudRule],
+        seq: u64,
+        client: u16,
+        flow: Flow,
+        amount: Decimal,
+    ) {
+        let horizon = rules
+            .iter()
+            .map(|rule| match rule.condition {
+                FraudCondition::WithdrawalCount { window, .. } => window,
+                FraudCondition::WithdrawalAfterDeposit { within } => within,
+                FraudCondition::CumulativeAmount { window, .. } => window.unwrap_or(0),
+            })
+            .max()
+            .unwrap_or(0);
+        let activity = self.clients.entry(client).or_default();
+        match flow {
+            Flow::Deposit => activity.deposited += amount,
+            Flow::Withdrawal => activity.withdrawn += amount,
+        }
+        activity.recent.push_back(Activity { seq, flow, amount });
+        while activity
+            .recent
+            .front()
+            .is_some_and(|past| seq.saturating_sub(past.seq) > horizon)
+        {
+            activity.recent.pop_front();
+        }
+    }
+
+    /// Transactions that triggered a rule, in processing order
+    pub fn flagged(&self) -> &[FlaggedTransaction] {
+        &self.flagged
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn rule(name: &str, action: FraudAction, condition: FraudCondition) -> FraudRule {
+        FraudRule {
+            name: name.to_string(),
+            action,
+            condition,
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let rules = vec![
+            rule(
+                "burst",
+                FraudAction::Reject,
+                FraudCondition::WithdrawalCount { max: 2, window: 5 },
+            ),
+            rule(
+                "quick_out",
+                FraudAction::Flag,
+                FraudCondition::WithdrawalAfterDeposit { within: 1 },
+            ),
+            rule(
+                "drain",
+                FraudAction::Lock,
+                FraudCondition::CumulativeAmount {
+                    max: Decimal::new(100, 0),
+                    flow: Flow::Withdrawal,
+                    window: None,
+                },
+            ),
+        ];
+        let mut monitor = FraudMonitor::default();
+        let ten = Decimal::new(10, 0);
+        monitor.record(&rules, 0, 1, Flow::Deposit, Decimal::new(500, 0));
+        assert_eq!(
+            monitor.check(&rules, 1, 1, 2, Flow::Withdrawal, ten),
+            Some((FraudAction::Flag, "quick_out"))
+        );
+        monitor.record(&rules, 1, 1, Flow::Withdrawal, ten);
+        assert_eq!(monitor.check(&rules, 3, 1, 3, Flow::Withdrawal, ten), None);
+        monitor.record(&rules, 3, 1, Flow::Withdrawal, ten);
+        assert_eq!(
+            monitor.check(&rules, 4, 1, 4, Flow::Withdrawal, ten),
+            Some((FraudAction::Reject, "burst"))
+        );
+        assert_eq!(monitor.check(&rules, 9, 1, 5, Flow::Withdrawal, ten), None);
+        assert_eq!(
+            monitor.check(&rules, 10, 1, 6, Flow::Withdrawal, Decimal::new(90, 0)),
+            Some((FraudAction::Lock, "drain"))
+        );
+        assert_eq!(monitor.check(&rules, 11, 2, 7, Flow::Withdrawal, ten), None);
+        assert_eq!(monitor.flagged().len(), 3);
+        assert_eq!(monitor.flagged()[2].rule, "drain");
+    }
+}
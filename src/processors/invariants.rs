@@ -0,0 +1,541 @@
+use crate::config::InvariantCheck;
+use crate::structs::{
+    clients::ClientAccount,
+    hold::{Hold, HoldKind},
+    journal::{Journal, LedgerAccount},
+    transaction::{AuthorizationState, RecordStatus, TransactionRecord},
+};
+use rust_decimal::Decimal;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+// Broken invariant, with the transaction after which it was found.
+// The transaction is unknown when the invariants are checked at the end
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    pub invariant: &'static str,
+    pub seq: Option<u64>,
+    pub tx: Option<u32>,
+    pub client: Option<u16>,
+    pub expected: Decimal,
+    pub actual: Decimal,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} invariant violated", self.invariant)?;
+        if let Some(tx) = self.tx {
+            write!(f, " after tx {tx}")?;
+        }
+        if let Some(seq) = self.seq {
+            write!(f, " (seq {seq})")?;
+        }
+        if let Some(client) = self.client {
+            write!(f, " for client {client}")?;
+        }
+        write!(f, ": expected {}, found {}", self.expected, self.actual)
+    }
+}
+
+// Conservation checks of the ledgers over a run. Money only enters the
+// accounts through deposits and credits, and only leaves them through
+// withdrawals, captures, refunds, chargebacks and debits, every other
+// transaction moves it between the funds of an account.
+// The held, pending and authorized funds are also checked against the
+// disputes, deposit holds and authorizations they come from
+#[derive(Clone, Debug)]
+pub struct InvariantChecker {
+    check: InvariantCheck,
+    // Sum of the account totals when the run started
+    opening: Decimal,
+    // Money that entered the accounts minus the one that left them
+    net_flow: Decimal,
+    // Funds accounted for by the disputed records and the open
+    // authorizations, by tx, only the records accounting for some
+    backing: HashMap<u32, Backing>,
+    // First rejected record found changed, reported once the run finished
+    // when the invariants are only checked then
+    deferred: Option<Violation>,
+}
+
+// Funds of a client's account a transaction record accounts for
+#[derive(Clone, Copy, Debug)]
+struct Backing {
+    client: u16,
+    held: Decimal,
+    authorized: Decimal,
+}
+
+impl InvariantChecker {
+    /// Returns a checker for a run starting from the given accounts
+    ///
+    /// # Arguments
+    ///
+    /// * `check` - When the invariants are checked
+    /// * `clients` - Accounts before the first transaction of the run
+    /// * `records` - Transaction records before the first transaction of the run
+    pub fn new(
+        check: InvariantCheck,
+        clients: &HashMap<u16, ClientAccount>,
+        records: &[(u32, TransactionRecord)],
+    ) -> InvariantChecker {
+        let mut checker = InvariantChecker {
+            check,
+            opening: system_total(clients),
+            net_flow: Decimal::ZERO,
+            backing: HashMap::new(),
+            deferred: None,
+        };
+        for (tx, record) in records {
+            checker.index(*tx, record);
+        }
+        checker
+    }
+
+    /// Updates the funds a record accounts for, after a transaction that
+    /// created or referenced it, and checks a rejected record was neither
+    /// disputed, refunded nor charged back. When the invariants are only
+    /// checked at the end, a violation is reported then
+    ///
+    /// # Arguments
+    ///
+    /// * `tx` - Transaction id of the record
+    /// * `record` - Record after the transaction
+    pub fn record(&mut self, tx: u32, record: &TransactionRecord) -> Result<(), Violation> {
+        self.index(tx, record);
+        let changed = record.disputed() || record.charged_back() || !record.refunded().is_zero();
+        if record.status() != RecordStatus::Rejected || !changed {
+            return Ok(());
+        }
+        let violation = Violation {
+            invariant: "rejected record",
+            seq: None,
+            tx: Some(tx),
+            client: Some(record.client()),
+            expected: Decimal::ZERO,
+            actual: record.amount(),
+        };
+        if self.check == InvariantCheck::Transaction {
+            return Err(violation);
+        }
+        self.deferred.get_or_insert(violation);
+        Ok(())
+    }
+
+    // Keeps the funds held by a disputed record, or reserved by an open
+    // authorization, when it was applied
+    fn index(&mut self, tx: u32, record: &TransactionRecord) {
+        let applied = record.status() == RecordStatus::Applied;
+        let backing = Backing {
+            client: record.client(),
+            held: match applied && record.disputed() {
+                true => record.disputable(),
+                false => Decimal::ZERO,
+            },
+            authorized: match applied
+                && record.authorization_state() == Some(AuthorizationState::Open)
+            {
+                true => record.amount(),
+                false => Decimal::ZERO,
+            },
+        };
+        if backing.held.is_zero() && backing.authorized.is_zero() {
+            self.backing.remove(&tx);
+        } else {
+            self.backing.insert(tx, backing);
+        }
+    }
+
+    /// Checks the invariants after an applied transaction, when they are
+    /// checked after every one: the funds of the client's account, their
+    /// balances in the journal and what its records and holds account for,
+    /// the change of its total against the money the transaction moved in
+    /// or out, and the sum of every account against the opening one
+    ///
+    /// # Arguments
+    ///
+    /// * `seq` - Position of the transaction in the processing order
+    /// * `tx` - Transaction id
+    /// * `client` - Client id of the transaction
+    /// * `flow` - Money the transaction moved in, negative when it moved out
+    /// * `before` - Total of the client's account before the transaction
+    /// * `clients` - Accounts after the transaction
+    /// * `journal` - Journal, with the postings of the transaction
+    /// * `holds` - Deposits and authorizations on hold after the transaction
+    #[allow(clippy::too_many_arguments)]
+    pub fn applied(
+        &mut self,
+        seq: u64,
+        tx: u32,
+        client: u16,
+        flow: Decimal,
+        before: Decimal,
+        clients: &HashMap<u16, ClientAccount>,
+        journal: &Journal,
+        holds: &VecDeque<Hold>,
+    ) -> Result<(), Violation> {
+        self.net_flow += flow;
+        if self.check != InvariantCheck::Transaction {
+            return Ok(());
+        }
+        let at = |violation: Violation| Violation {
+            seq: Some(seq),
+            tx: Some(tx),
+            client: Some(client),
+            ..violation
+        };
+        let after = match clients.get(&client) {
+            Some(account) => {
+                check_account(account).map_err(at)?;
+                check_journal(account, journal).map_err(at)?;
+                let backed = self.backed(|backing| backing.client == client);
+                check_backing(account, backed, holds).map_err(at)?;
+                account.total()
+            }
+            None => Decimal::ZERO,
+        };
+        if after - before != flow {
+            return Err(at(Violation {
+                invariant: "account flow",
+                seq: None,
+                tx: None,
+                client: None,
+                expected: flow,
+                actual: after - before,
+            }));
+        }
+        self.check_system(clients).map_err(at)
+    }
+
+    /// Checks the invariants of every account and of their sum once the
+    /// run finished, that the funds of every account are the balances of
+    /// its ledger accounts in the journal, and what its records and holds
+    /// account for
+    ///
+    /// # Arguments
+    ///
+    /// * `clients` - Accounts after the last transaction
+    /// * `journal` - Journal of every posting made to the accounts
+    /// * `holds` - Deposits and authorizations still on hold
+    pub fn finished(
+        &self,
+        clients: &HashMap<u16, ClientAccount>,
+        journal: &Journal,
+        holds: &VecDeque<Hold>,
+    ) -> Result<(), Violation> {
+        if let Some(violation) = &self.deferred {
+            return Err(violation.clone());
+        }
+        let mut backed: HashMap<u16, (Decimal, Decimal)> = HashMap::new();
+        for backing in self.backing.values() {
+            let funds = backed.entry(backing.client).or_default();
+            funds.0 += backing.held;
+            funds.1 += backing.authorized;
+        }
+        let mut accounts: Vec<&ClientAccount> = clients.values().collect();
+        accounts.sort_by_key(|account| account.client());
+        for account in accounts {
+            let client = account.client();
+            check_account(account)
+                .and_then(|()| check_journal(account, journal))
+                .and_then(|()| {
+                    let backed = backed.get(&client).copied().unwrap_or_default();
+                    check_backing(account, backed, holds)
+                })
+                .map_err(|violation| Violation {
+                    client: Some(client),
+                    ..violation
+                })?;
+        }
+        self.check_system(clients)
+    }
+
+    // Sums the held and authorized funds of the records matching a filter
+    fn backed(&self, filter: impl Fn(&Backing) -> bool) -> (Decimal, Decimal) {
+        self.backing
+            .values()
+            .filter(|backing| filter(backing))
+            .fold(
+                (Decimal::ZERO, Decimal::ZERO),
+                |(held, authorized), backing| {
+                    (held + backing.held, authorized + backing.authorized)
+                },
+            )
+    }
+
+    // The sum of the accounts is the opening one plus the money moved in
+    fn check_system(&self, clients: &HashMap<u16, ClientAccount>) -> Result<(), Violation> {
+        let actual = system_total(clients);
+        let expected = self.opening + self.net_flow;
+        if actual != expected {
+            return Err(Violation {
+                invariant: "system balance",
+                seq: None,
+                tx: None,
+                client: None,
+                expected,
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
+
+// The total is the sum of the funds, and only the available ones can be
+// negative, down to the overdraft
+fn check_account(account: &ClientAccount) -> Result<(), Violation> {
+    let violation = |invariant, expected, actual| Violation {
+        invariant,
+        seq: None,
+        tx: None,
+        client: None,
+        expected,
+        actual,
+    };
+    let sum = account.available() + account.held() + account.pending() + account.authorized();
+    if account.total() != sum {
+        return Err(violation("account total", sum, account.total()));
+    }
+    for (invariant, funds) in [
+        ("non-negative held funds", account.held()),
+        ("non-negative pending funds", account.pending()),
+        ("non-negative authorized funds", account.authorized()),
+    ] {
+        if funds < Decimal::ZERO {
+            return Err(violation(invariant, Decimal::ZERO, funds));
+        }
+    }
+    Ok(())
+}
+
+// Every fund of the account is the balance of its ledger account
+fn check_journal(account: &ClientAccount, journal: &Journal) -> Result<(), Violation> {
+    let client = account.client();
+    for (ledger_account, funds) in [
+        (LedgerAccount::Available(client), account.available()),
+        (LedgerAccount::Held(client), account.held()),
+        (LedgerAccount::Pending(client), account.pending()),
+        (LedgerAccount::Authorized(client), account.authorized()),
+    ] {
+        let expected = journal.balance(ledger_account);
+        if funds != expected {
+            return Err(Violation {
+                invariant: "journal balance",
+                seq: None,
+                tx: None,
+                client: None,
+                expected,
+                actual: funds,
+            });
+        }
+    }
+    Ok(())
+}
+
+// The held funds are the disputed amounts of the client's records, the
+// pending ones its deposits on hold and the authorized ones its open
+// authorizations
+fn check_backing(
+    account: &ClientAccount,
+    (held, authorized): (Decimal, Decimal),
+    holds: &VecDeque<Hold>,
+) -> Result<(), Violation> {
+    let pending = holds
+        .iter()
+        .filter(|hold| hold.client == account.client() && hold.kind == HoldKind::Deposit)
+        .map(|hold| hold.amount)
+        .sum();
+    for (invariant, expected, actual) in [
+        ("held funds", held, account.held()),
+        ("pending funds", pending, account.pending()),
+        ("authorized funds", authorized, account.authorized()),
+    ] {
+        if actual != expected {
+            return Err(Violation {
+                invariant,
+                seq: None,
+                tx: None,
+                client: None,
+                expected,
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn system_total(clients: &HashMap<u16, ClientAccount>) -> Decimal {
+    clients.values().map(|account| account.total()).sum()
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config::Policies;
+
+    #[test]
+    fn test_applied() {
+        let mut clients = HashMap::new();
+        let mut checker = InvariantChecker::new(InvariantCheck::Transaction, &clients, &[]);
+        let mut account = ClientAccount::new(1);
+        account
+            .deposit(Decimal::new(10, 0), &Policies::default())
+            .unwrap();
+        clients.insert(1, account);
+        let ten = Decimal::new(10, 0);
+        let mut journal = Journal::default();
+        let holds = VecDeque::new();
+        assert_eq!(
+            checker
+                .finished(&clients, &journal, &holds)
+                .unwrap_err()
+                .invariant,
+            "journal balance"
+        );
+        let postings = clients.get_mut(&1).unwrap().take_postings();
+        journal.post(0, 1, "deposit", postings);
+        assert!(checker
+            .applied(0, 1, 1, ten, Decimal::ZERO, &clients, &journal, &holds)
+            .is_ok());
+        let violation = checker
+            .applied(1, 2, 1, ten, ten, &clients, &journal, &holds)
+            .unwrap_err();
+        assert_eq!(violation.invariant, "account flow");
+        assert_eq!(violation.tx, Some(2));
+        assert_eq!(
+            checker
+                .finished(&clients, &journal, &holds)
+                .unwrap_err()
+                .invariant,
+            "system balance"
+        );
+    }
+
+    // Returns the accounts and journal after a deposit of 10 to client 1,
+    // and its applied record
+    fn deposited(policies: &Policies) -> (HashMap<u16, ClientAccount>, Journal, TransactionRecord) {
+        let mut account = ClientAccount::new(1);
+        account.deposit(Decimal::new(10, 0), policies).unwrap();
+        let mut journal = Journal::default();
+        journal.post(0, 1, "deposit", account.take_postings());
+        let mut record = TransactionRecord::new(1, Decimal::new(10, 0), 0);
+        record.settle(true);
+        (HashMap::from([(1, account)]), journal, record)
+    }
+
+    #[test]
+    fn test_rejected_record() {
+        let (clients, journal, applied) = deposited(&Policies::default());
+        let holds = VecDeque::new();
+        let mut checker =
+            InvariantChecker::new(InvariantCheck::Transaction, &clients, &[(1, applied)]);
+        // A second deposit of 10 was rejected, then disputed
+        let mut rejected = TransactionRecord::new(1, Decimal::new(10, 0), 1);
+        rejected.settle(false);
+        assert!(checker.record(2, &rejected).is_ok());
+        rejected.dispute();
+        let violation = checker.record(2, &rejected).unwrap_err();
+        assert_eq!(violation.invariant, "rejected record");
+        assert_eq!(violation.tx, Some(2));
+        assert_eq!(violation.client, Some(1));
+
+        // Checked at the end, it is reported once the run finished
+        let mut checker = InvariantChecker::new(InvariantCheck::End, &clients, &[(1, applied)]);
+        assert!(checker.record(2, &rejected).is_ok());
+        assert_eq!(
+            checker
+                .finished(&clients, &journal, &holds)
+                .unwrap_err()
+                .invariant,
+            "rejected record"
+        );
+    }
+
+    #[test]
+    fn test_held_funds() {
+        let policies = Policies {
+            dispute_requires_available: false,
+            ..Policies::default()
+        };
+        let (mut clients, mut journal, mut record) = deposited(&policies);
+        let holds = VecDeque::new();
+        let mut checker = InvariantChecker::new(InvariantCheck::Transaction, &clients, &[]);
+        // The deposit is disputed twice, holding 20
+        let account = clients.get_mut(&1).unwrap();
+        account.dispute(Decimal::new(10, 0), &policies).unwrap();
+        account.dispute(Decimal::new(10, 0), &policies).unwrap();
+        journal.post(1, 1, "dispute", account.take_postings());
+        record.dispute();
+        assert!(checker.record(1, &record).is_ok());
+        let violation = checker
+            .applied(
+                1,
+                1,
+                1,
+                Decimal::ZERO,
+                Decimal::new(10, 0),
+                &clients,
+                &journal,
+                &holds,
+            )
+            .unwrap_err();
+        assert_eq!(violation.invariant, "held funds");
+        assert_eq!(violation.expected, Decimal::new(10, 0));
+        assert_eq!(violation.actual, Decimal::new(20, 0));
+        assert_eq!(
+            checker
+                .finished(&clients, &journal, &holds)
+                .unwrap_err()
+                .invariant,
+            "held funds"
+        );
+    }
+
+    #[test]
+    fn test_pending_authorized_funds() {
+        let policies = Policies {
+            deposit_hold: Some(5),
+            ..Policies::default()
+        };
+        let (mut clients, mut journal, _) = deposited(&policies);
+        let checker = InvariantChecker::new(InvariantCheck::End, &clients, &[]);
+        // The deposit is pending without a hold releasing it
+        let mut holds = VecDeque::new();
+        assert_eq!(
+            checker
+                .finished(&clients, &journal, &holds)
+                .unwrap_err()
+                .invariant,
+            "pending funds"
+        );
+        holds.push_back(Hold {
+            tx: 1,
+            client: 1,
+            amount: Decimal::new(10, 0),
+            release: 5,
+            kind: HoldKind::Deposit,
+        });
+        assert!(checker.finished(&clients, &journal, &holds).is_ok());
+
+        // Funds are reserved without an open authorization
+        let account = clients.get_mut(&1).unwrap();
+        account.release(Decimal::new(10, 0)).unwrap();
+        account.authorize(Decimal::new(4, 0)).unwrap();
+        journal.post(1, 2, "authorize", account.take_postings());
+        holds.clear();
+        let mut checker = InvariantChecker::new(InvariantCheck::End, &clients, &[]);
+        assert_eq!(
+            checker
+                .finished(&clients, &journal, &holds)
+                .unwrap_err()
+                .invariant,
+            "authorized funds"
+        );
+        let mut authorization = TransactionRecord::authorization(1, Decimal::new(4, 0), 1);
+        authorization.settle(true);
+        assert!(checker.record(2, &authorization).is_ok());
+        assert!(checker.finished(&clients, &journal, &holds).is_ok());
+    }
+}
@@ -1 +1,9 @@
+pub mod fraud;
+pub mod invariants;
+pub mod pipeline;
+pub mod reconciler;
+pub mod server;
+pub mod snapshot;
 pub mod txprocessor;
+pub mod validator;
+pub mod watcher;
@@ -0,0 +1,293 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    mpsc::{self, Receiver, Sender},
+    Arc, Mutex,
+};
+
+use crate::config::EngineConfig;
+use crate::csv::reader::{self, CSVReaderError, ReadOptions, ReadReport};
+use crate::processors::{fraud::FraudMonitor, snapshot::Snapshot, txprocessor};
+use crate::store::{self, memory::MemoryStore, SharedStore, StoreError, TransactionStore};
+use crate::structs::{
+    adjustment::Adjustment, clients::ClientAccount, hold::Hold, journal::Journal,
+    registry::RegisteredClient, statement::StatementEntry, transaction::Transaction,
+};
+use futures::future::join_all;
+use tracing::{error, info, warn};
+
+// Ledgers shared by the pipeline tasks
+#[derive(Clone)]
+pub struct Ledgers {
+    // Client records on a HashMap, the key is the client's ID
+    pub clients: Arc<Mutex<HashMap<u16, ClientAccount>>>,
+    // Tx records, by tx's ID, in memory unless another store is given
+    pub transactions: SharedStore,
+    // Statement entries, in processing order, if recorded
+    pub statements: Option<Arc<Mutex<Vec<StatementEntry>>>>,
+    // Position of the next transaction in the processing order
+    pub sequence: Arc<AtomicU64>,
+    // Velocity state of the fraud rules and the transactions they flagged
+    pub fraud: Arc<Mutex<FraudMonitor>>,
+    // Deposits and authorizations on hold, in release order
+    pub holds: Arc<Mutex<VecDeque<Hold>>>,
+    // Manual adjustments, pending or approved, in processing order
+    pub adjustments: Arc<Mutex<Vec<Adjustment>>>,
+    // Double-entry journal the client balances are derived from
+    pub journal: Arc<Mutex<Journal>>,
+    // Whether the ledgers are a copy, whose transactions are committed by
+    // the caller once it keeps them
+    pub forked: bool,
+}
+
+impl Default for Ledgers {
+    fn default() -> Self {
+        Ledgers::with_store(MemoryStore::default(), false)
+    }
+}
+
+impl Ledgers {
+    /// Returns empty ledgers, keeping the transactions in memory
+    ///
+    /// # Arguments
+    ///
+    /// * `record_statements` - Records a statement entry for every transaction
+    pub fn new(record_statements: bool) -> Ledgers {
+        Ledgers::with_store(MemoryStore::default(), record_statements)
+    }
+
+    /// Returns ledgers without clients, keeping the transactions in the given store
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - Transaction store
+    /// * `record_statements` - Records a statement entry for every transaction
+    pub fn with_store(store: impl TransactionStore + 'static, record_statements: bool) -> Ledgers {
+        Ledgers {
+            clients: Arc::default(),
+            transactions: store::shared(store),
+            statements: record_statements.then(|| Arc::new(Mutex::new(Vec::new()))),
+            sequence: Arc::default(),
+            fraud: Arc::default(),
+            holds: Arc::default(),
+            adjustments: Arc::default(),
+            journal: Arc::default(),
+            forked: false,
+        }
+    }
+
+    /// Opens the accounts of the registered clients that don't have one yet,
+    /// and updates the tier and opening date of the others
+    ///
+    /// # Arguments
+    ///
+    /// * `registry` - Clients of the registry
+    pub fn register(&self, registry: &[RegisteredClient]) {
+        let mut clients = self.clients.lock().unwrap();
+        for registered in registry {
+            clients
+                .entry(registered.client)
+                .and_modify(|account| account.register(registered))
+                .or_insert_with(|| ClientAccount::open(registered));
+        }
+    }
+
+    /// Returns an independent copy of the ledgers, so a batch can be
+    /// processed and then either kept or thrown away.
+    /// The transactions written by the copy are only committed by `commit`
+    pub fn fork(&self) -> Ledgers {
+        Ledgers {
+            clients: Arc::new(Mutex::new(self.clients.lock().unwrap().clone())),
+            transactions: Arc::new(Mutex::new(self.transactions.lock().unwrap().fork())),
+            statements: self
+                .statements
+                .as_ref()
+                .map(|statements| Arc::new(Mutex::new(statements.lock().unwrap().clone()))),
+            sequence: Arc::new(AtomicU64::new(self.sequence.load(Ordering::Relaxed))),
+            fraud: Arc::new(Mutex::new(self.fraud.lock().unwrap().clone())),
+            holds: Arc::new(Mutex::new(self.holds.lock().unwrap().clone())),
+            adjustments: Arc::new(Mutex::new(self.adjustments.lock().unwrap().clone())),
+            journal: Arc::new(Mutex::new(self.journal.lock().unwrap().clone())),
+            forked: true,
+        }
+    }
+
+    /// Makes the transactions written to the ledgers durable. A persistent
+    /// store saves the rest of the engine state with them, so the next run
+    /// resumes from the same accounts, and a store keeping the accounts
+    /// writes every one
+    pub fn commit(&self) -> Result<(), StoreError> {
+        let mut store = self.transactions.lock().unwrap();
+        if store.is_persistent() {
+            store.save_state(serde_json::to_vec(&Snapshot::state(self))?)?;
+        }
+        if store.keeps_history() {
+            store.save_accounts(self.clients.lock().unwrap().values().cloned().collect())?;
+        }
+        store.commit()
+    }
+}
+
+// Outcome of a pipeline run
+pub struct PipelineRun {
+    // Row counts of the input, or the error that stopped the reader
+    pub input: Result<ReadReport, CSVReaderError>,
+    // Set by the process task once every transaction was processed
+    pub finished: Arc<AtomicBool>,
+    // Transactions rejected by the business rules
+    pub rejected: u64,
+}
+
+impl PipelineRun {
+    /// Whether every input row was read and processed
+    pub fn succeeded(&self) -> bool {
+        self.input.is_ok() && self.finished.load(Ordering::Relaxed)
+    }
+}
+
+/// Runs the reader, store and process tasks over the CSV files,
+/// applying the transactions to the given ledgers,
+/// and returns once every task finished.
+///
+/// # Arguments
+///
+/// * `inputs` - Paths, directories or glob patterns of the CSV files holding the transactions
+/// * `ledgers` - Ledgers the transactions are applied to
+/// * `read_options` - Error policy of the reader for malformed rows
+/// * `config` - Engine configuration, with the policies, rounding and limits
+pub async fn run(
+    inputs: Vec<String>,
+    ledgers: &Ledgers,
+    read_options: ReadOptions,
+    config: &EngineConfig,
+) -> PipelineRun {
+    info!(?inputs, "starting pipeline");
+
+    if let Some(path) = &config.registry.path {
+        match reader::read_registry(path) {
+            Ok(registry) => {
+                for registered in &registry {
+                    let tier = registered.tier.as_deref();
+                    if tier.is_some_and(|tier| !config.tiers.contains_key(tier)) {
+                        warn!(
+                            client = registered.client,
+                            tier, "unknown tier, no tier limits apply"
+                        );
+                    }
+                }
+                ledgers.register(&registry)
+            }
+            Err(e) => {
+                error!(error = %e, "failed reading the client registry");
+                return PipelineRun {
+                    input: Err(e),
+                    finished: Arc::default(),
+                    rejected: 0,
+                };
+            }
+        }
+    }
+
+    // Atomic flags to write the client's records to STDOUT
+    let start_write = Arc::new(AtomicBool::new(false));
+    let finished = Arc::clone(&start_write);
+
+    // Channels for the task communication
+    let (tx_transactions, rx_transactions): (Sender<Transaction>, Receiver<Transaction>) =
+        mpsc::channel();
+    let (tx_transactions2, rx_transactions2): (Sender<Transaction>, Receiver<Transaction>) =
+        mpsc::channel();
+
+    // Tasks handlers
+    let mut handlers = vec![];
+
+    // Reader task
+    let tx_clone_reader = tx_transactions.clone();
+    let handle_reader = tokio::spawn(async move {
+        let result = reader::read(tx_clone_reader, &inputs, &read_options);
+        if let Err(e) = &result {
+            error!(error = %e, "reader task failed");
+        }
+        result
+    });
+
+    // task that will store the Transactions to the HashMap
+    let config = Arc::new(config.clone());
+    let tl_store = Arc::clone(&ledgers.transactions);
+    let tx_store = tx_transactions2.clone();
+    let first_seq = ledgers.sequence.load(Ordering::Relaxed);
+    let config_store = Arc::clone(&config);
+    handlers.push(tokio::spawn(async move {
+        if let Err(e) = txprocessor::store_transactions(
+            rx_transactions,
+            tx_store,
+            tl_store,
+            first_seq,
+            config_store,
+        ) {
+            error!(error = %e, "store task failed");
+        }
+    }));
+
+    // task that will process the Transactions and, by the end,
+    // enable the writer task
+    let tl_process = Arc::clone(&ledgers.transactions);
+    let cl_process = Arc::clone(&ledgers.clients);
+    let st_process = ledgers.statements.clone();
+    let sq_process = Arc::clone(&ledgers.sequence);
+    let fr_process = Arc::clone(&ledgers.fraud);
+    let ho_process = Arc::clone(&ledgers.holds);
+    let ad_process = Arc::clone(&ledgers.adjustments);
+    let jo_process = Arc::clone(&ledgers.journal);
+
+    let handle_process = tokio::spawn(async {
+        txprocessor::process_transactions(
+            rx_transactions2,
+            tl_process,
+            cl_process,
+            start_write,
+            st_process,
+            sq_process,
+            fr_process,
+            ho_process,
+            ad_process,
+            jo_process,
+            config,
+        )
+        .unwrap_or_else(|e| {
+            error!(error = %e, "process task failed");
+            0
+        })
+    });
+
+    let results = join_all(handlers).await;
+
+    for result in results {
+        if let Err(e) = result {
+            error!(error = %e, "task panicked");
+        }
+    }
+    let input = handle_reader.await.unwrap_or_else(|e| {
+        error!(error = %e, "reader task panicked");
+        Err(CSVReaderError::ReadingError)
+    });
+    let rejected = handle_process.await.unwrap_or_else(|e| {
+        error!(error = %e, "process task panicked");
+        0
+    });
+
+    // Only the transactions of a complete run are made durable, the ones
+    // of a fork once it is kept
+    if input.is_ok() && finished.load(Ordering::Relaxed) && !ledgers.forked {
+        if let Err(e) = ledgers.commit() {
+            error!(error = %e, "failed committing the transactions");
+            finished.store(false, Ordering::Relaxed);
+        }
+    }
+
+    PipelineRun {
+        input,
+        finished,
+        rejected,
+    }
+}
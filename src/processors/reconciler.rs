@@ -0,0 +1,145 @@
+use crate::structs::clients::ClientAccount;
+use rust_decimal::prelude::*;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+
+// Kind of difference found between two account files
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscrepancyKind {
+    MissingLeft,
+    MissingRight,
+    AmountMismatch,
+    StatusMismatch,
+}
+
+// Discrepancy struct
+// `delta` is the right value minus the left value, for amount mismatches
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Discrepancy {
+    client: u16,
+    kind: DiscrepancyKind,
+    field: Option<&'static str>,
+    left: Option<String>,
+    right: Option<String>,
+    delta: Option<Decimal>,
+}
+
+/// Matches two sets of client balances by client id and returns every
+/// missing client, amount mismatch and account status difference,
+/// ordered by client id.
+///
+/// # Arguments
+///
+/// * `left` - Client balances of the first file, the key is the client's ID
+/// * `right` - Client balances of the second file, the key is the client's ID
+pub fn reconcile(
+    left: &HashMap<u16, ClientAccount>,
+    right: &HashMap<u16, ClientAccount>,
+) -> Vec<Discrepancy> {
+    let clients: BTreeSet<u16> = left.keys().chain(right.keys()).copied().collect();
+    let mut discrepancies = vec![];
+    for client in clients {
+        match (left.get(&client), right.get(&client)) {
+            (Some(l), Some(r)) => compare(l, r, &mut discrepancies),
+            (None, _) => discrepancies.push(missing(client, DiscrepancyKind::MissingLeft)),
+            (_, None) => discrepancies.push(missing(client, DiscrepancyKind::MissingRight)),
+        }
+    }
+    discrepancies
+}
+
+// Compares the fields of the same client on both files
+fn compare(left: &ClientAccount, right: &ClientAccount, discrepancies: &mut Vec<Discrepancy>) {
+    let amounts = [
+        ("available", left.available(), right.available()),
+        ("held", left.held(), right.held()),
+        ("pending", left.pending(), right.pending()),
+        ("total", left.total(), right.total()),
+    ];
+    for (field, l, r) in amounts {
+        if l != r {
+            discrepancies.push(Discrepancy {
+                client: left.client(),
+                kind: DiscrepancyKind::AmountMismatch,
+                field: Some(field),
+                left: Some(l.to_string()),
+                right: Some(r.to_string()),
+                delta: Some(r - l),
+            });
+        }
+    }
+    if left.status() != right.status() {
+        discrepancies.push(Discrepancy {
+            client: left.client(),
+            kind: DiscrepancyKind::StatusMismatch,
+            field: Some("status"),
+            left: Some(left.status().to_string()),
+            right: Some(right.status().to_string()),
+            delta: None,
+        });
+    }
+}
+
+fn missing(client: u16, kind: DiscrepancyKind) -> Discrepancy {
+    Discrepancy {
+        client,
+        kind,
+        field: None,
+        left: None,
+        right: None,
+        delta: None,
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config::Policies;
+
+    fn account(client: u16, deposit: i64) -> ClientAccount {
+        let mut account = ClientAccount::new(client);
+        account
+            .deposit(Decimal::new(deposit, 0), &Policies::default())
+            .unwrap();
+        account
+    }
+
+    #[test]
+    fn test_reconcile_matching() {
+        let left = HashMap::from([(1, account(1, 10)), (2, account(2, 5))]);
+        let right = left.clone();
+        assert!(reconcile(&left, &right).is_empty());
+    }
+
+    #[test]
+    fn test_reconcile_missing_clients() {
+        let left = HashMap::from([(1, account(1, 10))]);
+        let right = HashMap::from([(2, account(2, 10))]);
+        let discrepancies = reconcile(&left, &right);
+        assert_eq!(discrepancies.len(), 2);
+        assert_eq!(discrepancies[0].kind, DiscrepancyKind::MissingRight);
+        assert_eq!(discrepancies[1].kind, DiscrepancyKind::MissingLeft);
+    }
+
+    #[test]
+    fn test_reconcile_mismatches() {
+        let left = HashMap::from([(1, account(1, 10))]);
+        let mut other = account(1, 12);
+        other
+            .withdrawal(Decimal::new(2, 0), &Policies::default())
+            .unwrap();
+        other
+            .dispute(Decimal::new(10, 0), &Policies::default())
+            .unwrap();
+        other.chargeback(Decimal::new(10, 0)).unwrap();
+        let right = HashMap::from([(1, other)]);
+        let discrepancies = reconcile(&left, &right);
+        let fields: Vec<_> = discrepancies.iter().map(|d| d.field.unwrap()).collect();
+        assert_eq!(fields, vec!["available", "total", "status"]);
+        assert_eq!(discrepancies[0].delta, Some(Decimal::new(-10, 0)));
+        assert_eq!(discrepancies[2].kind, DiscrepancyKind::StatusMismatch);
+    }
+}
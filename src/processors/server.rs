@@ -0,0 +1,213 @@
+use crate::config::EngineConfig;
+use crate::csv::{
+    reader::ReadOptions,
+    writer::{self, CSVWriterError},
+};
+use crate::processors::{
+    pipeline::{self, Ledgers},
+    snapshot::{Snapshot, SnapshotError},
+};
+use crate::store::disk::DiskStore;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info, info_span, instrument, Instrument};
+
+// Server Error definition
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum ServeError {
+    #[error("Error accepting the connections")]
+    ListenError(#[from] io::Error),
+    #[error("Error persisting the engine state")]
+    StateError(#[from] SnapshotError),
+    #[error("Error writing the balances")]
+    BalancesError(#[from] CSVWriterError),
+}
+
+// Serve mode options
+#[derive(Clone, Debug)]
+pub struct ServeOptions {
+    // Engine state file, loaded at start and saved after each batch
+    pub state: PathBuf,
+    // Database file keeping the transactions, instead of the state file
+    pub tx_store: Option<PathBuf>,
+    pub read: ReadOptions,
+    pub config: EngineConfig,
+}
+
+/// Serves the connections of a listener, one at a time in the order they
+/// were accepted. A client sends a CSV file of transactions, with its
+/// header, and shuts down its side of the connection. The file is a batch:
+/// it is applied as a whole against the persisted engine state, and the
+/// client balances are sent back in the configuration's output format, or
+/// it is not applied at all and an `error:` line is sent back.
+///
+/// # Arguments
+///
+/// * `listener` - Bound listener the connections are accepted from
+/// * `options` - State, reader options and engine configuration
+#[instrument(name = "server", skip_all, fields(state = %options.state.display()))]
+pub async fn serve(listener: TcpListener, options: ServeOptions) -> Result<(), ServeError> {
+    let mut snapshot = if options.state.exists() {
+        Snapshot::load(&options.state)?
+    } else {
+        Snapshot::default()
+    };
+    let store = match &options.tx_store {
+        Some(path) => Some(Box::new(DiskStore::open(path).map_err(SnapshotError::from)?) as _),
+        None => None,
+    };
+    let mut ledgers = snapshot.ledgers(false, store)?;
+    info!(address = %listener.local_addr()?, "listening");
+
+    loop {
+        let (mut stream, peer) = listener.accept().await?;
+        let span = info_span!("connection", %peer);
+        match serve_batch(&mut stream, peer, &mut ledgers, &mut snapshot, &options)
+            .instrument(span)
+            .await
+        {
+            Ok(()) => (),
+            // A client that went away doesn't stop the server
+            Err(ServeError::ListenError(e)) => error!(error = %e, %peer, "connection failed"),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Applies the file sent on a connection to a fork of the ledgers, which
+// replaces them once the whole file was applied, and saves it in the snapshot
+async fn serve_batch(
+    stream: &mut TcpStream,
+    peer: SocketAddr,
+    ledgers: &mut Ledgers,
+    snapshot: &mut Snapshot,
+    options: &ServeOptions,
+) -> Result<(), ServeError> {
+    let mut input = Vec::new();
+    stream.read_to_end(&mut input).await?;
+    // The reader takes files, the batch is spooled next to the state file
+    let spool = spool_path(&options.state);
+    fs::write(&spool, &input)?;
+    let batch = ledgers.fork();
+    let run = pipeline::run(
+        vec![spool.to_string_lossy().into_owned()],
+        &batch,
+        options.read.clone(),
+        &options.config,
+    )
+    .await;
+    fs::remove_file(&spool)?;
+
+    if !run.succeeded() {
+        let reason = match &run.input {
+            Err(e) => e.to_string(),
+            Ok(_) => "the transactions could not be processed".to_string(),
+        };
+        error!(%peer, reason, "batch failed");
+        stream
+            .write_all(format!("error: {reason}\n").as_bytes())
+            .await?;
+        stream.shutdown().await?;
+        return Ok(());
+    }
+
+    // The state is saved before the transactions are committed, and the
+    // ledgers replaced before the commit, like the batches of the watch mode
+    snapshot.update(&batch, vec![], &options.state)?;
+    *ledgers = batch;
+    ledgers.commit().map_err(SnapshotError::from)?;
+    let mut balances = Vec::new();
+    writer::write_balances_to(
+        &mut balances,
+        &ledgers.clients.lock().unwrap(),
+        options.config.output.format,
+        options.config.output.sort,
+    )?;
+    stream.write_all(&balances).await?;
+    stream.shutdown().await?;
+    info!(
+        rows = run.input.map_or(0, |report| report.rows),
+        rejected = run.rejected,
+        "batch applied"
+    );
+    Ok(())
+}
+
+// File a received batch is written to while it is processed
+fn spool_path(state: &Path) -> PathBuf {
+    let mut name = state.file_name().unwrap_or_default().to_os_string();
+    name.push(".batch.csv");
+    state.with_file_name(name)
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::Shutdown;
+
+    // Sends each batch on its own connection from a client thread, while
+    // the server runs, returns the replies
+    async fn exchange(options: &ServeOptions, batches: Vec<&'static str>) -> Vec<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = tokio::task::spawn_blocking(move || {
+            batches
+                .into_iter()
+                .map(|batch| {
+                    let mut stream = std::net::TcpStream::connect(address).unwrap();
+                    stream.write_all(batch.as_bytes()).unwrap();
+                    stream.shutdown(Shutdown::Write).unwrap();
+                    let mut reply = String::new();
+                    stream.read_to_string(&mut reply).unwrap();
+                    reply
+                })
+                .collect()
+        });
+        tokio::select! {
+            served = serve(listener, options.clone()) => panic!("server stopped: {served:?}"),
+            replies = client => replies.unwrap(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_serve_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = ServeOptions {
+            state: dir.path().join("state.json"),
+            tx_store: None,
+            read: ReadOptions::default(),
+            config: EngineConfig::default(),
+        };
+        let replies = exchange(
+            &options,
+            vec![
+                "type,client,tx,amount\ndeposit,1,1,10.0\n",
+                "type,client,tx,amount\ndeposit,x,2,5.0\n",
+                "type,client,tx,amount\nwithdrawal,1,3,4.0\n",
+            ],
+        )
+        .await;
+        assert_eq!(
+            replies[0],
+            "client,available,held,pending,total,status\n\
+             1,10.0000,0.0000,0.0000,10.0000,active\n"
+        );
+        // A malformed batch is not applied
+        assert!(replies[1].starts_with("error: "));
+        assert!(replies[2].ends_with("1,6.0000,0.0000,0.0000,6.0000,active\n"));
+
+        // The state survives a restart
+        let replies = exchange(&options, vec!["type,client,tx,amount\ndispute,1,3,\n"]).await;
+        assert!(replies[0].ends_with("1,2.0000,4.0000,0.0000,6.0000,active\n"));
+        assert!(!spool_path(&options.state).exists());
+    }
+}
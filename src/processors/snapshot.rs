@@ -0,0 +1,439 @@
+use crate::csv::writer;
+use crate::processors::pipeline::Ledgers;
+use crate::store::{memory::MemoryStore, StoreError, TransactionStore};
+use crate::structs::{
+    adjustment::Adjustment,
+    clients::ClientAccount,
+    hold::Hold,
+    journal::{Journal, LedgerAccount},
+    transaction::TransactionRecord,
+};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use thiserror::Error;
+use tracing::{info, instrument};
+
+// Lines of the transaction log that may be outdated, replaced by later
+// ones, on top of one per transaction before it is compacted
+const COMPACT_AFTER: u64 = 1024;
+
+// Snapshot Error definition
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("Error accessing the snapshot file")]
+    FileError(#[from] io::Error),
+    #[error("Error encoding the snapshot")]
+    EncodingError(#[from] serde_json::Error),
+    #[error("Error accessing the transaction store")]
+    StoreError(#[from] StoreError),
+}
+
+// Engine state snapshot, persisted as JSON between runs
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Snapshot {
+    clients: Vec<ClientAccount>,
+    // Transactions or, when they are kept in a persistent store, the ones
+    // not committed to it yet
+    transactions: Vec<(u32, TransactionRecord)>,
+    // Position of the next transaction in the processing order
+    #[serde(default)]
+    sequence: u64,
+    // Deposits and authorizations on hold, in release order
+    #[serde(default)]
+    holds: Vec<Hold>,
+    // Manual adjustments, pending or approved
+    #[serde(default)]
+    adjustments: Vec<Adjustment>,
+    // Balances of the ledger accounts in the journal, its entries are not
+    // kept. Missing from the snapshots written before it
+    #[serde(default)]
+    ledger_balances: BTreeMap<LedgerAccount, Decimal>,
+    // Input files already applied to this state, but not yet moved away
+    // from the directory they were read from
+    pub pending_files: Vec<String>,
+    // Log holding the transactions kept in memory, when they are not
+    // written with the rest of the state
+    #[serde(default)]
+    log: Option<TransactionLog>,
+}
+
+// Transaction log of a snapshot, a file next to it with one transaction
+// record per line, the later lines replacing the earlier ones
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+struct TransactionLog {
+    // Incremented on every compaction, it names the file
+    generation: u64,
+    // Bytes of the file belonging to the snapshot, the ones after them
+    // were written by a save that didn't complete
+    len: u64,
+    // Lines written
+    lines: u64,
+    // Lines written by the last compaction, one per transaction
+    compacted: u64,
+}
+
+impl Snapshot {
+    /// Captures the client ledger and the transactions, only the uncommitted
+    /// ones when they are kept in a persistent store
+    ///
+    /// # Arguments
+    ///
+    /// * `ledgers` - Ledgers to capture, statements are not kept
+    /// * `pending_files` - Input files applied to the ledgers but not moved yet
+    pub fn capture(
+        ledgers: &Ledgers,
+        pending_files: Vec<String>,
+    ) -> Result<Snapshot, SnapshotError> {
+        let state = Snapshot::state(ledgers);
+        let store = ledgers.transactions.lock().unwrap();
+        Ok(Snapshot {
+            transactions: match store.is_persistent() {
+                true => store.uncommitted(),
+                false => store.records()?,
+            },
+            pending_files,
+            ..state
+        })
+    }
+
+    /// Captures the engine state without the transactions, the one a
+    /// persistent store keeps along with them
+    ///
+    /// # Arguments
+    ///
+    /// * `ledgers` - Ledgers to capture, statements are not kept
+    pub fn state(ledgers: &Ledgers) -> Snapshot {
+        Snapshot {
+            clients: ledgers.clients.lock().unwrap().values().cloned().collect(),
+            transactions: vec![],
+            sequence: ledgers.sequence.load(Ordering::Relaxed),
+            holds: ledgers.holds.lock().unwrap().iter().copied().collect(),
+            adjustments: ledgers.adjustments.lock().unwrap().clone(),
+            ledger_balances: ledgers.journal.lock().unwrap().balances().clone(),
+            pending_files: vec![],
+            log: None,
+        }
+    }
+
+    /// Replaces the snapshot's state with the ledgers' and saves it.
+    /// The transactions kept in memory are not all written again: the ones
+    /// written since the last commit are appended to the transaction log,
+    /// which is rewritten once most of its lines are outdated. Those of a
+    /// persistent store are saved until they are committed
+    ///
+    /// # Arguments
+    ///
+    /// * `ledgers` - Ledgers to save, statements are not kept
+    /// * `pending_files` - Input files applied to the ledgers but not moved yet
+    /// * `path` - Path of the snapshot file
+    #[instrument(name = "snapshot_update", skip(self, ledgers))]
+    pub fn update(
+        &mut self,
+        ledgers: &Ledgers,
+        pending_files: Vec<String>,
+        path: &Path,
+    ) -> Result<(), SnapshotError> {
+        let previous = self.log;
+        let store = ledgers.transactions.lock().unwrap();
+        let (transactions, log) = match previous {
+            _ if store.is_persistent() => (store.uncommitted(), None),
+            Some(log) if log.lines < 2 * log.compacted + COMPACT_AFTER => {
+                (vec![], Some(append_log(path, log, &store.uncommitted())?))
+            }
+            // The transactions written with a snapshot are moved to the log
+            _ => {
+                let generation = previous.map_or(0, |log| log.generation + 1);
+                let log = write_log(path, generation, &store.records()?)?;
+                info!(transactions = log.lines, "transaction log compacted");
+                (vec![], Some(log))
+            }
+        };
+        drop(store);
+        *self = Snapshot {
+            transactions,
+            pending_files,
+            log,
+            ..Snapshot::state(ledgers)
+        };
+        self.save(path)?;
+
+        // The replaced log is removed once the snapshot doesn't read it anymore
+        let generation = |log: Option<TransactionLog>| log.map(|log| log.generation);
+        match generation(previous) {
+            Some(replaced) if generation(log) != Some(replaced) => {
+                match fs::remove_file(log_path(path, replaced)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+                    _ => Ok(()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Loads the engine state a store keeps along with its transactions,
+    /// an empty one when it has none
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - Transaction store
+    pub fn stored(store: &dyn TransactionStore) -> Result<Snapshot, SnapshotError> {
+        match store.state()? {
+            Some(state) => Ok(serde_json::from_slice(&state)?),
+            None => Ok(Snapshot::default()),
+        }
+    }
+
+    /// Returns ledgers holding the snapshot's clients and transactions.
+    /// A snapshot without ledger balances gets opening entries for the funds
+    /// of its accounts
+    ///
+    /// # Arguments
+    ///
+    /// * `record_statements` - Records a statement entry for every transaction
+    /// * `store` - Store the transactions are kept in, in memory when `None`.
+    ///   The snapshot's transactions are added to it
+    pub fn ledgers(
+        &self,
+        record_statements: bool,
+        store: Option<Box<dyn TransactionStore>>,
+    ) -> Result<Ledgers, SnapshotError> {
+        let clients: HashMap<u16, ClientAccount> = self
+            .clients
+            .iter()
+            .map(|account| (account.client(), account.clone()))
+            .collect();
+        let mut store = store.unwrap_or_else(|| Box::new(MemoryStore::default()));
+        for (tx, record) in &self.transactions {
+            store.insert(*tx, *record)?;
+        }
+        store.commit()?;
+        let mut journal = Journal::with_balances(self.ledger_balances.clone());
+        if journal.balances().is_empty() {
+            let mut accounts: Vec<&ClientAccount> = clients.values().collect();
+            accounts.sort_by_key(|account| account.client());
+            for account in accounts {
+                journal.post(self.sequence, 0, "opening", account.opening_postings());
+            }
+        }
+        Ok(Ledgers {
+            clients: Arc::new(Mutex::new(clients)),
+            transactions: Arc::new(Mutex::new(store)),
+            sequence: Arc::new(AtomicU64::new(self.sequence)),
+            holds: Arc::new(Mutex::new(self.holds.iter().copied().collect())),
+            adjustments: Arc::new(Mutex::new(self.adjustments.clone())),
+            journal: Arc::new(Mutex::new(journal)),
+            ..Ledgers::new(record_statements)
+        })
+    }
+
+    /// Loads a snapshot file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the snapshot file
+    #[instrument(name = "snapshot_load")]
+    pub fn load(path: &Path) -> Result<Snapshot, SnapshotError> {
+        let mut snapshot: Snapshot = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        if let Some(log) = snapshot.log {
+            let file = File::open(log_path(path, log.generation))?;
+            for line in BufReader::new(file.take(log.len)).lines() {
+                snapshot.transactions.push(serde_json::from_str(&line?)?);
+            }
+        }
+        info!(
+            clients = snapshot.clients.len(),
+            transactions = snapshot.transactions.len(),
+            "snapshot loaded"
+        );
+        Ok(snapshot)
+    }
+
+    /// Saves the snapshot, replacing the file atomically
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the snapshot file
+    #[instrument(name = "snapshot_save", skip(self))]
+    pub fn save(&self, path: &Path) -> Result<(), SnapshotError> {
+        writer::write_atomically(path, |file| {
+            serde_json::to_writer(file, self).map_err(SnapshotError::from)
+        })
+    }
+}
+
+// File of the transaction log of a snapshot
+fn log_path(path: &Path, generation: u64) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".transactions.{generation}"));
+    path.with_file_name(name)
+}
+
+// Encodes the records as lines of the transaction log
+fn log_lines(records: &[(u32, TransactionRecord)]) -> Result<Vec<u8>, SnapshotError> {
+    let mut lines = Vec::new();
+    for record in records {
+        serde_json::to_writer(&mut lines, record)?;
+        lines.push(b'\n');
+    }
+    Ok(lines)
+}
+
+// Appends the records to the transaction log, after the bytes belonging
+// to the snapshot
+fn append_log(
+    path: &Path,
+    log: TransactionLog,
+    records: &[(u32, TransactionRecord)],
+) -> Result<TransactionLog, SnapshotError> {
+    let lines = log_lines(records)?;
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(log_path(path, log.generation))?;
+    file.set_len(log.len)?;
+    file.write_all(&lines)?;
+    file.sync_data()?;
+    Ok(TransactionLog {
+        len: log.len + lines.len() as u64,
+        lines: log.lines + records.len() as u64,
+        ..log
+    })
+}
+
+// Writes a new transaction log holding the records
+fn write_log(
+    path: &Path,
+    generation: u64,
+    records: &[(u32, TransactionRecord)],
+) -> Result<TransactionLog, SnapshotError> {
+    let lines = log_lines(records)?;
+    writer::write_atomically(&log_path(path, generation), |file| {
+        file.write_all(&lines).map_err(SnapshotError::from)
+    })?;
+    Ok(TransactionLog {
+        generation,
+        len: lines.len() as u64,
+        lines: records.len() as u64,
+        compacted: records.len() as u64,
+    })
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config::Policies;
+    use crate::store::disk::DiskStore;
+
+    #[test]
+    fn test_save_load() {
+        let ledgers = Ledgers::new(false);
+        let mut account = ClientAccount::new(3);
+        account
+            .deposit(Decimal::new(125, 1), &Policies::default())
+            .unwrap();
+        ledgers.clients.lock().unwrap().insert(3, account);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        Snapshot::capture(&ledgers, vec!["day1.csv".to_string()])
+            .unwrap()
+            .save(&path)
+            .unwrap();
+
+        let snapshot = Snapshot::load(&path).unwrap();
+        assert_eq!(snapshot.pending_files, vec!["day1.csv".to_string()]);
+        let restored = snapshot.ledgers(false, None).unwrap();
+        // The opening balances are kept by the next snapshot
+        let recaptured = Snapshot::capture(&restored, vec![]).unwrap();
+        assert_eq!(recaptured.ledger_balances.len(), 2);
+        let clients = restored.clients.lock().unwrap();
+        assert_eq!(clients[&3].available(), Decimal::new(125, 1));
+        let journal = restored.journal.lock().unwrap();
+        assert_eq!(
+            journal.balance(LedgerAccount::Available(3)),
+            Decimal::new(125, 1)
+        );
+        assert!(journal.entries().is_empty());
+        assert!(!path.with_file_name("state.json.tmp").exists());
+    }
+
+    #[test]
+    fn test_update() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let insert = |ledgers: &Ledgers, tx: u32| {
+            let record = TransactionRecord::new(1, Decimal::new(tx.into(), 0), tx.into());
+            ledgers
+                .transactions
+                .lock()
+                .unwrap()
+                .insert(tx, record)
+                .unwrap();
+        };
+        let mut snapshot = Snapshot::default();
+        let ledgers = Ledgers::new(false);
+        insert(&ledgers, 1);
+        snapshot.update(&ledgers, vec![], &path).unwrap();
+        ledgers.commit().unwrap();
+        // Only the transaction written since the commit is appended
+        insert(&ledgers, 2);
+        snapshot.update(&ledgers, vec![], &path).unwrap();
+        ledgers.commit().unwrap();
+        let log = snapshot.log.unwrap();
+        assert_eq!((log.generation, log.lines, log.compacted), (0, 2, 1));
+        assert!(snapshot.transactions.is_empty());
+
+        let mut loaded = Snapshot::load(&path).unwrap();
+        assert_eq!(loaded.transactions.len(), 2);
+
+        // The outdated log is replaced by a compacted one
+        loaded.log = Some(TransactionLog {
+            lines: COMPACT_AFTER + 2,
+            ..log
+        });
+        let restored = loaded.ledgers(false, None).unwrap();
+        loaded.update(&restored, vec![], &path).unwrap();
+        let log = loaded.log.unwrap();
+        assert_eq!((log.generation, log.lines, log.compacted), (1, 2, 2));
+        assert!(!log_path(&path, 0).exists());
+        let restored = Snapshot::load(&path).unwrap().ledgers(false, None).unwrap();
+        let store = restored.transactions.lock().unwrap();
+        assert_eq!(store.get(2).unwrap().unwrap().amount(), Decimal::new(2, 0));
+    }
+
+    #[test]
+    fn test_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transactions.redb");
+        let ledgers = Ledgers::with_store(DiskStore::open(&path).unwrap(), false);
+        let mut account = ClientAccount::new(3);
+        account
+            .deposit(Decimal::new(125, 1), &Policies::default())
+            .unwrap();
+        ledgers.clients.lock().unwrap().insert(3, account);
+        ledgers.sequence.store(4, Ordering::Relaxed);
+        ledgers.commit().unwrap();
+        drop(ledgers);
+
+        let store = DiskStore::open(&path).unwrap();
+        let restored = Snapshot::stored(&store)
+            .unwrap()
+            .ledgers(false, Some(Box::new(store)))
+            .unwrap();
+        assert_eq!(
+            restored.clients.lock().unwrap()[&3].available(),
+            Decimal::new(125, 1)
+        );
+        assert_eq!(restored.sequence.load(Ordering::Relaxed), 4);
+    }
+}
@@ -1,21 +1,95 @@
+use crate::config::{
+    EngineConfig, Flow, FraudAction, InvariantCheck, Policies, UnknownClientPolicy,
+};
+use crate::processors::{
+    fraud::FraudMonitor,
+    invariants::{InvariantChecker, Violation},
+};
+use crate::store::{SharedStore, StoreError};
 use crate::structs::{
-    clients::ClientAccount,
-    transaction::{Transaction, TransactionRecord},
+    adjustment::{Adjustment, AdjustmentStatus},
+    clients::{ClientAccount, ClientError},
+    hold::{Hold, HoldKind},
+    journal::Journal,
+    statement::StatementEntry,
+    transaction::{AuthorizationState, RecordKind, RecordStatus, Transaction, TransactionRecord},
 };
 use rust_decimal::prelude::*;
-use std::collections::HashMap;
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    mpsc::{Receiver, Sender},
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    mpsc::{Receiver, SendError, Sender},
     Arc, Mutex,
 };
 use thiserror::Error;
+use tracing::{debug, debug_span, error, info, instrument};
 
 // TX Processor Error definition
 #[derive(Error, Debug)]
 pub enum TXProcessError {
     #[error("Invalid Transaction")]
     InvalidTxType,
+    #[error("Failed forwarding the transaction")]
+    ForwardingError(#[source] Box<SendError<Transaction>>),
+    #[error("{0}")]
+    ClientRejection(#[from] ClientError),
+    #[error("unknown transaction")]
+    UnknownTransaction,
+    #[error("unknown client")]
+    UnknownClient,
+    #[error("transaction belongs to another client")]
+    ClientMismatch,
+    #[error("transaction not under dispute")]
+    NotDisputed,
+    #[error("amount above the {0} limit")]
+    LimitExceeded(&'static str),
+    #[error("{limit} limit of the `{tier}` tier exceeded")]
+    TierLimitExceeded { limit: &'static str, tier: String },
+    #[error("dispute window expired")]
+    DisputeWindowExpired,
+    #[error("authorization not open")]
+    AuthorizationNotOpen,
+    #[error("authorization not captured")]
+    NotCaptured,
+    #[error("capture above the authorized amount")]
+    CaptureExceedsAuthorization,
+    #[error("only deposits can be refunded")]
+    NotRefundable,
+    #[error("refund above the unrefunded amount")]
+    RefundExceedsAmount,
+    #[error("transaction fully refunded")]
+    FullyRefunded,
+    #[error("transaction under dispute")]
+    AlreadyDisputed,
+    #[error("transaction charged back")]
+    ChargedBack,
+    #[error("transaction was rejected")]
+    RejectedTransaction,
+    #[error("adjustment requires an amount, a reason code and an operator")]
+    IncompleteAdjustment,
+    #[error("duplicate adjustment id")]
+    DuplicateAdjustment,
+    #[error("approval requires an operator")]
+    MissingApprover,
+    #[error("adjustment must be approved by another operator")]
+    SameOperator,
+    #[error("adjustment already approved")]
+    AlreadyApproved,
+    #[error("account status change requires a reason code")]
+    MissingReason,
+    #[error("rejected by the fraud rule `{0}`")]
+    FraudRejected(String),
+    #[error("{0}")]
+    InvariantViolation(Violation),
+    #[error("transaction store failed: {0}")]
+    StorageError(#[from] StoreError),
+}
+
+// The unsent transaction is boxed, it is much larger than the other errors
+impl From<SendError<Transaction>> for TXProcessError {
+    fn from(e: SendError<Transaction>) -> Self {
+        TXProcessError::ForwardingError(Box::new(e))
+    }
 }
 
 /// Parse the Transactinos to Transaction Records
@@ -28,208 +102,1255 @@ pub enum TXProcessError {
 ///
 /// * `rx_channel` - Receiver channel that will receive the Transactions read
 /// * `tx_channel` - Sender channel where the Transactions will be send
-/// * `tx_ledger` - Transaction HashMap that holds deposit and withdrawals
-/// the transaction ID is the key for the Transaction record associated
+/// * `tx_ledger` - Transaction store that holds deposit and withdrawals
+///   the transaction ID is the key for the Transaction record associated
+/// * `first_seq` - Position of the first transaction in the processing order
+/// * `config` - Engine configuration, for the amounts rounding
+#[instrument(name = "store", skip_all)]
 pub fn store_transactions(
     rx_channel: Receiver<Transaction>,
     tx_channel: Sender<Transaction>,
-    tx_ledger: Arc<Mutex<HashMap<u32, TransactionRecord>>>,
+    tx_ledger: SharedStore,
+    first_seq: u64,
+    config: Arc<EngineConfig>,
 ) -> Result<(), TXProcessError> {
     // Number of retries before finish the thread
     let mut retry: u32 = 100000;
     let mut stop = false;
+    let mut stored: u64 = 0;
+    let mut seq = first_seq;
     while !stop {
         // Tries to receive a Transaction
         if let Ok(transaction) = rx_channel.try_recv() {
             let tx_clone = transaction.clone();
+            seq += 1;
             match transaction.tx_type().as_str() {
-                "deposit" | "withdrawal" => {
+                kind @ ("deposit" | "withdrawal") => {
+                    let amount = config.round(tx_clone.amount().unwrap_or(Decimal::new(0, 4)));
+                    let record = match kind {
+                        "withdrawal" => TransactionRecord::withdrawal,
+                        _ => TransactionRecord::new,
+                    };
                     tx_ledger
                         .lock()
                         .unwrap()
-                        .insert(tx_clone.tx(), TransactionRecord::from(&tx_clone));
-                    tx_channel.send(tx_clone).unwrap()
+                        .insert(tx_clone.tx(), record(tx_clone.client(), amount, seq - 1))?;
+                    tx_channel.send(tx_clone)?;
+                    stored += 1;
                 }
-                "dispute" | "resolve" | "chargeback" => {
-                    tx_channel.send(tx_clone).unwrap();
+                "authorize" => {
+                    let amount = config.round(tx_clone.amount().unwrap_or(Decimal::new(0, 4)));
+                    tx_ledger.lock().unwrap().insert(
+                        tx_clone.tx(),
+                        TransactionRecord::authorization(tx_clone.client(), amount, seq - 1),
+                    )?;
+                    tx_channel.send(tx_clone)?;
+                    stored += 1;
+                }
+                "dispute" | "resolve" | "chargeback" | "capture" | "void" | "refund"
+                | "adjustment" | "approve" | "lock" | "unlock" | "freeze" | "close" => {
+                    tx_channel.send(tx_clone)?;
+                }
+                kind => {
+                    error!(tx = tx_clone.tx(), kind, "invalid transaction type");
+                    return Err(TXProcessError::InvalidTxType);
                 }
-                _ => return Err(TXProcessError::InvalidTxType),
             }
         } else {
             // If no message is received, try again
-            retry = retry - 1;
+            retry -= 1;
             if retry == 0 {
                 stop = true;
             }
         }
     }
+    info!(stored, "finished storing transactions");
     Ok(())
 }
 
 /// Process the transactions, performing the transaction actions, by type.
 /// After it finishes runs, it sets the start_writing flag to true,
-/// starting the writer thread.
+/// and returns the number of transactions rejected by the business rules.
 /// This function is designed to run in a thread.
 ///
 /// # Arguments
 ///
 /// * `rx_channel` - Receiver channel that will receive the Transactions read
-/// * `tx_ledger` - Transaction HashMap that holds deposit and withdrawals
-/// the transaction ID is the key for the Transaction record associated
+/// * `tx_ledger` - Transaction store that holds deposit and withdrawals
+///   the transaction ID is the key for the Transaction record associated,
+///   every processed transaction is recorded if it keeps the history
 /// * `client_ledger` - ClientAccount HashMap that holds clients balance and status,
-/// the client ID is the key for the ClientAccount associated
+///   the client ID is the key for the ClientAccount associated
 /// * `start_writing` - Boolean that starts the writing thread
+/// * `statements` - Optional list where an entry is recorded for every
+///   transaction, applied or rejected, with the client's running balances
+/// * `sequence` - Position of the next transaction in the processing order
+/// * `fraud` - Velocity state the deposits and withdrawals are screened against
+/// * `holds` - Deposits on hold, released once their position is reached
+/// * `adjustments` - Manual adjustments, pending until approved
+/// * `journal` - Double-entry journal where the postings of every applied
+///   transaction and released hold are recorded
+/// * `config` - Engine configuration, with the policies, limits, fraud rules
+///   and when the invariants are checked
+#[allow(clippy::too_many_arguments)]
+#[instrument(name = "process", skip_all)]
 pub fn process_transactions(
     rx_channel: Receiver<Transaction>,
-    tx_ledger: Arc<Mutex<HashMap<u32, TransactionRecord>>>,
+    tx_ledger: SharedStore,
     client_ledger: Arc<Mutex<HashMap<u16, ClientAccount>>>,
     start_writing: Arc<AtomicBool>,
-) -> Result<(), TXProcessError> {
+    statements: Option<Arc<Mutex<Vec<StatementEntry>>>>,
+    sequence: Arc<AtomicU64>,
+    fraud: Arc<Mutex<FraudMonitor>>,
+    holds: Arc<Mutex<VecDeque<Hold>>>,
+    adjustments: Arc<Mutex<Vec<Adjustment>>>,
+    journal: Arc<Mutex<Journal>>,
+    config: Arc<EngineConfig>,
+) -> Result<u64, TXProcessError> {
     // Number of retries before finish the thread
     let mut retry: u32 = 100000;
     let mut stop = false;
+    let mut processed: u64 = 0;
+    let mut rejected: u64 = 0;
+    let keeps_history = tx_ledger.lock().unwrap().keeps_history();
+    let mut checker = match config
+        .invariants
+        .check
+        .or(cfg!(debug_assertions).then_some(InvariantCheck::End))
+    {
+        Some(check) => {
+            let records = tx_ledger.lock().unwrap().records()?;
+            Some(InvariantChecker::new(
+                check,
+                &client_ledger.lock().unwrap(),
+                &records,
+            ))
+        }
+        None => None,
+    };
     while !stop {
         // Tries to receive a Transaction
         if let Ok(transaction) = rx_channel.try_recv() {
             let tx_clone = transaction.clone();
-            match tx_clone.tx_type().as_ref() {
-                "deposit" => {
-                    deposit(
-                        Arc::clone(&client_ledger),
-                        transaction.client(),
-                        Decimal::from_f32(transaction.amount().unwrap_or(0.000))
-                            .unwrap_or(Decimal::new(0, 4))
-                            .round_dp(4),
-                    )
-                    .unwrap();
+            let kind = tx_clone.tx_type();
+            let file = transaction.provenance().map(|p| &*p.file);
+            let line = transaction.provenance().map(|p| p.line);
+            let _span = debug_span!(
+                "transaction",
+                tx = transaction.tx(),
+                client = transaction.client(),
+                kind = kind.as_str(),
+                file,
+                line
+            )
+            .entered();
+            let seq = sequence.fetch_add(1, Ordering::Relaxed);
+            let released = match release_holds(
+                Arc::clone(&client_ledger),
+                &tx_ledger,
+                &holds,
+                &journal,
+                seq,
+            ) {
+                Ok(released) => released,
+                Err(e) => {
+                    error!(error = %e, "transaction store failed");
+                    return Err(e);
                 }
-                "withdrawal" => {
-                    withdrawal(
-                        Arc::clone(&client_ledger),
-                        transaction.client(),
-                        Decimal::from_f32(transaction.amount().unwrap_or(0.000))
-                            .unwrap_or(Decimal::new(0, 4))
-                            .round_dp(4),
-                    )
-                    .unwrap();
+            };
+            let amount = config.round(transaction.amount().unwrap_or(Decimal::new(0, 4)));
+            let before = match &checker {
+                Some(_) => Some(balances_before(
+                    &client_ledger,
+                    &tx_ledger,
+                    &transaction,
+                    &kind,
+                )?),
+                None => None,
+            };
+            let flow = match kind.as_str() {
+                "deposit" => Some(Flow::Deposit),
+                "withdrawal" => Some(Flow::Withdrawal),
+                _ => None,
+            };
+            let screened = match flow {
+                Some(flow) if !config.fraud.rules.is_empty() => screen(
+                    Arc::clone(&client_ledger),
+                    &fraud,
+                    &transaction,
+                    seq,
+                    flow,
+                    amount,
+                    &config,
+                ),
+                _ => Ok(()),
+            };
+            let result = match kind.as_str() {
+                _ if screened.is_err() => screened,
+                "deposit" => deposit(
+                    Arc::clone(&client_ledger),
+                    transaction.client(),
+                    amount,
+                    &config,
+                ),
+                "withdrawal" => withdrawal(
+                    Arc::clone(&client_ledger),
+                    transaction.client(),
+                    amount,
+                    seq,
+                    &config,
+                ),
+                "authorize" => authorize(
+                    Arc::clone(&client_ledger),
+                    Arc::clone(&tx_ledger),
+                    transaction.tx(),
+                    transaction.client(),
+                    amount,
+                ),
+                "capture" => capture(
+                    Arc::clone(&client_ledger),
+                    Arc::clone(&tx_ledger),
+                    &holds,
+                    transaction.tx(),
+                    transaction.client(),
+                    transaction.amount().map(|_| amount),
+                ),
+                "void" => void(
+                    Arc::clone(&client_ledger),
+                    Arc::clone(&tx_ledger),
+                    &holds,
+                    transaction.tx(),
+                    transaction.client(),
+                ),
+                "adjustment" => adjustment(
+                    &adjustments,
+                    &transaction,
+                    transaction.amount().map(|_| amount),
+                    seq,
+                ),
+                "approve" => approve(
+                    Arc::clone(&client_ledger),
+                    &adjustments,
+                    &transaction,
+                    seq,
+                    &config.policies,
+                ),
+                "lock" | "unlock" | "freeze" | "close" => administer(
+                    Arc::clone(&client_ledger),
+                    &transaction,
+                    &kind,
+                    &config.policies,
+                ),
+                "refund" => refund(
+                    Arc::clone(&client_ledger),
+                    Arc::clone(&tx_ledger),
+                    &holds,
+                    transaction.tx(),
+                    transaction.client(),
+                    transaction.amount().map(|_| amount),
+                ),
+                "dispute" => dispute(
+                    Arc::clone(&client_ledger),
+                    Arc::clone(&tx_ledger),
+                    &holds,
+                    transaction.tx(),
+                    transaction.client(),
+                    seq,
+                    &config,
+                ),
+                "resolve" => resolve(
+                    Arc::clone(&client_ledger),
+                    Arc::clone(&tx_ledger),
+                    transaction.tx(),
+                    transaction.client(),
+                ),
+                "chargeback" => chargeback(
+                    Arc::clone(&client_ledger),
+                    Arc::clone(&tx_ledger),
+                    transaction.tx(),
+                    transaction.client(),
+                ),
+                _ => {
+                    error!("invalid transaction type");
+                    return Err(TXProcessError::InvalidTxType);
                 }
-                "dispute" => {
-                    dispute(
-                        Arc::clone(&client_ledger),
-                        Arc::clone(&tx_ledger),
-                        transaction.tx(),
-                        transaction.client(),
-                    )
-                    .unwrap();
+            };
+            if let Err(e @ TXProcessError::StorageError(_)) = result {
+                error!(error = %e, "transaction store failed");
+                return Err(e);
+            }
+            if let "deposit" | "withdrawal" | "authorize" = kind.as_str() {
+                if let Err(e) = settle(&tx_ledger, transaction.tx(), result.is_ok()) {
+                    error!(error = %e, "transaction store failed");
+                    return Err(e);
                 }
-                "resolve" => {
-                    resolve(
-                        Arc::clone(&client_ledger),
-                        Arc::clone(&tx_ledger),
-                        transaction.tx(),
-                        transaction.client(),
-                    )
-                    .unwrap();
+            }
+            post_journal(
+                &client_ledger,
+                &journal,
+                transaction.client(),
+                seq,
+                transaction.tx(),
+                &kind,
+            );
+            let hold = match kind.as_str() {
+                "deposit" => config
+                    .policies
+                    .deposit_hold
+                    .map(|hold| (HoldKind::Deposit, hold)),
+                "authorize" => config
+                    .policies
+                    .authorization_expiry
+                    .map(|expiry| (HoldKind::Authorization, expiry)),
+                _ => None,
+            };
+            if let (Ok(()), Some((hold_kind, length))) = (&result, hold) {
+                let hold = Hold {
+                    tx: transaction.tx(),
+                    client: transaction.client(),
+                    amount,
+                    release: seq + length,
+                    kind: hold_kind,
+                };
+                add_hold(&holds, hold);
+            }
+            if let Some(checker) = &mut checker {
+                let expired = released
+                    .iter()
+                    .filter(|hold| hold.kind == HoldKind::Authorization)
+                    .map(|hold| hold.tx);
+                let referenced = match kind.as_str() {
+                    "adjustment" | "approve" | "lock" | "unlock" | "freeze" | "close" => None,
+                    _ => Some(transaction.tx()),
+                };
+                let checked = check_records(checker, &tx_ledger, expired.chain(referenced))?;
+                if let Err(violation) = checked {
+                    error!(%violation, file, line, "invariant violated");
+                    return Err(TXProcessError::InvariantViolation(violation));
+                }
+            }
+            if let (Ok(()), Some(checker), Some((total, refunded))) =
+                (&result, &mut checker, before)
+            {
+                let moved = money_moved(
+                    &tx_ledger,
+                    &adjustments,
+                    &transaction,
+                    &kind,
+                    amount,
+                    refunded,
+                )?;
+                let cl = client_ledger.lock().unwrap();
+                let checked = checker.applied(
+                    seq,
+                    transaction.tx(),
+                    transaction.client(),
+                    moved,
+                    total,
+                    &cl,
+                    &journal.lock().unwrap(),
+                    &holds.lock().unwrap(),
+                );
+                if let Err(violation) = checked {
+                    error!(%violation, file, line, "invariant violated");
+                    return Err(TXProcessError::InvariantViolation(violation));
                 }
-                "chargeback" => {
-                    chargeback(
-                        Arc::clone(&client_ledger),
-                        Arc::clone(&tx_ledger),
-                        transaction.tx(),
+            }
+            if let (Ok(()), Some(flow)) = (&result, flow) {
+                if !config.fraud.rules.is_empty() {
+                    fraud.lock().unwrap().record(
+                        &config.fraud.rules,
+                        seq,
                         transaction.client(),
-                    )
-                    .unwrap();
+                        flow,
+                        amount,
+                    );
+                }
+            }
+            match &result {
+                Ok(()) => debug!("transaction processed"),
+                Err(e) => {
+                    info!(
+                        tx = transaction.tx(),
+                        file,
+                        line,
+                        error = %e,
+                        "transaction rejected"
+                    );
+                    rejected += 1;
+                }
+            }
+            processed += 1;
+            if statements.is_some() || keeps_history {
+                let touched = touched_accounts(&client_ledger, transaction.client(), &released);
+                let account = touched
+                    .iter()
+                    .find(|account| account.client() == transaction.client());
+                let blank = ClientAccount::new(transaction.client());
+                let entry = StatementEntry::new(
+                    processed,
+                    &transaction,
+                    transaction.amount().map(|_| amount),
+                    result.err().map(|e| e.to_string()),
+                    account.unwrap_or(&blank),
+                );
+                if keeps_history {
+                    if let Err(e) = tx_ledger.lock().unwrap().record(seq, &entry, &touched) {
+                        error!(error = %e, "transaction store failed");
+                        return Err(e.into());
+                    }
+                }
+                if let Some(statements) = &statements {
+                    statements.lock().unwrap().push(entry);
                 }
-                _ => return Err(TXProcessError::InvalidTxType),
             }
         } else {
             // If no message is received, try again
-            retry = retry - 1;
+            retry -= 1;
             if retry == 0 {
                 stop = true;
             }
         }
     }
+    if let Some(checker) = &checker {
+        let checked = checker.finished(
+            &client_ledger.lock().unwrap(),
+            &journal.lock().unwrap(),
+            &holds.lock().unwrap(),
+        );
+        if let Err(violation) = checked {
+            error!(%violation, "invariant violated");
+            return Err(TXProcessError::InvariantViolation(violation));
+        }
+    }
+    info!(processed, rejected, "finished processing transactions");
     // Sets the flag to start writing thread.
     start_writing.store(true, Ordering::Relaxed);
+    Ok(rejected)
+}
+
+/// Releases the holds whose position in the processing order was reached,
+/// making the pending deposits available and expiring the authorizations.
+/// Returns the released holds
+///
+/// # Arguments
+///
+/// * `client_ledger` - ClientAccount HashMap that holds clients balance and status,
+///   the client ID is the key for the ClientAccount associated
+/// * `tx_ledger` - Transaction store, where the expired authorizations are closed
+/// * `holds` - Deposits and authorizations on hold, in release order
+/// * `journal` - Journal where the postings of the releases are recorded
+/// * `seq` - Position of the transaction about to be processed
+fn release_holds(
+    client_ledger: Arc<Mutex<HashMap<u16, ClientAccount>>>,
+    tx_ledger: &SharedStore,
+    holds: &Mutex<VecDeque<Hold>>,
+    journal: &Mutex<Journal>,
+    seq: u64,
+) -> Result<Vec<Hold>, TXProcessError> {
+    let mut holds = holds.lock().unwrap();
+    let mut released_holds = Vec::new();
+    while let Some(hold) = holds.front().filter(|hold| hold.release <= seq).copied() {
+        holds.pop_front();
+        released_holds.push(hold);
+        let mut cl = client_ledger.lock().unwrap();
+        let released = cl.get_mut(&hold.client).map(|account| match hold.kind {
+            HoldKind::Deposit => account.release(hold.amount),
+            HoldKind::Authorization => account.void(hold.amount),
+        });
+        match released {
+            Some(Ok(())) => {
+                debug!(tx = hold.tx, client = hold.client, kind = ?hold.kind, "hold released")
+            }
+            _ => error!(
+                tx = hold.tx,
+                client = hold.client,
+                kind = ?hold.kind,
+                "failed releasing the hold"
+            ),
+        }
+        let release = match hold.kind {
+            HoldKind::Deposit => "release",
+            HoldKind::Authorization => "expire",
+        };
+        if let Some(account) = cl.get_mut(&hold.client) {
+            let postings = account.take_postings();
+            journal
+                .lock()
+                .unwrap()
+                .post(seq, hold.tx, release, postings);
+        }
+        drop(cl);
+        if hold.kind == HoldKind::Authorization {
+            let mut tl = tx_ledger.lock().unwrap();
+            if let Some(mut record) = tl.get(hold.tx)? {
+                record.release(AuthorizationState::Expired);
+                tl.insert(hold.tx, record)?;
+            }
+        }
+    }
+    Ok(released_holds)
+}
+
+/// Returns the accounts a transaction changed, right after it: the client's,
+/// if it has one, and the ones whose holds were released before it
+///
+/// # Arguments
+///
+/// * `client_ledger` - ClientAccount HashMap that holds clients balance and status,
+///   the client ID is the key for the ClientAccount associated
+/// * `client` - Client ID of the transaction
+/// * `released` - Holds released before the transaction
+fn touched_accounts(
+    client_ledger: &Mutex<HashMap<u16, ClientAccount>>,
+    client: u16,
+    released: &[Hold],
+) -> Vec<ClientAccount> {
+    let mut clients: Vec<u16> = released.iter().map(|hold| hold.client).collect();
+    clients.push(client);
+    clients.sort_unstable();
+    clients.dedup();
+    let cl = client_ledger.lock().unwrap();
+    clients
+        .iter()
+        .filter_map(|client| cl.get(client).cloned())
+        .collect()
+}
+
+/// Marks the record of a deposit, withdrawal or authorization as applied or
+/// rejected, once the transaction was processed
+///
+/// # Arguments
+///
+/// * `tx_ledger` - Transaction store that holds the record
+/// * `tx_id` - Transaction ID of the record
+/// * `applied` - Whether the transaction was applied
+fn settle(tx_ledger: &SharedStore, tx_id: u32, applied: bool) -> Result<(), TXProcessError> {
+    let mut tl = tx_ledger.lock().unwrap();
+    if let Some(mut record) = tl.get(tx_id)? {
+        record.settle(applied);
+        tl.insert(tx_id, record)?;
+    }
     Ok(())
 }
 
-/// Deposit action. If the client is not registered, it creates a new entry.
+/// Passes the records a transaction created, referenced or expired to the
+/// invariant checker, once the transaction was processed
+///
+/// # Arguments
+///
+/// * `checker` - Invariant checker of the run
+/// * `tx_ledger` - Transaction store that holds the records
+/// * `txs` - Transaction IDs of the records, the unknown ones are skipped
+fn check_records(
+    checker: &mut InvariantChecker,
+    tx_ledger: &SharedStore,
+    txs: impl Iterator<Item = u32>,
+) -> Result<Result<(), Violation>, TXProcessError> {
+    let tl = tx_ledger.lock().unwrap();
+    for tx in txs {
+        if let Some(record) = tl.get(tx)? {
+            if let Err(violation) = checker.record(tx, &record) {
+                return Ok(Err(violation));
+            }
+        }
+    }
+    Ok(Ok(()))
+}
+
+/// Records in the journal the postings a transaction made on the client's
+/// account, a rejected transaction made none
 ///
 /// # Arguments
 ///
 /// * `client_ledger` - ClientAccount HashMap that holds clients balance and status,
-/// the client ID is the key for the ClientAccount associated
+///   the client ID is the key for the ClientAccount associated
+/// * `journal` - Journal where the postings are recorded
+/// * `client` - Client ID of the transaction
+/// * `seq` - Position of the transaction in the processing order
+/// * `tx` - Transaction ID
+/// * `kind` - Type of the transaction
+fn post_journal(
+    client_ledger: &Mutex<HashMap<u16, ClientAccount>>,
+    journal: &Mutex<Journal>,
+    client: u16,
+    seq: u64,
+    tx: u32,
+    kind: &str,
+) {
+    if let Some(account) = client_ledger.lock().unwrap().get_mut(&client) {
+        let postings = account.take_postings();
+        if !postings.is_empty() {
+            journal.lock().unwrap().post(seq, tx, kind, postings);
+        }
+    }
+}
+
+/// Returns the total of the client's account before a transaction, and the
+/// refunded amount of the deposit it refunds, for the invariant checks
+///
+/// # Arguments
+///
+/// * `client_ledger` - ClientAccount HashMap that holds clients balance and status,
+///   the client ID is the key for the ClientAccount associated
+/// * `tx_ledger` - Transaction store, with the deposit of a refund
+/// * `transaction` - Transaction about to be processed
+/// * `kind` - Type of the transaction
+fn balances_before(
+    client_ledger: &Mutex<HashMap<u16, ClientAccount>>,
+    tx_ledger: &SharedStore,
+    transaction: &Transaction,
+    kind: &str,
+) -> Result<(Decimal, Decimal), TXProcessError> {
+    let total = client_ledger
+        .lock()
+        .unwrap()
+        .get(&transaction.client())
+        .map_or(Decimal::ZERO, |account| account.total());
+    let refunded = match kind {
+        "refund" => tx_ledger
+            .lock()
+            .unwrap()
+            .get(transaction.tx())?
+            .map_or(Decimal::ZERO, |record| record.refunded()),
+        _ => Decimal::ZERO,
+    };
+    Ok((total, refunded))
+}
+
+/// Returns the money an applied transaction moved into the client's account,
+/// negative when it moved out of it, for the invariant checks
+///
+/// # Arguments
+///
+/// * `tx_ledger` - Transaction store, with the records after the transaction
+/// * `adjustments` - Manual adjustments, with the one an approval applied
+/// * `transaction` - Applied transaction
+/// * `kind` - Type of the transaction
+/// * `amount` - Rounded amount of the transaction
+/// * `refunded` - Refunded amount of the deposit before a refund
+fn money_moved(
+    tx_ledger: &SharedStore,
+    adjustments: &Mutex<Vec<Adjustment>>,
+    transaction: &Transaction,
+    kind: &str,
+    amount: Decimal,
+    refunded: Decimal,
+) -> Result<Decimal, TXProcessError> {
+    let record = || -> Result<TransactionRecord, TXProcessError> {
+        tx_ledger
+            .lock()
+            .unwrap()
+            .get(transaction.tx())?
+            .ok_or(TXProcessError::UnknownTransaction)
+    };
+    Ok(match kind {
+        "deposit" => amount,
+        "withdrawal" => -amount,
+        "capture" => -record()?.amount(),
+        "chargeback" => -record()?.disputable(),
+        "refund" => refunded - record()?.refunded(),
+        "approve" => adjustments
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|adjustment| adjustment.tx == transaction.tx())
+            .map_or(Decimal::ZERO, |adjustment| adjustment.amount),
+        _ => Decimal::ZERO,
+    })
+}
+
+/// Screens a deposit or withdrawal with the fraud rules before it is applied.
+/// The transaction is rejected when a rule rejects it or locks the account,
+/// in which case the account is locked first.
+///
+/// # Arguments
+///
+/// * `client_ledger` - ClientAccount HashMap that holds clients balance and status,
+///   the client ID is the key for the ClientAccount associated
+/// * `fraud` - Velocity state of the clients
+/// * `transaction` - Deposit or withdrawal to screen
+/// * `seq` - Position of the transaction in the processing order
+/// * `flow` - Whether it is a deposit or a withdrawal
+/// * `amount` - Rounded amount of the transaction
+/// * `config` - Engine configuration, with the fraud rules
+fn screen(
+    client_ledger: Arc<Mutex<HashMap<u16, ClientAccount>>>,
+    fraud: &Mutex<FraudMonitor>,
+    transaction: &Transaction,
+    seq: u64,
+    flow: Flow,
+    amount: Decimal,
+    config: &EngineConfig,
+) -> Result<(), TXProcessError> {
+    let client = transaction.client();
+    let mut monitor = fraud.lock().unwrap();
+    match monitor.check(
+        &config.fraud.rules,
+        seq,
+        client,
+        transaction.tx(),
+        flow,
+        amount,
+    ) {
+        None | Some((FraudAction::Flag, _)) => Ok(()),
+        Some((FraudAction::Reject, rule)) => Err(TXProcessError::FraudRejected(rule.to_string())),
+        Some((FraudAction::Lock, rule)) => {
+            if let Some(account) = client_ledger.lock().unwrap().get_mut(&client) {
+                account.lock(rule)?;
+            }
+            Err(TXProcessError::FraudRejected(rule.to_string()))
+        }
+    }
+}
+
+/// Applies an operation to the account of a client. The account of an
+/// unknown client is opened when the policies create them, and only kept
+/// if the operation succeeds, so a rejected transaction leaves no empty
+/// account behind.
+///
+/// # Arguments
+///
+/// * `client_ledger` - ClientAccount HashMap that holds clients balance and status,
+///   the client ID is the key for the ClientAccount associated
+/// * `client` - Client id to perform the action
+/// * `policies` - Policies, with the one for the unknown clients
+/// * `operation` - Operation applied to the account
+fn with_account<E>(
+    client_ledger: &Mutex<HashMap<u16, ClientAccount>>,
+    client: u16,
+    policies: &Policies,
+    operation: impl FnOnce(&mut ClientAccount) -> Result<(), E>,
+) -> Result<(), TXProcessError>
+where
+    TXProcessError: From<E>,
+{
+    let mut cl = client_ledger.lock().unwrap();
+    match cl.entry(client) {
+        Entry::Occupied(mut account) => operation(account.get_mut())?,
+        Entry::Vacant(_) if policies.unknown_clients == UnknownClientPolicy::Reject => {
+            return Err(TXProcessError::UnknownClient);
+        }
+        Entry::Vacant(entry) => {
+            let mut account = ClientAccount::new(client);
+            operation(&mut account)?;
+            entry.insert(account);
+        }
+    }
+    Ok(())
+}
+
+/// Checks a deposit or withdrawal against the limits of the client's tier:
+/// the balance cap for deposits, the single, short and long window
+/// maximums for withdrawals.
+///
+/// # Arguments
+///
+/// * `account` - Client's account before the transaction
+/// * `flow` - Whether it is a deposit or a withdrawal
+/// * `amount` - Amount of the transaction
+/// * `windows` - Short and long windows of the transaction, if any is configured
+/// * `config` - Engine configuration, with the tiers
+fn check_tier(
+    account: &ClientAccount,
+    flow: Flow,
+    amount: Decimal,
+    windows: Option<(u64, u64)>,
+    config: &EngineConfig,
+) -> Result<(), TXProcessError> {
+    let Some((tier, limits)) = config.tier(account.tier()) else {
+        return Ok(());
+    };
+    let exceeded = |limit| {
+        Err(TXProcessError::TierLimitExceeded {
+            limit,
+            tier: tier.to_string(),
+        })
+    };
+    let above = |limit: Option<Decimal>, value: Decimal| limit.is_some_and(|max| value > max);
+    match flow {
+        Flow::Deposit if above(limits.max_balance, account.total() + amount) => exceeded("balance"),
+        Flow::Deposit => Ok(()),
+        Flow::Withdrawal if above(limits.max_withdrawal, amount) => exceeded("single withdrawal"),
+        Flow::Withdrawal => {
+            let (short, long) = windows
+                .map(|(short, long)| account.withdrawn(short, long))
+                .unwrap_or_default();
+            if above(limits.short_window_withdrawal, short + amount) {
+                return exceeded("short window withdrawal");
+            }
+            if above(limits.long_window_withdrawal, long + amount) {
+                return exceeded("long window withdrawal");
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Deposit action. If the client is not registered, it creates a new entry
+/// unless the policies reject the unknown clients.
+///
+/// # Arguments
+///
+/// * `client_ledger` - ClientAccount HashMap that holds clients balance and status,
+///   the client ID is the key for the ClientAccount associated
 /// * `client` - Client id to perform the action
 /// * `amount` - Amount to be deposited
+/// * `config` - Engine configuration, with the policies, limits and tiers
 fn deposit(
     client_ledger: Arc<Mutex<HashMap<u16, ClientAccount>>>,
     client: u16,
     amount: Decimal,
+    config: &EngineConfig,
 ) -> Result<(), TXProcessError> {
-    let mut cl = client_ledger.lock().unwrap();
-    if let Some(client_record) = cl.get_mut(&client) {
-        client_record.deposit(amount).unwrap();
-    } else {
-        let mut new_client = ClientAccount::new(client);
-        new_client.deposit(amount).unwrap();
-        cl.insert(client, new_client);
+    if config.limits.max_deposit.is_some_and(|max| amount > max) {
+        return Err(TXProcessError::LimitExceeded("deposit"));
     }
-    Ok(())
+    with_account(&client_ledger, client, &config.policies, |account| {
+        check_tier(account, Flow::Deposit, amount, None, config)?;
+        account
+            .deposit(amount, &config.policies)
+            .map_err(TXProcessError::from)
+    })
 }
 
-/// Withdrawal action. If the client is not registered, it creates a new entry.
+/// Withdrawal action. If the client is not registered, it creates a new entry
+/// when the withdrawal is applied, which the overdraft limit can allow,
+/// unless the policies reject the unknown clients.
 ///
 /// # Arguments
 ///
 /// * `client_ledger` - ClientAccount HashMap that holds clients balance and status,
-/// the client ID is the key for the ClientAccount associated
+///   the client ID is the key for the ClientAccount associated
 /// * `client` - Client id to perform the action
 /// * `amount` - Amount to be withdrawed
+/// * `seq` - Position of the withdrawal in the processing order, giving its
+///   windows for the tier limits
+/// * `config` - Engine configuration, with the policies, limits and tiers
 fn withdrawal(
     client_ledger: Arc<Mutex<HashMap<u16, ClientAccount>>>,
     client: u16,
     amount: Decimal,
+    seq: u64,
+    config: &EngineConfig,
+) -> Result<(), TXProcessError> {
+    if config.limits.max_withdrawal.is_some_and(|max| amount > max) {
+        return Err(TXProcessError::LimitExceeded("withdrawal"));
+    }
+    let windows = match (config.limits.short_window, config.limits.long_window) {
+        (None, None) => None,
+        (short, long) => Some((
+            short.map_or(0, |length| seq / length),
+            long.map_or(0, |length| seq / length),
+        )),
+    };
+    with_account(&client_ledger, client, &config.policies, |account| {
+        check_tier(account, Flow::Withdrawal, amount, windows, config)?;
+        account.withdrawal(amount, &config.policies)?;
+        if let Some((short, long)) = windows {
+            account.record_withdrawal(short, long, amount);
+        }
+        Ok::<_, TXProcessError>(())
+    })
+}
+
+/// Authorize action. If the client is registered and has the funds, the
+/// amount is reserved until the authorization is captured, voided or expires.
+/// A rejected authorization is closed, so it can't be captured.
+///
+/// # Arguments
+///
+/// * `client_ledger` - ClientAccount HashMap that holds clients balance and status,
+///   the client ID is the key for the ClientAccount associated
+/// * `tx_ledger` - Transaction store that holds the authorization record
+/// * `tx_id` - Transaction ID of the authorization
+/// * `client` - Client id to perform the action
+/// * `amount` - Amount to be reserved
+fn authorize(
+    client_ledger: Arc<Mutex<HashMap<u16, ClientAccount>>>,
+    tx_ledger: SharedStore,
+    tx_id: u32,
+    client: u16,
+    amount: Decimal,
+) -> Result<(), TXProcessError> {
+    let mut cl = client_ledger.lock().unwrap();
+    let authorized = match cl.get_mut(&client) {
+        Some(client_record) => client_record
+            .authorize(amount)
+            .map_err(TXProcessError::from),
+        None => Err(TXProcessError::UnknownClient),
+    };
+    if authorized.is_err() {
+        let mut tl = tx_ledger.lock().unwrap();
+        if let Some(mut record) = tl.get(tx_id)? {
+            record.release(AuthorizationState::Voided);
+            tl.insert(tx_id, record)?;
+        }
+    }
+    authorized
+}
+
+/// Capture action. If there is an open authorization with the designed ID
+/// and the right client ID, the given amount, or the whole authorization
+/// if there is none, is captured and the rest released.
+///
+/// # Arguments
+///
+/// * `client_ledger` - ClientAccount HashMap that holds clients balance and status,
+///   the client ID is the key for the ClientAccount associated
+/// * `tx_ledger` - Transaction store that holds the authorization record
+/// * `holds` - Holds, where the authorization expiry is removed
+/// * `tx_id` - Transaction ID of the authorization
+/// * `client` - Client id to perform the action
+/// * `amount` - Amount to be captured, the authorized one if `None`
+fn capture(
+    client_ledger: Arc<Mutex<HashMap<u16, ClientAccount>>>,
+    tx_ledger: SharedStore,
+    holds: &Mutex<VecDeque<Hold>>,
+    tx_id: u32,
+    client: u16,
+    amount: Option<Decimal>,
 ) -> Result<(), TXProcessError> {
+    let mut tl = tx_ledger.lock().unwrap();
+    let mut authorization = open_authorization(tl.get(tx_id)?, client)?;
+    let captured = amount.unwrap_or(authorization.amount());
+    if captured > authorization.amount() {
+        return Err(TXProcessError::CaptureExceedsAuthorization);
+    }
     let mut cl = client_ledger.lock().unwrap();
-    if let Some(client_record) = cl.get_mut(&client) {
-        client_record.withdrawal(amount).unwrap();
-    } else {
-        let new_client = ClientAccount::new(client);
-        cl.insert(client, new_client);
+    let client_record = cl.get_mut(&client).ok_or(TXProcessError::UnknownClient)?;
+    client_record.capture(authorization.amount(), captured)?;
+    authorization.capture(captured);
+    tl.insert(tx_id, authorization)?;
+    remove_hold(holds, tx_id, HoldKind::Authorization);
+    Ok(())
+}
+
+/// Void action. If there is an open authorization with the designed ID
+/// and the right client ID, its funds are released.
+///
+/// # Arguments
+///
+/// * `client_ledger` - ClientAccount HashMap that holds clients balance and status,
+///   the client ID is the key for the ClientAccount associated
+/// * `tx_ledger` - Transaction store that holds the authorization record
+/// * `holds` - Holds, where the authorization expiry is removed
+/// * `tx_id` - Transaction ID of the authorization
+/// * `client` - Client id to perform the action
+fn void(
+    client_ledger: Arc<Mutex<HashMap<u16, ClientAccount>>>,
+    tx_ledger: SharedStore,
+    holds: &Mutex<VecDeque<Hold>>,
+    tx_id: u32,
+    client: u16,
+) -> Result<(), TXProcessError> {
+    let mut tl = tx_ledger.lock().unwrap();
+    let mut authorization = open_authorization(tl.get(tx_id)?, client)?;
+    let mut cl = client_ledger.lock().unwrap();
+    let client_record = cl.get_mut(&client).ok_or(TXProcessError::UnknownClient)?;
+    client_record.void(authorization.amount())?;
+    authorization.release(AuthorizationState::Voided);
+    tl.insert(tx_id, authorization)?;
+    remove_hold(holds, tx_id, HoldKind::Authorization);
+    Ok(())
+}
+
+// Checks the record is an applied transaction of the client. A record not
+// processed yet is unknown, its transaction comes later in the input
+fn applied_record(
+    record: Option<TransactionRecord>,
+    client: u16,
+) -> Result<TransactionRecord, TXProcessError> {
+    let record = record
+        .filter(|record| record.status() != RecordStatus::Pending)
+        .ok_or(TXProcessError::UnknownTransaction)?;
+    if record.client() != client {
+        return Err(TXProcessError::ClientMismatch);
+    }
+    if record.status() == RecordStatus::Rejected {
+        return Err(TXProcessError::RejectedTransaction);
+    }
+    Ok(record)
+}
+
+// Checks the record is an open authorization of the client
+fn open_authorization(
+    record: Option<TransactionRecord>,
+    client: u16,
+) -> Result<TransactionRecord, TXProcessError> {
+    let record = applied_record(record, client)?;
+    if record.authorization_state() != Some(AuthorizationState::Open) {
+        return Err(TXProcessError::AuthorizationNotOpen);
+    }
+    Ok(record)
+}
+
+// Adds a hold, keeping them in release order
+fn add_hold(holds: &Mutex<VecDeque<Hold>>, hold: Hold) {
+    let mut holds = holds.lock().unwrap();
+    let at = holds.partition_point(|held| held.release <= hold.release);
+    holds.insert(at, hold);
+}
+
+// Removes the hold of a transaction, returning it if there was one
+fn remove_hold(holds: &Mutex<VecDeque<Hold>>, tx_id: u32, kind: HoldKind) -> Option<Hold> {
+    let mut holds = holds.lock().unwrap();
+    let at = holds
+        .iter()
+        .position(|hold| hold.tx == tx_id && hold.kind == kind)?;
+    holds.remove(at)
+}
+
+/// Adjustment action. A complete adjustment, with an amount, a reason code
+/// and the operator entering it, is recorded as pending; the balances are
+/// only changed once it is approved.
+///
+/// # Arguments
+///
+/// * `adjustments` - Manual adjustments, pending or approved
+/// * `transaction` - Adjustment row, with its reason code and operator
+/// * `amount` - Rounded amount, credited when positive and debited when negative
+/// * `seq` - Position of the adjustment in the processing order
+fn adjustment(
+    adjustments: &Mutex<Vec<Adjustment>>,
+    transaction: &Transaction,
+    amount: Option<Decimal>,
+    seq: u64,
+) -> Result<(), TXProcessError> {
+    let (Some(amount), Some(reason_code), Some(maker)) =
+        (amount, transaction.reason_code(), transaction.operator())
+    else {
+        return Err(TXProcessError::IncompleteAdjustment);
+    };
+    let mut adjustments = adjustments.lock().unwrap();
+    if adjustments
+        .iter()
+        .any(|adjustment| adjustment.tx == transaction.tx())
+    {
+        return Err(TXProcessError::DuplicateAdjustment);
     }
+    adjustments.push(Adjustment {
+        tx: transaction.tx(),
+        client: transaction.client(),
+        amount,
+        reason_code: reason_code.to_string(),
+        maker: maker.to_string(),
+        checker: None,
+        status: AdjustmentStatus::Pending,
+        seq,
+        approved_seq: None,
+    });
+    Ok(())
+}
 
+/// Approve action. If there is a pending adjustment with the designed ID
+/// and the right client ID, entered by another operator, it is applied
+/// to the client's available funds.
+///
+/// # Arguments
+///
+/// * `client_ledger` - ClientAccount HashMap that holds clients balance and status,
+///   the client ID is the key for the ClientAccount associated
+/// * `adjustments` - Manual adjustments, pending or approved
+/// * `transaction` - Approval row, with the operator approving
+/// * `seq` - Position of the approval in the processing order
+/// * `policies` - Policies, with the one for the unknown clients
+fn approve(
+    client_ledger: Arc<Mutex<HashMap<u16, ClientAccount>>>,
+    adjustments: &Mutex<Vec<Adjustment>>,
+    transaction: &Transaction,
+    seq: u64,
+    policies: &Policies,
+) -> Result<(), TXProcessError> {
+    let checker = transaction
+        .operator()
+        .ok_or(TXProcessError::MissingApprover)?;
+    let mut adjustments = adjustments.lock().unwrap();
+    let adjustment = adjustments
+        .iter_mut()
+        .find(|adjustment| adjustment.tx == transaction.tx())
+        .ok_or(TXProcessError::UnknownTransaction)?;
+    if adjustment.client != transaction.client() {
+        return Err(TXProcessError::ClientMismatch);
+    }
+    if adjustment.status == AdjustmentStatus::Approved {
+        return Err(TXProcessError::AlreadyApproved);
+    }
+    if adjustment.maker == checker {
+        return Err(TXProcessError::SameOperator);
+    }
+    with_account(&client_ledger, adjustment.client, policies, |account| {
+        account.adjust(adjustment.amount)
+    })?;
+    adjustment.checker = Some(checker.to_string());
+    adjustment.status = AdjustmentStatus::Approved;
+    adjustment.approved_seq = Some(seq);
     Ok(())
 }
 
-/// Dispute action. If there is a Transaction with the designed ID to be disputed,
+/// Account administration action, locking, unlocking, freezing or closing
+/// the account of the client. Locking or freezing a client that is not
+/// registered creates its account, so it is blocked before its first
+/// transaction, unless the policies reject the unknown clients;
+/// unlocking or closing requires the account.
+///
+/// # Arguments
+///
+/// * `client_ledger` - ClientAccount HashMap that holds clients balance and status,
+///   the client ID is the key for the ClientAccount associated
+/// * `transaction` - Administration row, with the reason code and operator
+/// * `action` - One of `lock`, `unlock`, `freeze` or `close`
+/// * `policies` - Policies, with the one for the unknown clients
+fn administer(
+    client_ledger: Arc<Mutex<HashMap<u16, ClientAccount>>>,
+    transaction: &Transaction,
+    action: &str,
+    policies: &Policies,
+) -> Result<(), TXProcessError> {
+    let reason = transaction
+        .reason_code()
+        .ok_or(TXProcessError::MissingReason)?;
+    let client = transaction.client();
+    match action {
+        "lock" => with_account(&client_ledger, client, policies, |account| {
+            account.lock(reason)
+        }),
+        "freeze" => with_account(&client_ledger, client, policies, |account| {
+            account.freeze(reason)
+        }),
+        _ => {
+            let mut cl = client_ledger.lock().unwrap();
+            let account = cl.get_mut(&client).ok_or(TXProcessError::UnknownClient)?;
+            match action {
+                "unlock" => account.unlock(),
+                _ => account.close(reason),
+            }
+            .map_err(TXProcessError::from)
+        }
+    }?;
+    info!(
+        client,
+        action,
+        reason,
+        operator = transaction.operator(),
+        "account status changed"
+    );
+    Ok(())
+}
+
+/// Refund action. If there is an applied deposit with the designed ID and the right
+/// client ID, which isn't under dispute, the given amount, or all of it that
+/// wasn't refunded yet if there is none, is returned to its payer.
+/// A deposit on hold is refunded from the pending funds.
+///
+/// # Arguments
+///
+/// * `client_ledger` - ClientAccount HashMap that holds clients balance and status,
+///   the client ID is the key for the ClientAccount associated
+/// * `tx_ledger` - Transaction store that holds the deposit record
+/// * `holds` - Holds, the hold of a deposit is reduced by the refund
+/// * `tx_id` - Transaction ID of the deposit
+/// * `client` - Client id to perform the action
+/// * `amount` - Amount to be refunded, the unrefunded one if `None`
+fn refund(
+    client_ledger: Arc<Mutex<HashMap<u16, ClientAccount>>>,
+    tx_ledger: SharedStore,
+    holds: &Mutex<VecDeque<Hold>>,
+    tx_id: u32,
+    client: u16,
+    amount: Option<Decimal>,
+) -> Result<(), TXProcessError> {
+    let mut tl = tx_ledger.lock().unwrap();
+    let mut deposit = applied_record(tl.get(tx_id)?, client)?;
+    if deposit.kind() != RecordKind::Deposit {
+        return Err(TXProcessError::NotRefundable);
+    }
+    if deposit.charged_back() {
+        return Err(TXProcessError::ChargedBack);
+    }
+    if deposit.disputed() {
+        return Err(TXProcessError::AlreadyDisputed);
+    }
+    let refunded = amount.unwrap_or(deposit.disputable());
+    if refunded > deposit.disputable() {
+        return Err(TXProcessError::RefundExceedsAmount);
+    }
+    let mut cl = client_ledger.lock().unwrap();
+    let client_record = cl.get_mut(&client).ok_or(TXProcessError::UnknownClient)?;
+    let mut holds = holds.lock().unwrap();
+    let hold = holds
+        .iter_mut()
+        .find(|hold| hold.tx == tx_id && hold.kind == HoldKind::Deposit);
+    client_record.refund(refunded, hold.is_some())?;
+    if let Some(hold) = hold {
+        hold.amount -= refunded;
+    }
+    deposit.refund(refunded);
+    tl.insert(tx_id, deposit)?;
+    Ok(())
+}
+
+/// Dispute action. If there is an applied Transaction with the designed ID to be disputed,
 /// with the righ client ID, it will be disputed.
 ///
 /// # Arguments
 ///
 /// * `client_ledger` - ClientAccount HashMap that holds clients balance and status,
-/// the client ID is the key for the ClientAccount associated
-/// * `tx_ledger` - Transaction HashMap that holds deposit and withdrawals
-/// the transaction ID is the key for the Transaction record associated
+///   the client ID is the key for the ClientAccount associated
+/// * `tx_ledger` - Transaction store that holds deposit and withdrawals
+///   the transaction ID is the key for the Transaction record associated
+/// * `holds` - Holds, a disputed deposit on hold is released first
 /// * `tx_id` - Transaction ID to look for
 /// * `client` - Client id to perform the action
+/// * `seq` - Position of the dispute in the processing order
+/// * `config` - Engine configuration, with the policies
 fn dispute(
     client_ledger: Arc<Mutex<HashMap<u16, ClientAccount>>>,
-    tx_ledger: Arc<Mutex<HashMap<u32, TransactionRecord>>>,
+    tx_ledger: SharedStore,
+    holds: &Mutex<VecDeque<Hold>>,
     tx_id: u32,
     client: u16,
+    seq: u64,
+    config: &EngineConfig,
 ) -> Result<(), TXProcessError> {
-    if let Some(transaction) = tx_ledger.lock().unwrap().get_mut(&tx_id) {
-        if transaction.client() == client {
-            let mut cl = client_ledger.lock().unwrap();
-            if let Some(client_record) = cl.get_mut(&client) {
-                client_record.dispute(transaction.amount()).unwrap();
-                transaction.dispute();
-            }
+    let mut tl = tx_ledger.lock().unwrap();
+    let mut transaction = applied_record(tl.get(tx_id)?, client)?;
+    if transaction
+        .authorization_state()
+        .is_some_and(|state| state != AuthorizationState::Captured)
+    {
+        return Err(TXProcessError::NotCaptured);
+    }
+    if transaction.charged_back() {
+        return Err(TXProcessError::ChargedBack);
+    }
+    if transaction.disputed() {
+        return Err(TXProcessError::AlreadyDisputed);
+    }
+    if transaction.disputable().is_zero() {
+        return Err(TXProcessError::FullyRefunded);
+    }
+    let window = config.policies.dispute_window;
+    if window.is_some_and(|window| seq.saturating_sub(transaction.seq()) > window) {
+        return Err(TXProcessError::DisputeWindowExpired);
+    }
+    let mut cl = client_ledger.lock().unwrap();
+    let client_record = cl.get_mut(&client).ok_or(TXProcessError::UnknownClient)?;
+    let hold = remove_hold(holds, tx_id, HoldKind::Deposit);
+    let mut disputed = client_record.clone();
+    let applied = hold
+        .map_or(Ok(()), |hold| disputed.release(hold.amount))
+        .and_then(|()| disputed.dispute(transaction.disputable(), &config.policies));
+    if let Err(e) = applied {
+        // The deposit stays on hold
+        if let Some(hold) = hold {
+            add_hold(holds, hold);
         }
+        return Err(e.into());
     }
-
+    *client_record = disputed;
+    transaction.dispute();
+    tl.insert(tx_id, transaction)?;
     Ok(())
 }
 
@@ -239,27 +1360,27 @@ fn dispute(
 /// # Arguments
 ///
 /// * `client_ledger` - ClientAccount HashMap that holds clients balance and status,
-/// the client ID is the key for the ClientAccount associated
-/// * `tx_ledger` - Transaction HashMap that holds deposit and withdrawals
-/// the transaction ID is the key for the Transaction record associated
+///   the client ID is the key for the ClientAccount associated
+/// * `tx_ledger` - Transaction store that holds deposit and withdrawals
+///   the transaction ID is the key for the Transaction record associated
 /// * `tx_id` - Transaction ID to look for
 /// * `client` - Client id to perform the action
 fn resolve(
     client_ledger: Arc<Mutex<HashMap<u16, ClientAccount>>>,
-    tx_ledger: Arc<Mutex<HashMap<u32, TransactionRecord>>>,
+    tx_ledger: SharedStore,
     tx_id: u32,
     client: u16,
 ) -> Result<(), TXProcessError> {
-    if let Some(transaction) = tx_ledger.lock().unwrap().get_mut(&tx_id) {
-        if transaction.disputed() && transaction.client() == client {
-            let mut cl = client_ledger.lock().unwrap();
-            if let Some(client_record) = cl.get_mut(&client) {
-                client_record.resolve(transaction.amount()).unwrap();
-                transaction.resolve();
-            }
-        }
+    let mut tl = tx_ledger.lock().unwrap();
+    let mut transaction = applied_record(tl.get(tx_id)?, client)?;
+    if !transaction.disputed() {
+        return Err(TXProcessError::NotDisputed);
     }
-
+    let mut cl = client_ledger.lock().unwrap();
+    let client_record = cl.get_mut(&client).ok_or(TXProcessError::UnknownClient)?;
+    client_record.resolve(transaction.disputable())?;
+    transaction.resolve();
+    tl.insert(tx_id, transaction)?;
     Ok(())
 }
 
@@ -270,26 +1391,388 @@ fn resolve(
 /// # Arguments
 ///
 /// * `client_ledger` - ClientAccount HashMap that holds clients balance and status,
-/// the client ID is the key for the ClientAccount associated
-/// * `tx_ledger` - Transaction HashMap that holds deposit and withdrawals
-/// the transaction ID is the key for the Transaction record associated
+///   the client ID is the key for the ClientAccount associated
+/// * `tx_ledger` - Transaction store that holds deposit and withdrawals
+///   the transaction ID is the key for the Transaction record associated
 /// * `tx_id` - Transaction ID to look for
 /// * `amount` - Amount to be deposited
 fn chargeback(
     client_ledger: Arc<Mutex<HashMap<u16, ClientAccount>>>,
-    tx_ledger: Arc<Mutex<HashMap<u32, TransactionRecord>>>,
+    tx_ledger: SharedStore,
     tx_id: u32,
     client: u16,
 ) -> Result<(), TXProcessError> {
-    if let Some(transaction) = tx_ledger.lock().unwrap().get_mut(&tx_id) {
-        if transaction.disputed() && transaction.client() == client {
-            let mut cl = client_ledger.lock().unwrap();
-            if let Some(client_record) = cl.get_mut(&client) {
-                client_record.chargeback(transaction.amount()).unwrap();
-                transaction.resolve();
-            }
-        }
+    let mut tl = tx_ledger.lock().unwrap();
+    let mut transaction = applied_record(tl.get(tx_id)?, client)?;
+    if transaction.charged_back() {
+        return Err(TXProcessError::ChargedBack);
     }
-
+    if !transaction.disputed() {
+        return Err(TXProcessError::NotDisputed);
+    }
+    let mut cl = client_ledger.lock().unwrap();
+    let client_record = cl.get_mut(&client).ok_or(TXProcessError::UnknownClient)?;
+    client_record.chargeback(transaction.disputable())?;
+    transaction.charge_back();
+    tl.insert(tx_id, transaction)?;
     Ok(())
 }
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config::TierLimits;
+    use crate::csv::reader::ReadOptions;
+    use crate::processors::pipeline::{self, Ledgers};
+    use crate::structs::clients::AccountStatus;
+    use std::io::Write;
+
+    const HEADER: &str = "type,client,tx,amount,reason_code,operator\n";
+
+    // Runs the rows through the pipeline, returning the ledgers and the
+    // rejection reason of every row, `None` when it was applied
+    async fn process(rows: &str, config: &EngineConfig) -> (Ledgers, Vec<Option<String>>) {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(HEADER.as_bytes()).unwrap();
+        file.write_all(rows.as_bytes()).unwrap();
+        let ledgers = Ledgers::new(true);
+        let inputs = vec![file.path().to_string_lossy().into_owned()];
+        let run = pipeline::run(inputs, &ledgers, ReadOptions::default(), config).await;
+        assert!(run.succeeded());
+        let reasons = ledgers
+            .statements
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.reason().map(str::to_string))
+            .collect();
+        (ledgers, reasons)
+    }
+
+    // Account of a client after a run
+    fn account(ledgers: &Ledgers, client: u16) -> Option<ClientAccount> {
+        ledgers.clients.lock().unwrap().get(&client).cloned()
+    }
+
+    fn reason(error: TXProcessError) -> Option<String> {
+        Some(error.to_string())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_charged_back_after_unlock() {
+        let rows = "deposit,1,1,100,,\n\
+                    deposit,1,2,100,,\n\
+                    dispute,1,1,,,\n\
+                    chargeback,1,1,,,\n\
+                    unlock,1,0,,cleared,ops\n";
+        let config = EngineConfig::default();
+        let again = format!("{rows}dispute,1,1,,,\nchargeback,1,1,,,\n");
+        let (ledgers, reasons) = process(&again, &config).await;
+        assert_eq!(reasons[..5], [None, None, None, None, None]);
+        assert_eq!(reasons[5], reason(TXProcessError::ChargedBack));
+        assert_eq!(reasons[6], reason(TXProcessError::ChargedBack));
+        let client = account(&ledgers, 1).unwrap();
+        assert_eq!(client.total(), Decimal::new(100, 0));
+        assert_eq!(client.status(), AccountStatus::Active);
+
+        let refund = format!("{rows}refund,1,1,,,\n");
+        let (ledgers, reasons) = process(&refund, &config).await;
+        assert_eq!(reasons[5], reason(TXProcessError::ChargedBack));
+        assert_eq!(account(&ledgers, 1).unwrap().total(), Decimal::new(100, 0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rejected_records() {
+        let mut config = EngineConfig::default();
+        config.policies.default_tier = Some("basic".to_string());
+        config.tiers.insert(
+            "basic".to_string(),
+            TierLimits {
+                max_balance: Some(Decimal::new(150, 0)),
+                ..TierLimits::default()
+            },
+        );
+        let rows = "dispute,1,1,,,\n\
+                    deposit,1,1,100,,\n\
+                    deposit,1,2,100,,\n\
+                    refund,1,2,,,\n\
+                    dispute,1,2,,,\n\
+                    chargeback,1,2,,,\n\
+                    authorize,1,3,500,,\n\
+                    capture,1,3,,,\n";
+        let (ledgers, reasons) = process(rows, &config).await;
+        // A transaction can't be referenced before it is processed
+        assert_eq!(reasons[0], reason(TXProcessError::UnknownTransaction));
+        assert_eq!(reasons[1], None);
+        let exceeded = TXProcessError::TierLimitExceeded {
+            limit: "balance",
+            tier: "basic".to_string(),
+        };
+        assert_eq!(reasons[2], reason(exceeded));
+        let rejected = reason(TXProcessError::RejectedTransaction);
+        assert_eq!(
+            reasons[3..6],
+            [rejected.clone(), rejected.clone(), rejected.clone()]
+        );
+        assert_eq!(reasons[6], reason(ClientError::InsufficientFunds.into()));
+        assert_eq!(reasons[7], rejected);
+        let client = account(&ledgers, 1).unwrap();
+        assert_eq!(client.available(), Decimal::new(100, 0));
+        assert_eq!(client.total(), Decimal::new(100, 0));
+        assert_eq!(client.status(), AccountStatus::Active);
+        let store = ledgers.transactions.lock().unwrap();
+        assert_eq!(
+            store.get(1).unwrap().unwrap().status(),
+            RecordStatus::Applied
+        );
+        assert_eq!(
+            store.get(2).unwrap().unwrap().status(),
+            RecordStatus::Rejected
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dispute_twice() {
+        let rows = "deposit,1,1,100,,\n\
+                    deposit,1,2,100,,\n\
+                    dispute,1,1,,,\n\
+                    dispute,1,1,,,\n\
+                    chargeback,1,1,,,\n";
+        let (ledgers, reasons) = process(rows, &EngineConfig::default()).await;
+        assert_eq!(reasons[..3], [None, None, None]);
+        assert_eq!(reasons[3], reason(TXProcessError::AlreadyDisputed));
+        assert_eq!(reasons[4], None);
+        // Only the first dispute held the amount, the chargeback took it
+        let client = account(&ledgers, 1).unwrap();
+        assert_eq!(client.available(), Decimal::new(100, 0));
+        assert_eq!(client.held(), Decimal::ZERO);
+        assert_eq!(client.status(), AccountStatus::Locked);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_authorize() {
+        let rows = "deposit,1,1,100,,\nauthorize,1,2,30,,\nauthorize,1,3,80,,\n";
+        let (ledgers, reasons) = process(rows, &EngineConfig::default()).await;
+        assert_eq!(reasons[..2], [None, None]);
+        assert_eq!(reasons[2], reason(ClientError::InsufficientFunds.into()));
+        let client = account(&ledgers, 1).unwrap();
+        assert_eq!(client.available(), Decimal::new(70, 0));
+        assert_eq!(client.authorized(), Decimal::new(30, 0));
+        assert_eq!(client.total(), Decimal::new(100, 0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_capture_and_void() {
+        let rows = "deposit,1,1,100,,\n\
+                    authorize,1,2,30,,\n\
+                    capture,1,2,20,,\n\
+                    authorize,1,3,10,,\n\
+                    capture,1,3,,,\n\
+                    authorize,1,4,25,,\n\
+                    void,1,4,,,\n\
+                    capture,1,4,5,,\n\
+                    capture,1,2,5,,\n\
+                    authorize,1,5,10,,\n\
+                    capture,1,5,15,,\n";
+        let (ledgers, reasons) = process(rows, &EngineConfig::default()).await;
+        assert_eq!(reasons[..7], [None, None, None, None, None, None, None]);
+        let not_open = reason(TXProcessError::AuthorizationNotOpen);
+        assert_eq!(reasons[7], not_open);
+        assert_eq!(reasons[8], not_open);
+        assert_eq!(reasons[9], None);
+        assert_eq!(
+            reasons[10],
+            reason(TXProcessError::CaptureExceedsAuthorization)
+        );
+        // 20 and 10 captured, 25 voided, 10 still authorized
+        let client = account(&ledgers, 1).unwrap();
+        assert_eq!(client.available(), Decimal::new(60, 0));
+        assert_eq!(client.authorized(), Decimal::new(10, 0));
+        assert_eq!(client.total(), Decimal::new(70, 0));
+        let store = ledgers.transactions.lock().unwrap();
+        let partial = store.get(2).unwrap().unwrap();
+        assert_eq!(partial.amount(), Decimal::new(20, 0));
+        assert_eq!(
+            partial.authorization_state(),
+            Some(AuthorizationState::Captured)
+        );
+        let voided = store.get(4).unwrap().unwrap();
+        assert_eq!(
+            voided.authorization_state(),
+            Some(AuthorizationState::Voided)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_authorization_expiry() {
+        let mut config = EngineConfig::default();
+        config.policies.authorization_expiry = Some(2);
+        let rows = "deposit,1,1,100,,\n\
+                    authorize,1,2,30,,\n\
+                    deposit,1,3,1,,\n\
+                    capture,1,2,,,\n\
+                    void,1,2,,,\n";
+        let (ledgers, reasons) = process(rows, &config).await;
+        assert_eq!(reasons[..3], [None, None, None]);
+        let not_open = reason(TXProcessError::AuthorizationNotOpen);
+        assert_eq!(reasons[3], not_open);
+        assert_eq!(reasons[4], not_open);
+        let client = account(&ledgers, 1).unwrap();
+        assert_eq!(client.available(), Decimal::new(101, 0));
+        assert_eq!(client.authorized(), Decimal::ZERO);
+        assert!(ledgers.holds.lock().unwrap().is_empty());
+        let expired = ledgers
+            .transactions
+            .lock()
+            .unwrap()
+            .get(2)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            expired.authorization_state(),
+            Some(AuthorizationState::Expired)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dispute_capture() {
+        let rows = "deposit,1,1,100,,\n\
+                    authorize,1,2,30,,\n\
+                    dispute,1,2,,,\n\
+                    capture,1,2,20,,\n\
+                    dispute,1,2,,,\n";
+        let (ledgers, reasons) = process(rows, &EngineConfig::default()).await;
+        assert_eq!(reasons[2], reason(TXProcessError::NotCaptured));
+        assert_eq!(reasons[3..], [None, None]);
+        // Only the captured amount is held
+        let client = account(&ledgers, 1).unwrap();
+        assert_eq!(client.held(), Decimal::new(20, 0));
+        assert_eq!(client.available(), Decimal::new(60, 0));
+        assert_eq!(client.total(), Decimal::new(80, 0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_refund() {
+        let rows = "deposit,1,1,100,,\n\
+                    refund,1,1,30,,\n\
+                    refund,1,1,80,,\n\
+                    dispute,1,1,,,\n\
+                    refund,1,1,10,,\n\
+                    resolve,1,1,,,\n\
+                    refund,1,1,,,\n\
+                    dispute,1,1,,,\n";
+        let (ledgers, reasons) = process(rows, &EngineConfig::default()).await;
+        assert_eq!(reasons[..2], [None, None]);
+        assert_eq!(reasons[2], reason(TXProcessError::RefundExceedsAmount));
+        assert_eq!(reasons[3], None);
+        assert_eq!(reasons[4], reason(TXProcessError::AlreadyDisputed));
+        assert_eq!(reasons[5..7], [None, None]);
+        assert_eq!(reasons[7], reason(TXProcessError::FullyRefunded));
+        assert_eq!(account(&ledgers, 1).unwrap().total(), Decimal::ZERO);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dispute_after_partial_refund() {
+        let rows = "deposit,1,1,100,,\n\
+                    refund,1,1,30,,\n\
+                    dispute,1,1,,,\n";
+        let (ledgers, reasons) = process(rows, &EngineConfig::default()).await;
+        assert_eq!(reasons, [None, None, None]);
+        // Only what wasn't refunded is held
+        let client = account(&ledgers, 1).unwrap();
+        assert_eq!(client.available(), Decimal::ZERO);
+        assert_eq!(client.held(), Decimal::new(70, 0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_maker_checker() {
+        let rows = "deposit,1,1,100,,\n\
+                    adjustment,1,2,-15,fee,alice\n\
+                    approve,1,2,,,alice\n\
+                    approve,1,2,,,\n\
+                    approve,2,2,,,bob\n\
+                    approve,1,2,,,bob\n\
+                    approve,1,2,,,carol\n";
+        let (ledgers, reasons) = process(rows, &EngineConfig::default()).await;
+        assert_eq!(reasons[..2], [None, None]);
+        assert_eq!(reasons[2], reason(TXProcessError::SameOperator));
+        assert_eq!(reasons[3], reason(TXProcessError::MissingApprover));
+        assert_eq!(reasons[4], reason(TXProcessError::ClientMismatch));
+        assert_eq!(reasons[5], None);
+        assert_eq!(reasons[6], reason(TXProcessError::AlreadyApproved));
+        assert_eq!(account(&ledgers, 1).unwrap().total(), Decimal::new(85, 0));
+        let adjustments = ledgers.adjustments.lock().unwrap();
+        assert_eq!(adjustments[0].checker.as_deref(), Some("bob"));
+        assert_eq!(adjustments[0].status, AdjustmentStatus::Approved);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_unknown_clients() {
+        let rows = "deposit,1,1,10,,\n\
+                    withdrawal,2,2,5,,\n\
+                    lock,3,0,,fraud,ops\n";
+        let mut config = EngineConfig::default();
+        config.policies.unknown_clients = UnknownClientPolicy::Reject;
+        let (ledgers, reasons) = process(rows, &config).await;
+        let unknown = reason(TXProcessError::UnknownClient);
+        assert_eq!(reasons, [unknown.clone(), unknown.clone(), unknown]);
+        assert!(ledgers.clients.lock().unwrap().is_empty());
+
+        // A rejected first transaction doesn't open an account either
+        let (ledgers, reasons) = process(rows, &EngineConfig::default()).await;
+        assert_eq!(reasons[0], None);
+        assert_eq!(reasons[1], reason(ClientError::InsufficientFunds.into()));
+        assert_eq!(reasons[2], None);
+        assert!(account(&ledgers, 2).is_none());
+        assert_eq!(
+            account(&ledgers, 3).unwrap().status(),
+            AccountStatus::Locked
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tier_windows() {
+        let mut config = EngineConfig::default();
+        config.limits.short_window = Some(2);
+        config.limits.long_window = Some(60);
+        config.policies.default_tier = Some("basic".to_string());
+        config.tiers.insert(
+            "basic".to_string(),
+            TierLimits {
+                short_window_withdrawal: Some(Decimal::new(10, 0)),
+                long_window_withdrawal: Some(Decimal::new(25, 0)),
+                ..TierLimits::default()
+            },
+        );
+        // Windows of two and sixty transactions
+        let rows = format!(
+            "deposit,1,1,100,,\n\
+             withdrawal,1,2,6,,\n\
+             withdrawal,1,3,6,,\n\
+             withdrawal,1,4,5,,\n\
+             withdrawal,1,5,5,,\n\
+             withdrawal,1,6,5,,\n\
+             withdrawal,1,7,4,,\n\
+             {}\
+             withdrawal,1,8,10,,\n",
+            "dispute,1,99,,,\n".repeat(53)
+        );
+        let (ledgers, reasons) = process(&rows, &config).await;
+        let exceeded = |limit| {
+            reason(TXProcessError::TierLimitExceeded {
+                limit,
+                tier: "basic".to_string(),
+            })
+        };
+        assert_eq!(reasons[..3], [None, None, None]);
+        assert_eq!(reasons[3], exceeded("short window withdrawal"));
+        assert_eq!(reasons[4..6], [None, None]);
+        assert_eq!(reasons[6], exceeded("long window withdrawal"));
+        assert_eq!(reasons[60], None);
+        assert_eq!(account(&ledgers, 1).unwrap().total(), Decimal::new(68, 0));
+    }
+}
@@ -0,0 +1,147 @@
+use crate::config::EngineConfig;
+use crate::csv::reader::ReadOptions;
+use crate::processors::{
+    pipeline::{self, Ledgers, PipelineRun},
+    snapshot::{Snapshot, SnapshotError},
+    txprocessor::TXProcessError,
+};
+use crate::structs::statement::StatementEntry;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use tracing::{info, instrument};
+
+// Summary of a validation run
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub struct ValidationReport {
+    // Rows read, malformed rows included
+    pub rows: u64,
+    // Rows that couldn't be parsed, skipped or quarantined
+    pub bad_rows: u64,
+    // Processed transactions per type
+    pub kinds: BTreeMap<String, u64>,
+    // Rejected transactions per reason
+    pub rejections: BTreeMap<String, u64>,
+    // Disputes, resolves and chargebacks of transactions that don't exist
+    pub unknown_references: Vec<u32>,
+    // Deposits, withdrawals and authorizations reusing the id of an earlier one
+    pub duplicate_ids: Vec<u32>,
+}
+
+impl ValidationReport {
+    /// Builds the report from the statement entries of a run
+    ///
+    /// # Arguments
+    ///
+    /// * `entries` - Statement entries, in processing order
+    /// * `known_ids` - Ids of the deposits and withdrawals processed before the run
+    pub fn from_entries(entries: &[StatementEntry], mut known_ids: HashSet<u32>) -> Self {
+        let unknown = TXProcessError::UnknownTransaction.to_string();
+        let mut report = ValidationReport::default();
+        for entry in entries {
+            *report.kinds.entry(entry.tx_type().to_string()).or_default() += 1;
+            if let Some(reason) = entry.reason() {
+                *report.rejections.entry(reason.to_string()).or_default() += 1;
+                if reason == unknown {
+                    report.unknown_references.push(entry.tx());
+                }
+            }
+            if matches!(entry.tx_type(), "deposit" | "withdrawal" | "authorize")
+                && !known_ids.insert(entry.tx())
+            {
+                report.duplicate_ids.push(entry.tx());
+            }
+        }
+        report
+    }
+}
+
+/// Runs the reader and the business rules over the inputs on a throwaway
+/// engine, optionally seeded from a snapshot, and summarizes the outcome.
+/// Nothing is written and the snapshot is not modified.
+///
+/// # Arguments
+///
+/// * `inputs` - Paths, directories or glob patterns of the CSV files
+/// * `read_options` - Error policy of the reader for malformed rows
+/// * `config` - Engine configuration, with the policies, rounding and limits
+/// * `seed` - Snapshot the engine starts from, an empty engine when `None`.
+///   Its transactions are copied in memory
+#[instrument(name = "validator", skip_all)]
+pub async fn validate(
+    inputs: Vec<String>,
+    read_options: ReadOptions,
+    config: &EngineConfig,
+    seed: Option<&Snapshot>,
+) -> Result<(PipelineRun, ValidationReport), SnapshotError> {
+    let ledgers = match seed {
+        Some(snapshot) => snapshot.ledgers(true, None)?,
+        None => Ledgers::new(true),
+    };
+    let known_ids: HashSet<u32> = ledgers
+        .transactions
+        .lock()
+        .unwrap()
+        .records()?
+        .into_iter()
+        .map(|(tx, _)| tx)
+        .collect();
+    let run = pipeline::run(inputs, &ledgers, read_options, config).await;
+
+    let statements = ledgers.statements.unwrap_or_default();
+    let mut report = ValidationReport::from_entries(&statements.lock().unwrap(), known_ids);
+    if let Ok(input) = &run.input {
+        report.rows = input.rows + input.bad_rows;
+        report.bad_rows = input.bad_rows;
+    }
+    info!(
+        rows = report.rows,
+        rejected = run.rejected,
+        duplicates = report.duplicate_ids.len(),
+        "validation finished"
+    );
+    Ok((run, report))
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::csv::reader::ErrorPolicy;
+    use std::io::Write;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_validate() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(
+            b"type,client,tx,amount\n\
+              deposit,1,1,10.0\n\
+              deposit,1,1,5.0\n\
+              withdrawal,1,2,50.0\n\
+              dispute,1,9,\n\
+              deposit,x,3,1.0\n",
+        )
+        .unwrap();
+        let options = ReadOptions {
+            policy: ErrorPolicy::Skip,
+            ..ReadOptions::default()
+        };
+        let (run, report) = validate(
+            vec![file.path().to_string_lossy().into_owned()],
+            options,
+            &EngineConfig::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert!(run.succeeded());
+        assert_eq!(report.rows, 5);
+        assert_eq!(report.bad_rows, 1);
+        assert_eq!(report.kinds["deposit"], 2);
+        assert_eq!(report.rejections["insufficient available funds"], 1);
+        assert_eq!(report.rejections["unknown transaction"], 1);
+        assert_eq!(report.unknown_references, vec![9]);
+        assert_eq!(report.duplicate_ids, vec![1]);
+    }
+}
@@ -0,0 +1,372 @@
+use crate::config::EngineConfig;
+use crate::csv::{
+    reader::ReadOptions,
+    writer::{self, CSVWriterError},
+};
+use crate::processors::{
+    pipeline::{self, Ledgers},
+    snapshot::{Snapshot, SnapshotError},
+};
+use crate::store::disk::DiskStore;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{error, info, info_span, instrument, warn, Instrument};
+
+const PROCESSED_DIR: &str = "processed";
+const FAILED_DIR: &str = "failed";
+
+// Watcher Error definition
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum WatchError {
+    #[error("Error accessing the watched directory")]
+    DirectoryError(#[from] io::Error),
+    #[error("Error persisting the engine state")]
+    StateError(#[from] SnapshotError),
+    #[error("Error writing the balances file")]
+    BalancesError(#[from] CSVWriterError),
+}
+
+// Watch mode options
+#[derive(Clone, Debug)]
+pub struct WatchOptions {
+    // Landing directory where the transaction files are dropped
+    pub dir: PathBuf,
+    // Balances file, rewritten after each batch
+    pub output: PathBuf,
+    // Engine state file, loaded at start and saved after each batch
+    pub state: PathBuf,
+    // Database file keeping the transactions, instead of the state file
+    pub tx_store: Option<PathBuf>,
+    // Time between two scans of the directory
+    pub interval: Duration,
+    // Stops once the files found in the directory are processed
+    pub once: bool,
+    pub read: ReadOptions,
+    pub config: EngineConfig,
+}
+
+/// Watches a directory, processing every new file once against the
+/// persisted engine state. Each file is a batch: it is applied as a whole,
+/// then moved to `processed/`, or not applied at all and moved to `failed/`.
+/// A file is picked up once its size didn't change between two scans.
+///
+/// # Arguments
+///
+/// * `options` - Directory, output, state, reader options and engine configuration
+#[instrument(name = "watcher", skip_all, fields(dir = %options.dir.display()))]
+pub async fn watch(options: WatchOptions) -> Result<(), WatchError> {
+    fs::create_dir_all(options.dir.join(PROCESSED_DIR))?;
+    fs::create_dir_all(options.dir.join(FAILED_DIR))?;
+
+    let mut snapshot = if options.state.exists() {
+        Snapshot::load(&options.state)?
+    } else {
+        Snapshot::default()
+    };
+    let store = match &options.tx_store {
+        Some(path) => Some(Box::new(DiskStore::open(path).map_err(SnapshotError::from)?) as _),
+        None => None,
+    };
+    let mut ledgers = snapshot.ledgers(false, store)?;
+
+    // Files applied before a restart, but not moved yet, must not be applied again
+    for name in snapshot.pending_files.drain(..) {
+        let path = options.dir.join(&name);
+        if path.exists() {
+            warn!(file = %name, "file already applied, moving it to processed");
+            move_to(&path, PROCESSED_DIR)?;
+        }
+    }
+
+    // Sizes seen on the previous scan, to wait until files are fully written
+    let mut sizes: HashMap<PathBuf, u64> = HashMap::new();
+    loop {
+        for path in scan(&options)? {
+            let size = fs::metadata(&path)?.len();
+            let ready = options.once || sizes.get(&path) == Some(&size);
+            if !ready {
+                sizes.insert(path, size);
+                continue;
+            }
+            sizes.remove(&path);
+            process_batch(&path, &mut ledgers, &mut snapshot, &options).await?;
+        }
+        if options.once {
+            return Ok(());
+        }
+        tokio::time::sleep(options.interval).await;
+    }
+}
+
+// Applies a file to a fork of the ledgers, which replaces them once the
+// whole file was applied, and saves it in the snapshot
+async fn process_batch(
+    path: &Path,
+    ledgers: &mut Ledgers,
+    snapshot: &mut Snapshot,
+    options: &WatchOptions,
+) -> Result<(), WatchError> {
+    let name = path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    let span = info_span!("batch", file = %name);
+    let batch = ledgers.fork();
+    let run = pipeline::run(
+        vec![path.to_string_lossy().into_owned()],
+        &batch,
+        options.read.clone(),
+        &options.config,
+    )
+    .instrument(span.clone())
+    .await;
+    let _span = span.entered();
+
+    if !run.succeeded() {
+        error!("batch failed, moving the file to failed");
+        move_to(path, FAILED_DIR)?;
+        return Ok(());
+    }
+
+    // The state, with the transactions not committed yet, is saved before
+    // the transactions are committed and the file moved, so a crash in
+    // between neither applies the file twice nor loses its transactions
+    snapshot.update(&batch, vec![name], &options.state)?;
+    // The ledgers are replaced first, so the committed transactions they
+    // share with the batch are not copied
+    *ledgers = batch;
+    ledgers.commit().map_err(SnapshotError::from)?;
+    writer::write_balances(
+        &ledgers.clients.lock().unwrap(),
+        options.config.output.format,
+        options.config.output.sort,
+        Some(&options.output),
+    )?;
+    move_to(path, PROCESSED_DIR)?;
+    info!(
+        rows = run.input.map_or(0, |report| report.rows),
+        "batch applied"
+    );
+    Ok(())
+}
+
+// Lists the candidate files of the directory, sorted by name
+fn scan(options: &WatchOptions) -> Result<Vec<PathBuf>, WatchError> {
+    let mut files: Vec<PathBuf> = fs::read_dir(&options.dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .filter(|path| !is_ignored(path, options))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+// Hidden and partial files, and the files written by the watcher, are ignored
+fn is_ignored(path: &Path, options: &WatchOptions) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.starts_with('.')
+        || name.ends_with(".tmp")
+        || name.ends_with(".part")
+        || same_file(path, &options.output)
+        || same_file(path, &options.state)
+        || is_transaction_log(path, &options.state)
+}
+
+// The transaction logs are named after the state file, next to it
+fn is_transaction_log(path: &Path, state: &Path) -> bool {
+    let mut prefix = state.file_name().unwrap_or_default().to_os_string();
+    prefix.push(".transactions.");
+    same_file(
+        path.parent().unwrap_or(Path::new(".")),
+        state.parent().unwrap_or(Path::new(".")),
+    ) && path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .starts_with(&*prefix.to_string_lossy())
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+// Moves a file to a subdirectory, next to it, without overwriting older files
+fn move_to(path: &Path, dir: &str) -> Result<(), WatchError> {
+    let parent = path.parent().unwrap_or(Path::new("."));
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut target = parent.join(dir).join(&*name);
+    let mut copy = 1;
+    while target.exists() {
+        target = parent.join(dir).join(format!("{name}.{copy}"));
+        copy += 1;
+    }
+    fs::rename(path, &target)?;
+    Ok(())
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::csv::{reader, writer::OutputFormat};
+    use rust_decimal::Decimal;
+
+    fn options(dir: &Path) -> WatchOptions {
+        WatchOptions {
+            dir: dir.join("landing"),
+            output: dir.join("balances.csv"),
+            state: dir.join("state.json"),
+            tx_store: None,
+            interval: Duration::from_millis(10),
+            once: true,
+            read: ReadOptions::default(),
+            config: EngineConfig::default(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_watch_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(dir.path());
+        fs::create_dir_all(&options.dir).unwrap();
+        fs::write(
+            options.dir.join("1.csv"),
+            "type,client,tx,amount\ndeposit,1,1,10.0\n",
+        )
+        .unwrap();
+        fs::write(
+            options.dir.join("2.csv"),
+            "type,client,tx,amount\ndeposit,1,2,5.0\ndeposit,x,3,1.0\n",
+        )
+        .unwrap();
+        watch(options.clone()).await.unwrap();
+
+        assert!(options.dir.join(PROCESSED_DIR).join("1.csv").exists());
+        assert!(options.dir.join(FAILED_DIR).join("2.csv").exists());
+        let balances = reader::read_accounts(&options.output.to_string_lossy()).unwrap();
+        assert_eq!(balances[&1].available(), Decimal::new(10, 0));
+
+        // The state survives a restart
+        fs::write(
+            options.dir.join("3.csv"),
+            "type,client,tx,amount\nwithdrawal,1,4,4.0\n",
+        )
+        .unwrap();
+        watch(options.clone()).await.unwrap();
+        let balances = reader::read_accounts(&options.output.to_string_lossy()).unwrap();
+        assert_eq!(balances[&1].available(), Decimal::new(6, 0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_watch_skips_pending_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = options(dir.path());
+        fs::create_dir_all(&options.dir).unwrap();
+        fs::write(
+            options.dir.join("1.csv"),
+            "type,client,tx,amount\ndeposit,1,1,10.0\n",
+        )
+        .unwrap();
+        Snapshot::capture(&Ledgers::new(false), vec!["1.csv".to_string()])
+            .unwrap()
+            .save(&options.state)
+            .unwrap();
+        watch(options.clone()).await.unwrap();
+
+        assert!(options.dir.join(PROCESSED_DIR).join("1.csv").exists());
+        assert!(!options.output.exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_watch_disk_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = WatchOptions {
+            tx_store: Some(dir.path().join("transactions.redb")),
+            ..options(dir.path())
+        };
+        fs::create_dir_all(&options.dir).unwrap();
+        fs::write(
+            options.dir.join("1.csv"),
+            "type,client,tx,amount\ndeposit,1,1,10.0\n",
+        )
+        .unwrap();
+        watch(options.clone()).await.unwrap();
+
+        // The deposit of the first batch is disputable after a restart
+        fs::write(
+            options.dir.join("2.csv"),
+            "type,client,tx,amount\ndispute,1,1,\n",
+        )
+        .unwrap();
+        watch(options.clone()).await.unwrap();
+        let balances = reader::read_accounts(&options.output.to_string_lossy()).unwrap();
+        assert_eq!(balances[&1].held(), Decimal::new(10, 0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_watch_crash_before_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = WatchOptions {
+            tx_store: Some(dir.path().join("transactions.redb")),
+            ..options(dir.path())
+        };
+        fs::create_dir_all(&options.dir).unwrap();
+        fs::write(
+            options.dir.join("1.csv"),
+            "type,client,tx,amount\ndeposit,1,1,10.0\n",
+        )
+        .unwrap();
+        let store = DiskStore::open(options.tx_store.as_deref().unwrap()).unwrap();
+        let ledgers = Snapshot::default()
+            .ledgers(false, Some(Box::new(store)))
+            .unwrap();
+        let batch = ledgers.fork();
+        let input = options.dir.join("1.csv").to_string_lossy().into_owned();
+        let run = pipeline::run(vec![input], &batch, ReadOptions::default(), &options.config).await;
+        assert!(run.succeeded());
+        Snapshot::capture(&batch, vec!["1.csv".to_string()])
+            .unwrap()
+            .save(&options.state)
+            .unwrap();
+        // Crashes before the transactions are committed
+        drop((batch, ledgers));
+
+        fs::write(
+            options.dir.join("2.csv"),
+            "type,client,tx,amount\ndispute,1,1,\n",
+        )
+        .unwrap();
+        watch(options.clone()).await.unwrap();
+        assert!(options.dir.join(PROCESSED_DIR).join("1.csv").exists());
+        let balances = reader::read_accounts(&options.output.to_string_lossy()).unwrap();
+        assert_eq!(balances[&1].available(), Decimal::ZERO);
+        assert_eq!(balances[&1].held(), Decimal::new(10, 0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_watch_output_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = options(dir.path());
+        options.config.output.format = OutputFormat::Json;
+        fs::create_dir_all(&options.dir).unwrap();
+        fs::write(
+            options.dir.join("1.csv"),
+            "type,client,tx,amount\ndeposit,1,1,10.0\n",
+        )
+        .unwrap();
+        watch(options.clone()).await.unwrap();
+        let balances: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&options.output).unwrap()).unwrap();
+        assert_eq!(balances[0]["client"], 1);
+    }
+}
@@ -0,0 +1,201 @@
+use crate::store::{StoreError, TransactionStore};
+use crate::structs::transaction::TransactionRecord;
+use redb::{Database, ReadableTable, TableDefinition};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{debug, info, instrument};
+
+const TRANSACTIONS: TableDefinition<u32, &[u8]> = TableDefinition::new("transactions");
+const STATE: TableDefinition<&str, &[u8]> = TableDefinition::new("state");
+
+// Key of the engine state in the state table
+const ENGINE_STATE: &str = "engine";
+
+// Transaction store kept in an embedded key-value database file.
+// Records are buffered in memory and only written when the store is
+// committed, along with the engine state, so a run that doesn't commit
+// leaves the database as it was
+#[derive(Clone)]
+pub struct DiskStore {
+    db: Arc<Database>,
+    pending: HashMap<u32, TransactionRecord>,
+    // Engine state to write on the next commit
+    state: Option<Vec<u8>>,
+}
+
+impl DiskStore {
+    /// Opens the database file, creating it when it doesn't exist
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the database file
+    #[instrument(name = "disk_store")]
+    pub fn open(path: &Path) -> Result<DiskStore, StoreError> {
+        let db = Database::create(path).map_err(redb::Error::from)?;
+        // Creates the tables, so they can always be opened by readers
+        let write = db.begin_write().map_err(redb::Error::from)?;
+        write.open_table(TRANSACTIONS).map_err(redb::Error::from)?;
+        write.open_table(STATE).map_err(redb::Error::from)?;
+        write.commit().map_err(redb::Error::from)?;
+        info!("transaction database opened");
+        Ok(DiskStore {
+            db: Arc::new(db),
+            pending: HashMap::new(),
+            state: None,
+        })
+    }
+}
+
+impl TransactionStore for DiskStore {
+    fn get(&self, tx: u32) -> Result<Option<TransactionRecord>, StoreError> {
+        if let Some(record) = self.pending.get(&tx) {
+            return Ok(Some(*record));
+        }
+        let read = self.db.begin_read().map_err(redb::Error::from)?;
+        let table = read.open_table(TRANSACTIONS).map_err(redb::Error::from)?;
+        match table.get(tx).map_err(redb::Error::from)? {
+            Some(value) => Ok(Some(serde_json::from_slice(value.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn insert(&mut self, tx: u32, record: TransactionRecord) -> Result<(), StoreError> {
+        self.pending.insert(tx, record);
+        Ok(())
+    }
+
+    fn records(&self) -> Result<Vec<(u32, TransactionRecord)>, StoreError> {
+        let read = self.db.begin_read().map_err(redb::Error::from)?;
+        let table = read.open_table(TRANSACTIONS).map_err(redb::Error::from)?;
+        let mut records = HashMap::new();
+        for entry in table.iter().map_err(redb::Error::from)? {
+            let (tx, value) = entry.map_err(redb::Error::from)?;
+            records.insert(tx.value(), serde_json::from_slice(value.value())?);
+        }
+        records.extend(self.pending.iter().map(|(tx, record)| (*tx, *record)));
+        Ok(records.into_iter().collect())
+    }
+
+    fn fork(&self) -> Box<dyn TransactionStore> {
+        Box::new(self.clone())
+    }
+
+    fn commit(&mut self) -> Result<(), StoreError> {
+        if self.pending.is_empty() && self.state.is_none() {
+            return Ok(());
+        }
+        let write = self.db.begin_write().map_err(redb::Error::from)?;
+        {
+            let mut table = write.open_table(TRANSACTIONS).map_err(redb::Error::from)?;
+            for (tx, record) in &self.pending {
+                table
+                    .insert(*tx, serde_json::to_vec(record)?.as_slice())
+                    .map_err(redb::Error::from)?;
+            }
+            if let Some(state) = &self.state {
+                let mut table = write.open_table(STATE).map_err(redb::Error::from)?;
+                table
+                    .insert(ENGINE_STATE, state.as_slice())
+                    .map_err(redb::Error::from)?;
+            }
+        }
+        write.commit().map_err(redb::Error::from)?;
+        debug!(records = self.pending.len(), "transactions committed");
+        self.pending.clear();
+        self.state = None;
+        Ok(())
+    }
+
+    fn uncommitted(&self) -> Vec<(u32, TransactionRecord)> {
+        self.pending
+            .iter()
+            .map(|(tx, record)| (*tx, *record))
+            .collect()
+    }
+
+    fn is_persistent(&self) -> bool {
+        true
+    }
+
+    fn state(&self) -> Result<Option<Vec<u8>>, StoreError> {
+        let read = self.db.begin_read().map_err(redb::Error::from)?;
+        let table = read.open_table(STATE).map_err(redb::Error::from)?;
+        Ok(table
+            .get(ENGINE_STATE)
+            .map_err(redb::Error::from)?
+            .map(|value| value.value().to_vec()))
+    }
+
+    fn save_state(&mut self, state: Vec<u8>) -> Result<(), StoreError> {
+        self.state = Some(state);
+        Ok(())
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn test_fork_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transactions.redb");
+        let mut store = DiskStore::open(&path).unwrap();
+        store
+            .insert(1, TransactionRecord::new(7, Decimal::new(10, 0), 0))
+            .unwrap();
+        store.commit().unwrap();
+
+        let mut fork = store.fork();
+        fork.insert(2, TransactionRecord::new(7, Decimal::new(5, 0), 1))
+            .unwrap();
+        assert!(fork.get(1).unwrap().is_some());
+        drop(fork);
+        assert!(store.get(2).unwrap().is_none());
+
+        let mut fork = store.fork();
+        let mut charged_back = TransactionRecord::new(7, Decimal::new(5, 0), 1);
+        charged_back.charge_back();
+        fork.insert(2, charged_back).unwrap();
+        fork.commit().unwrap();
+        drop((fork, store));
+
+        let store = DiskStore::open(&path).unwrap();
+        let record = store.get(2).unwrap().unwrap();
+        assert_eq!(record.amount(), Decimal::new(5, 0));
+        assert!(record.charged_back());
+        assert_eq!(store.records().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_commit_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transactions.redb");
+        let mut store = DiskStore::open(&path).unwrap();
+        for tx in 0..20_000 {
+            store
+                .insert(tx, TransactionRecord::new(7, Decimal::new(1, 0), tx as u64))
+                .unwrap();
+        }
+        store.save_state(b"state".to_vec()).unwrap();
+        // Nothing is written before the commit
+        drop(store);
+        let mut store = DiskStore::open(&path).unwrap();
+        assert!(store.records().unwrap().is_empty());
+        assert!(store.state().unwrap().is_none());
+
+        store
+            .insert(1, TransactionRecord::new(7, Decimal::new(1, 0), 0))
+            .unwrap();
+        store.save_state(b"state".to_vec()).unwrap();
+        store.commit().unwrap();
+        drop(store);
+        let store = DiskStore::open(&path).unwrap();
+        assert_eq!(store.records().unwrap().len(), 1);
+        assert_eq!(store.state().unwrap(), Some(b"state".to_vec()));
+    }
+}
@@ -0,0 +1,99 @@
+use crate::store::{StoreError, TransactionStore};
+use crate::structs::transaction::TransactionRecord;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Transaction store held in a HashMap, the key is the tx's ID.
+// The committed records are shared with the forks, the records written
+// since the last commit are kept apart until the next one
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore {
+    committed: Arc<HashMap<u32, TransactionRecord>>,
+    changes: HashMap<u32, TransactionRecord>,
+}
+
+impl From<HashMap<u32, TransactionRecord>> for MemoryStore {
+    fn from(records: HashMap<u32, TransactionRecord>) -> Self {
+        MemoryStore {
+            committed: Arc::new(records),
+            changes: HashMap::new(),
+        }
+    }
+}
+
+impl TransactionStore for MemoryStore {
+    fn get(&self, tx: u32) -> Result<Option<TransactionRecord>, StoreError> {
+        Ok(self
+            .changes
+            .get(&tx)
+            .or_else(|| self.committed.get(&tx))
+            .copied())
+    }
+
+    fn insert(&mut self, tx: u32, record: TransactionRecord) -> Result<(), StoreError> {
+        self.changes.insert(tx, record);
+        Ok(())
+    }
+
+    fn records(&self) -> Result<Vec<(u32, TransactionRecord)>, StoreError> {
+        Ok(self
+            .committed
+            .iter()
+            .filter(|(tx, _)| !self.changes.contains_key(tx))
+            .chain(self.changes.iter())
+            .map(|(tx, record)| (*tx, *record))
+            .collect())
+    }
+
+    // The fork shares the committed records, only the changes are copied
+    fn fork(&self) -> Box<dyn TransactionStore> {
+        Box::new(self.clone())
+    }
+
+    // The committed records are only copied if a fork still shares them
+    fn commit(&mut self) -> Result<(), StoreError> {
+        if !self.changes.is_empty() {
+            Arc::make_mut(&mut self.committed).extend(self.changes.drain());
+        }
+        Ok(())
+    }
+
+    fn uncommitted(&self) -> Vec<(u32, TransactionRecord)> {
+        self.changes
+            .iter()
+            .map(|(tx, record)| (*tx, *record))
+            .collect()
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn test_fork_commit() {
+        let mut store = MemoryStore::default();
+        store
+            .insert(1, TransactionRecord::new(1, Decimal::new(10, 0), 0))
+            .unwrap();
+        store.commit().unwrap();
+        assert!(store.uncommitted().is_empty());
+
+        let mut fork = store.fork();
+        fork.insert(2, TransactionRecord::new(1, Decimal::new(5, 0), 1))
+            .unwrap();
+        assert_eq!(fork.uncommitted().len(), 1);
+        assert_eq!(fork.records().unwrap().len(), 2);
+        assert!(store.get(2).unwrap().is_none());
+
+        // The fork keeps its changes once committed
+        drop(store);
+        fork.commit().unwrap();
+        assert!(fork.uncommitted().is_empty());
+        assert_eq!(fork.get(1).unwrap().unwrap().amount(), Decimal::new(10, 0));
+        assert_eq!(fork.get(2).unwrap().unwrap().amount(), Decimal::new(5, 0));
+    }
+}
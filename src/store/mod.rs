@@ -0,0 +1,132 @@
+use crate::structs::{
+    clients::ClientAccount, statement::StatementEntry, transaction::TransactionRecord,
+};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+pub mod disk;
+pub mod memory;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+// Store Error definition
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("Error accessing the transaction database")]
+    DatabaseError(#[source] Box<redb::Error>),
+    #[error("Error encoding a transaction record")]
+    EncodingError(#[from] serde_json::Error),
+    #[cfg(feature = "sqlite")]
+    #[error("Error accessing the SQLite database")]
+    SqliteError(#[source] Box<rusqlite::Error>),
+}
+
+// The database errors are boxed, they are much larger than the others
+impl From<redb::Error> for StoreError {
+    fn from(e: redb::Error) -> Self {
+        StoreError::DatabaseError(Box::new(e))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::SqliteError(Box::new(e))
+    }
+}
+
+// Transaction store shared by the pipeline tasks
+pub type SharedStore = Arc<Mutex<Box<dyn TransactionStore>>>;
+
+/// Storage of the deposits and withdrawals that can be disputed,
+/// by transaction id
+pub trait TransactionStore: Send {
+    /// Returns the record of a transaction, if it was stored
+    fn get(&self, tx: u32) -> Result<Option<TransactionRecord>, StoreError>;
+
+    /// Stores a record, replacing the previous one with the same id
+    fn insert(&mut self, tx: u32, record: TransactionRecord) -> Result<(), StoreError>;
+
+    /// Returns every stored record
+    fn records(&self) -> Result<Vec<(u32, TransactionRecord)>, StoreError>;
+
+    /// Returns a store that sees the same records, but whose changes are
+    /// only applied to the underlying storage once committed
+    fn fork(&self) -> Box<dyn TransactionStore>;
+
+    /// Makes the changes durable
+    fn commit(&mut self) -> Result<(), StoreError>;
+
+    /// Returns the records written since the last commit, so the changes
+    /// can be saved with the engine state before they are committed
+    fn uncommitted(&self) -> Vec<(u32, TransactionRecord)> {
+        vec![]
+    }
+
+    /// Whether the records outlive the process, so they don't need to be
+    /// saved with the engine state
+    fn is_persistent(&self) -> bool {
+        false
+    }
+
+    /// Returns the engine state saved with the records, `None` if there is
+    /// none. It is opaque to the store
+    fn state(&self) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(None)
+    }
+
+    /// Saves the engine state with the records on the next commit, so both
+    /// are made durable together. Only a persistent store keeps it
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - Encoded engine state, replacing the saved one
+    fn save_state(&mut self, _state: Vec<u8>) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// Whether the store keeps the client accounts and the transactions
+    /// history, so `record` is called after every processed transaction
+    fn keeps_history(&self) -> bool {
+        false
+    }
+
+    /// Records a processed transaction, applied or rejected, along with
+    /// the records and accounts it changed, as one change of the store.
+    /// The records stored ahead of their transaction wait for it
+    ///
+    /// # Arguments
+    ///
+    /// * `seq` - Position of the transaction in the processing order
+    /// * `entry` - Outcome of the transaction
+    /// * `accounts` - Accounts the transaction changed, right after it: the
+    ///   client's, if it has one, and the ones whose holds were released
+    fn record(
+        &mut self,
+        _seq: u64,
+        _entry: &StatementEntry,
+        _accounts: &[ClientAccount],
+    ) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// Saves every client account on the next commit, when the store keeps
+    /// the accounts
+    ///
+    /// # Arguments
+    ///
+    /// * `accounts` - Every client account
+    fn save_accounts(&mut self, _accounts: Vec<ClientAccount>) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// Wraps a store so it can be shared by the pipeline tasks
+///
+/// # Arguments
+///
+/// * `store` - Transaction store
+pub fn shared(store: impl TransactionStore + 'static) -> SharedStore {
+    Arc::new(Mutex::new(Box::new(store)))
+}
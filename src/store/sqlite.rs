@@ -0,0 +1,523 @@
+use crate::store::{StoreError, TransactionStore};
+use crate::structs::{
+    clients::ClientAccount,
+    statement::{EntryStatus, StatementEntry},
+    transaction::{AuthorizationState, RecordKind, RecordStatus, TransactionRecord},
+};
+use rusqlite::{params, Connection, OptionalExtension};
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, error, info, instrument};
+
+// Schema migrations, in order. The number of the ones applied is kept in
+// the database `user_version`, so only the new ones run when it is opened.
+// Amounts are stored as text, to keep every decimal place
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE accounts (
+        client INTEGER PRIMARY KEY,
+        available TEXT NOT NULL,
+        held TEXT NOT NULL,
+        total TEXT NOT NULL,
+        locked INTEGER NOT NULL
+    );
+    CREATE TABLE transactions (
+        tx INTEGER PRIMARY KEY,
+        client INTEGER NOT NULL,
+        amount TEXT NOT NULL,
+        disputed INTEGER NOT NULL,
+        seq INTEGER NOT NULL
+    );
+    CREATE TABLE history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        client INTEGER NOT NULL,
+        tx INTEGER NOT NULL,
+        type TEXT NOT NULL,
+        amount TEXT,
+        status TEXT NOT NULL,
+        reason TEXT,
+        file TEXT,
+        line INTEGER
+    );
+    CREATE INDEX history_client ON history (client);
+    CREATE INDEX history_tx ON history (tx);
+",
+    "
+    ALTER TABLE accounts ADD COLUMN pending TEXT NOT NULL DEFAULT '0';
+",
+    "
+    ALTER TABLE accounts ADD COLUMN authorized TEXT NOT NULL DEFAULT '0';
+    ALTER TABLE transactions ADD COLUMN authorization TEXT;
+",
+    "
+    ALTER TABLE transactions ADD COLUMN kind TEXT NOT NULL DEFAULT 'deposit';
+    ALTER TABLE transactions ADD COLUMN refunded TEXT NOT NULL DEFAULT '0';
+",
+    "
+    ALTER TABLE accounts ADD COLUMN status TEXT NOT NULL DEFAULT 'active';
+    ALTER TABLE accounts ADD COLUMN status_reason TEXT;
+    UPDATE accounts SET status = 'locked' WHERE locked = 1;
+    ALTER TABLE accounts DROP COLUMN locked;
+",
+    "
+    ALTER TABLE accounts ADD COLUMN tier TEXT;
+    ALTER TABLE accounts ADD COLUMN opened TEXT;
+",
+    "
+    ALTER TABLE transactions ADD COLUMN charged_back INTEGER NOT NULL DEFAULT 0;
+",
+    "
+    ALTER TABLE transactions ADD COLUMN status TEXT NOT NULL DEFAULT 'applied';
+",
+    "
+    CREATE TABLE state (
+        key TEXT PRIMARY KEY,
+        value BLOB NOT NULL
+    );
+",
+];
+
+// Key of the engine state in the state table
+const ENGINE_STATE: &str = "engine";
+
+// Transaction store kept in a SQLite database file, along with the client
+// accounts and the history of every processed transaction, so they can be
+// queried with SQL after a run.
+// Each processed transaction is written in its own database transaction,
+// with the records and accounts it changed; a forked store wraps them in a
+// savepoint, released when committed
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+    // Records not written yet, the ones stored ahead of their transaction
+    // included
+    pending: HashMap<u32, TransactionRecord>,
+    // Ids of the pending records, by the position of the transaction that
+    // created them, to write them with their transaction
+    pending_order: BTreeMap<u64, u32>,
+    // Accounts and engine state to write on the next commit
+    accounts: Vec<ClientAccount>,
+    state: Option<Vec<u8>>,
+    // Whether the store is a fork whose savepoint is still open
+    batch: bool,
+}
+
+impl SqliteStore {
+    /// Opens the database file, creating it when it doesn't exist,
+    /// and applies the pending schema migrations
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the database file
+    #[instrument(name = "sqlite_store")]
+    pub fn open(path: &Path) -> Result<SqliteStore, StoreError> {
+        let mut conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let migrate = conn.transaction()?;
+            migrate.execute_batch(migration)?;
+            migrate.pragma_update(None, "user_version", applied + 1)?;
+            migrate.commit()?;
+            info!(version = applied + 1, "database migrated");
+        }
+        info!("transaction database opened");
+        Ok(SqliteStore {
+            conn: Arc::new(Mutex::new(conn)),
+            pending: HashMap::new(),
+            pending_order: BTreeMap::new(),
+            accounts: vec![],
+            state: None,
+            batch: false,
+        })
+    }
+
+    // Writes the pending records of the transactions up to a position of
+    // the processing order, every one when `None`, on the connection's
+    // current transaction
+    fn flush(&mut self, conn: &Connection, up_to: Option<u64>) -> Result<(), StoreError> {
+        let ready = match up_to {
+            Some(seq) => {
+                let later = self.pending_order.split_off(&(seq + 1));
+                std::mem::replace(&mut self.pending_order, later)
+            }
+            None => std::mem::take(&mut self.pending_order),
+        };
+        let mut insert = conn.prepare_cached(
+            "INSERT OR REPLACE INTO transactions
+             (tx, client, amount, disputed, seq, authorization, kind, refunded, charged_back,
+              status)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        )?;
+        for tx in ready.into_values() {
+            // A tx id reused by a later transaction was replaced by its record
+            let Some(record) = self.pending.remove(&tx) else {
+                continue;
+            };
+            insert.execute(params![
+                tx,
+                record.client(),
+                record.amount().to_string(),
+                record.disputed(),
+                record.seq() as i64,
+                record.authorization_state().map(|state| match state {
+                    AuthorizationState::Open => "open",
+                    AuthorizationState::Captured => "captured",
+                    AuthorizationState::Voided => "voided",
+                    AuthorizationState::Expired => "expired",
+                }),
+                match record.kind() {
+                    RecordKind::Deposit => "deposit",
+                    RecordKind::Withdrawal => "withdrawal",
+                },
+                record.refunded().to_string(),
+                record.charged_back(),
+                match record.status() {
+                    RecordStatus::Pending => "pending",
+                    RecordStatus::Applied => "applied",
+                    RecordStatus::Rejected => "rejected",
+                }
+            ])?;
+        }
+        Ok(())
+    }
+}
+
+impl TransactionStore for SqliteStore {
+    fn get(&self, tx: u32) -> Result<Option<TransactionRecord>, StoreError> {
+        if let Some(record) = self.pending.get(&tx) {
+            return Ok(Some(*record));
+        }
+        let conn = self.conn.lock().unwrap();
+        let mut select = conn.prepare_cached(
+            "SELECT client, amount, disputed, seq, authorization, kind, refunded, charged_back,
+             status FROM transactions WHERE tx = ?1",
+        )?;
+        Ok(select.query_row([tx], read_record).optional()?)
+    }
+
+    fn insert(&mut self, tx: u32, record: TransactionRecord) -> Result<(), StoreError> {
+        self.pending.insert(tx, record);
+        self.pending_order.insert(record.seq(), tx);
+        Ok(())
+    }
+
+    fn records(&self) -> Result<Vec<(u32, TransactionRecord)>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut select = conn.prepare_cached(
+            "SELECT client, amount, disputed, seq, authorization, kind, refunded, charged_back,
+             status, tx FROM transactions",
+        )?;
+        let mut records = select
+            .query_map([], |row| Ok((row.get(9)?, read_record(row)?)))?
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        records.extend(self.pending.iter().map(|(tx, record)| (*tx, *record)));
+        Ok(records.into_iter().collect())
+    }
+
+    fn fork(&self) -> Box<dyn TransactionStore> {
+        if let Err(e) = self.conn.lock().unwrap().execute_batch("SAVEPOINT batch") {
+            error!(error = %e, "failed opening the batch savepoint");
+        }
+        Box::new(SqliteStore {
+            conn: Arc::clone(&self.conn),
+            pending: self.pending.clone(),
+            pending_order: self.pending_order.clone(),
+            accounts: self.accounts.clone(),
+            state: self.state.clone(),
+            batch: true,
+        })
+    }
+
+    fn commit(&mut self) -> Result<(), StoreError> {
+        let conn = Arc::clone(&self.conn);
+        let conn = conn.lock().unwrap();
+        conn.execute_batch("SAVEPOINT commit_pending")?;
+        let written = self.flush(&conn, None).and_then(|()| {
+            for account in self.accounts.drain(..) {
+                write_account(&conn, &account)?;
+            }
+            if let Some(state) = self.state.take() {
+                conn.prepare_cached("INSERT OR REPLACE INTO state (key, value) VALUES (?1, ?2)")?
+                    .execute(params![ENGINE_STATE, state])?;
+            }
+            Ok(())
+        });
+        if let Err(e) = written {
+            conn.execute_batch("ROLLBACK TO commit_pending; RELEASE commit_pending")?;
+            return Err(e);
+        }
+        conn.execute_batch("RELEASE commit_pending")?;
+        if self.batch {
+            conn.execute_batch("RELEASE batch")?;
+            self.batch = false;
+        }
+        debug!("transactions committed");
+        Ok(())
+    }
+
+    fn is_persistent(&self) -> bool {
+        true
+    }
+
+    fn state(&self) -> Result<Option<Vec<u8>>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut select = conn.prepare_cached("SELECT value FROM state WHERE key = ?1")?;
+        Ok(select
+            .query_row([ENGINE_STATE], |row| row.get(0))
+            .optional()?)
+    }
+
+    fn save_state(&mut self, state: Vec<u8>) -> Result<(), StoreError> {
+        self.state = Some(state);
+        Ok(())
+    }
+
+    fn keeps_history(&self) -> bool {
+        true
+    }
+
+    fn record(
+        &mut self,
+        seq: u64,
+        entry: &StatementEntry,
+        accounts: &[ClientAccount],
+    ) -> Result<(), StoreError> {
+        let conn = Arc::clone(&self.conn);
+        let conn = conn.lock().unwrap();
+        conn.execute_batch("SAVEPOINT apply")?;
+        let applied = self.flush(&conn, Some(seq)).and_then(|()| {
+            for account in accounts {
+                write_account(&conn, account)?;
+            }
+            conn.prepare_cached(
+                "INSERT INTO history (client, tx, type, amount, status, reason, file, line)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?
+            .execute(params![
+                entry.client(),
+                entry.tx(),
+                entry.tx_type(),
+                entry.amount().map(|amount| amount.to_string()),
+                match entry.status() {
+                    EntryStatus::Applied => "applied",
+                    EntryStatus::Rejected => "rejected",
+                },
+                entry.reason(),
+                entry.file(),
+                entry.line().map(|line| line as i64)
+            ])?;
+            Ok(())
+        });
+        match applied {
+            Ok(()) => conn.execute_batch("RELEASE apply")?,
+            Err(e) => {
+                conn.execute_batch("ROLLBACK TO apply; RELEASE apply")?;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn save_accounts(&mut self, accounts: Vec<ClientAccount>) -> Result<(), StoreError> {
+        self.accounts = accounts;
+        Ok(())
+    }
+}
+
+// A fork that wasn't committed leaves the database as it was before it
+impl Drop for SqliteStore {
+    fn drop(&mut self) {
+        if self.batch {
+            let conn = self.conn.lock().unwrap();
+            if let Err(e) = conn.execute_batch("ROLLBACK TO batch; RELEASE batch") {
+                error!(error = %e, "failed discarding the batch");
+            }
+        }
+    }
+}
+
+// Writes an account, replacing the previous row of the client
+fn write_account(conn: &Connection, account: &ClientAccount) -> Result<(), StoreError> {
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO accounts
+         (client, available, held, pending, authorized, total, status,
+          status_reason, tier, opened)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+    )?
+    .execute(params![
+        account.client(),
+        account.available().to_string(),
+        account.held().to_string(),
+        account.pending().to_string(),
+        account.authorized().to_string(),
+        account.total().to_string(),
+        account.status().to_string(),
+        account.status_reason(),
+        account.tier(),
+        account.opened()
+    ])?;
+    Ok(())
+}
+
+// Builds a record from its `client, amount, disputed, seq, authorization,
+// kind, refunded, charged_back, status` columns
+fn read_record(row: &rusqlite::Row) -> rusqlite::Result<TransactionRecord> {
+    let amount = read_decimal(row, 1)?;
+    let (client, seq) = (row.get(0)?, row.get::<_, i64>(3)? as u64);
+    let mut record = match (row.get::<_, Option<String>>(4)?, row.get::<_, String>(5)?) {
+        (None, kind) if kind == "withdrawal" => TransactionRecord::withdrawal(client, amount, seq),
+        (None, _) => TransactionRecord::new(client, amount, seq),
+        (Some(state), _) => {
+            let mut record = TransactionRecord::authorization(client, amount, seq);
+            match state.as_str() {
+                "captured" => record.capture(amount),
+                "voided" => record.release(AuthorizationState::Voided),
+                "expired" => record.release(AuthorizationState::Expired),
+                _ => (),
+            }
+            record
+        }
+    };
+    record.refund(read_decimal(row, 6)?);
+    if row.get(2)? {
+        record.dispute();
+    }
+    if row.get(7)? {
+        record.charge_back();
+    }
+    match row.get::<_, String>(8)?.as_str() {
+        "pending" => (),
+        status => record.settle(status == "applied"),
+    }
+    Ok(record)
+}
+
+// Parses an amount stored as text
+fn read_decimal(row: &rusqlite::Row, column: usize) -> rusqlite::Result<Decimal> {
+    let amount: String = row.get(column)?;
+    amount.parse::<Decimal>().map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(column, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config::Policies;
+    use crate::structs::transaction::Transaction;
+
+    #[test]
+    fn test_record_and_fork() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger.sqlite");
+        let mut store = SqliteStore::open(&path).unwrap();
+        let mut account = ClientAccount::new(7);
+        account
+            .deposit(Decimal::new(10, 0), &Policies::default())
+            .unwrap();
+        let transaction: Transaction =
+            csv::Reader::from_reader("type,client,tx,amount\ndeposit,7,1,10.0".as_bytes())
+                .deserialize()
+                .next()
+                .unwrap()
+                .unwrap();
+        store
+            .insert(1, TransactionRecord::new(7, Decimal::new(10, 0), 0))
+            .unwrap();
+        let entry = StatementEntry::new(1, &transaction, transaction.amount(), None, &account);
+        store.record(0, &entry, &[account]).unwrap();
+
+        let mut fork = store.fork();
+        fork.insert(2, TransactionRecord::new(7, Decimal::new(5, 0), 1))
+            .unwrap();
+        fork.commit().unwrap();
+        drop(fork);
+        let mut fork = store.fork();
+        fork.insert(3, TransactionRecord::new(7, Decimal::new(5, 0), 2))
+            .unwrap();
+        fork.commit().unwrap();
+        let mut fork = fork.fork();
+        fork.insert(4, TransactionRecord::new(7, Decimal::new(5, 0), 3))
+            .unwrap();
+        drop((fork, store));
+
+        let mut store = SqliteStore::open(&path).unwrap();
+        let mut charged_back = store.get(3).unwrap().unwrap();
+        charged_back.charge_back();
+        charged_back.settle(false);
+        store.insert(3, charged_back).unwrap();
+        store.commit().unwrap();
+        drop(store);
+
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.records().unwrap().len(), 3);
+        assert!(store.get(4).unwrap().is_none());
+        assert!(store.get(3).unwrap().unwrap().charged_back());
+        assert_eq!(
+            store.get(3).unwrap().unwrap().status(),
+            RecordStatus::Rejected
+        );
+        let conn = store.conn.lock().unwrap();
+        let (available, status): (String, String) = conn
+            .query_row(
+                "SELECT available, history.status FROM accounts JOIN history USING (client)",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(available, "10");
+        assert_eq!(status, "applied");
+    }
+
+    #[test]
+    fn test_record_accounts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger.sqlite");
+        let mut store = SqliteStore::open(&path).unwrap();
+        let transaction: Transaction = csv::Reader::from_reader(
+            "type,client,tx,amount
+deposit,7,1,10.0"
+                .as_bytes(),
+        )
+        .deserialize()
+        .next()
+        .unwrap()
+        .unwrap();
+        // The deposit of the next transaction is stored ahead of it
+        store
+            .insert(1, TransactionRecord::new(7, Decimal::new(10, 0), 0))
+            .unwrap();
+        store
+            .insert(2, TransactionRecord::new(8, Decimal::new(5, 0), 1))
+            .unwrap();
+        // A hold of client 8 was released before the deposit of client 7
+        let accounts = [ClientAccount::new(7), ClientAccount::new(8)];
+        let entry = StatementEntry::new(1, &transaction, transaction.amount(), None, &accounts[0]);
+        store.record(0, &entry, &accounts).unwrap();
+        let count = |store: &SqliteStore, table: &str| -> i64 {
+            let conn = store.conn.lock().unwrap();
+            conn.query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(count(&store, "transactions"), 1);
+        assert_eq!(count(&store, "accounts"), 2);
+
+        // Every account is written on commit, with the engine state
+        let registered = ClientAccount::new(9);
+        store
+            .save_accounts(vec![accounts[0].clone(), registered])
+            .unwrap();
+        store.save_state(b"state".to_vec()).unwrap();
+        store.commit().unwrap();
+        assert_eq!(count(&store, "transactions"), 2);
+        assert_eq!(count(&store, "accounts"), 3);
+        assert_eq!(store.state().unwrap(), Some(b"state".to_vec()));
+    }
+}
@@ -0,0 +1,31 @@
+use rust_decimal::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// Approval state of an adjustment
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AdjustmentStatus {
+    // Waiting for a second operator, the balances are unchanged
+    Pending,
+    // Applied to the client's available funds
+    Approved,
+}
+
+// Adjustment struct
+// A manual correction of a client's available funds, credited when the
+// amount is positive and debited when negative. It is entered by an
+// operator, the maker, and only applied once another one, the checker,
+// approves it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Adjustment {
+    pub tx: u32,
+    pub client: u16,
+    pub amount: Decimal,
+    pub reason_code: String,
+    pub maker: String,
+    pub checker: Option<String>,
+    pub status: AdjustmentStatus,
+    // Positions in the processing order of the adjustment and its approval
+    pub seq: u64,
+    pub approved_seq: Option<u64>,
+}
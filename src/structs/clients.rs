@@ -1,16 +1,126 @@
+use crate::config::{LockedPolicy, Policies};
+use crate::structs::{
+    journal::{LedgerAccount, Posting},
+    registry::RegisteredClient,
+};
 use rust_decimal::prelude::*;
-use serde::Serialize;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+use thiserror::Error;
 
-pub type ClientResult = Result<(), String>;
+// Client operation rejection definition
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientError {
+    #[error("account locked")]
+    AccountLocked,
+    #[error("account frozen")]
+    AccountFrozen,
+    #[error("account closed")]
+    AccountClosed,
+    #[error("account not locked or frozen")]
+    AccountNotLocked,
+    #[error("account balance not zero")]
+    NonZeroBalance,
+    #[error("insufficient available funds")]
+    InsufficientFunds,
+    #[error("insufficient held funds")]
+    InsufficientHeldFunds,
+    #[error("funds on hold")]
+    FundsOnHold,
+    #[error("insufficient authorized funds")]
+    InsufficientAuthorizedFunds,
+}
+
+pub type ClientResult = Result<(), ClientError>;
+
+// Operating status of an account
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    // Locked by a chargeback, a fraud rule or an operator, until unlocked.
+    // Only the deposits the policies accept are applied
+    Locked,
+    // No funds leave the account: withdrawals, authorizations and refunds
+    // are rejected, everything else is applied
+    Frozen,
+    // Emptied and closed for good, nothing is applied anymore
+    Closed,
+}
+
+impl fmt::Display for AccountStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AccountStatus::Active => "active",
+            AccountStatus::Locked => "locked",
+            AccountStatus::Frozen => "frozen",
+            AccountStatus::Closed => "closed",
+        })
+    }
+}
+
+// Reads the account status, or the `locked` flag of the balances and
+// snapshots written before the statuses existed
+fn status_or_locked<'de, D: Deserializer<'de>>(deserializer: D) -> Result<AccountStatus, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Field {
+        Locked(bool),
+        Status(AccountStatus),
+    }
+    Ok(match Field::deserialize(deserializer)? {
+        Field::Locked(true) => AccountStatus::Locked,
+        Field::Locked(false) => AccountStatus::Active,
+        Field::Status(status) => status,
+    })
+}
+
+// Withdrawals of a client within its current short and long windows, for
+// the window limits of its tier
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct WithdrawalUsage {
+    // Short and long windows of the last withdrawal, with the amounts
+    // withdrawn in them. The aliases read the accounts saved when the
+    // windows were days and months
+    #[serde(alias = "day")]
+    short_window: u64,
+    #[serde(alias = "daily")]
+    short: Decimal,
+    #[serde(alias = "month")]
+    long_window: u64,
+    #[serde(alias = "monthly")]
+    long: Decimal,
+}
 
 // Client account struct
-#[derive(Serialize, Clone, Copy, Debug, Default)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct ClientAccount {
     client: u16,
     available: Decimal,
     held: Decimal,
+    // Deposited funds on hold, not available until released
+    #[serde(default)]
+    pending: Decimal,
+    // Funds reserved by open card authorizations
+    #[serde(default)]
+    authorized: Decimal,
     total: Decimal,
-    locked: bool,
+    #[serde(default, alias = "locked", deserialize_with = "status_or_locked")]
+    status: AccountStatus,
+    // Why the account was locked, frozen or closed
+    #[serde(default)]
+    status_reason: Option<String>,
+    // Tier and opening date given by the client registry
+    #[serde(default)]
+    tier: Option<String>,
+    #[serde(default)]
+    opened: Option<String>,
+    #[serde(default)]
+    usage: WithdrawalUsage,
+    // Postings not recorded in the journal yet
+    #[serde(skip)]
+    postings: Vec<Posting>,
 }
 
 // Client account implementation
@@ -24,53 +134,326 @@ impl ClientAccount {
     /// # Examples
     ///
     /// ```
+    /// # use toy_payments::structs::clients::ClientAccount;
     /// let client = ClientAccount::new(1);
     /// ```
     pub fn new(client: u16) -> ClientAccount {
         ClientAccount {
-            client: client,
+            client,
             available: Decimal::new(0, 4),
             held: Decimal::new(0, 4),
+            pending: Decimal::new(0, 4),
+            authorized: Decimal::new(0, 4),
             total: Decimal::new(0, 4),
-            locked: false,
+            status: AccountStatus::Active,
+            status_reason: None,
+            tier: None,
+            opened: None,
+            usage: WithdrawalUsage::default(),
+            postings: Vec::new(),
         }
     }
 
+    /// Returns the ClientAccount of a client of the registry, without funds
+    ///
+    /// # Arguments
+    ///
+    /// * `registered` - Registry row of the client
+    pub fn open(registered: &RegisteredClient) -> ClientAccount {
+        let mut account = ClientAccount::new(registered.client);
+        account.status = registered.status;
+        account.register(registered);
+        account
+    }
+
+    // Updates the tier and opening date from the registry, the status of an
+    // existing account is only changed by its transactions
+    pub fn register(&mut self, registered: &RegisteredClient) {
+        self.tier.clone_from(&registered.tier);
+        self.opened.clone_from(&registered.opened);
+    }
+
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    pub fn available(&self) -> Decimal {
+        self.available
+    }
+
+    pub fn held(&self) -> Decimal {
+        self.held
+    }
+
+    pub fn pending(&self) -> Decimal {
+        self.pending
+    }
+
+    pub fn authorized(&self) -> Decimal {
+        self.authorized
+    }
+
+    pub fn total(&self) -> Decimal {
+        self.total
+    }
+
+    pub fn status(&self) -> AccountStatus {
+        self.status
+    }
+
+    pub fn status_reason(&self) -> Option<&str> {
+        self.status_reason.as_deref()
+    }
+
+    pub fn tier(&self) -> Option<&str> {
+        self.tier.as_deref()
+    }
+
+    pub fn opened(&self) -> Option<&str> {
+        self.opened.as_deref()
+    }
+
+    // Amounts withdrawn within the given short and long windows
+    pub fn withdrawn(&self, short_window: u64, long_window: u64) -> (Decimal, Decimal) {
+        let usage = &self.usage;
+        (
+            if usage.short_window == short_window {
+                usage.short
+            } else {
+                Decimal::ZERO
+            },
+            if usage.long_window == long_window {
+                usage.long
+            } else {
+                Decimal::ZERO
+            },
+        )
+    }
+
+    // Adds a withdrawal to the amounts of its short and long windows
+    pub fn record_withdrawal(&mut self, short_window: u64, long_window: u64, amount: Decimal) {
+        let (short, long) = self.withdrawn(short_window, long_window);
+        self.usage = WithdrawalUsage {
+            short_window,
+            short: short + amount,
+            long_window,
+            long: long + amount,
+        };
+    }
+
+    // Applies a posting to the funds of this client it moves
+    pub fn apply(&mut self, posting: &Posting) {
+        for (account, amount) in [
+            (posting.debit, -posting.amount),
+            (posting.credit, posting.amount),
+        ] {
+            match account {
+                LedgerAccount::Available(client) if client == self.client => {
+                    self.available += amount
+                }
+                LedgerAccount::Held(client) if client == self.client => self.held += amount,
+                LedgerAccount::Pending(client) if client == self.client => self.pending += amount,
+                LedgerAccount::Authorized(client) if client == self.client => {
+                    self.authorized += amount
+                }
+                _ => {}
+            }
+        }
+        self.update_total();
+    }
+
+    // Postings bringing the ledger accounts of this client from zero to its
+    // funds, for accounts whose history is not in the journal
+    pub fn opening_postings(&self) -> Vec<Posting> {
+        let client = self.client;
+        [
+            (LedgerAccount::Available(client), self.available),
+            (LedgerAccount::Held(client), self.held),
+            (LedgerAccount::Pending(client), self.pending),
+            (LedgerAccount::Authorized(client), self.authorized),
+        ]
+        .into_iter()
+        .filter(|(_, funds)| !funds.is_zero())
+        .map(|(account, funds)| match funds.is_sign_negative() {
+            true => Posting {
+                debit: account,
+                credit: LedgerAccount::HouseCash,
+                amount: -funds,
+            },
+            false => Posting {
+                debit: LedgerAccount::HouseCash,
+                credit: account,
+                amount: funds,
+            },
+        })
+        .collect()
+    }
+
+    // Postings made since the last call, to be recorded in the journal
+    pub fn take_postings(&mut self) -> Vec<Posting> {
+        std::mem::take(&mut self.postings)
+    }
+
+    // Moves funds between two ledger accounts, one of them at least of this
+    // client. The funds only change through postings, so they are always
+    // the balances of the journal
+    fn transfer(&mut self, debit: LedgerAccount, credit: LedgerAccount, amount: Decimal) {
+        if amount.is_zero() {
+            return;
+        }
+        let posting = Posting {
+            debit,
+            credit,
+            amount,
+        };
+        self.apply(&posting);
+        self.postings.push(posting);
+    }
+
     // Updates total amount of the clinet
     pub fn update_total(&mut self) {
-        self.total = self.available + self.held;
+        self.total = self.available + self.held + self.pending + self.authorized;
+        debug_assert!(
+            self.held >= Decimal::ZERO
+                && self.pending >= Decimal::ZERO
+                && self.authorized >= Decimal::ZERO,
+            "negative held, pending or authorized funds for client {}",
+            self.client
+        );
     }
 
     // Make a deposit in the client's account
-    // It should not deposit if the account is locked
-    pub fn deposit(&mut self, amount: Decimal) -> ClientResult {
-        if !self.locked {
-            self.available = self.available + amount;
-            self.update_total();
+    // It should not deposit if the account is closed or locked,
+    // unless the policies accept deposits on locked accounts.
+    // The funds are pending when the policies hold the deposits
+    pub fn deposit(&mut self, amount: Decimal, policies: &Policies) -> ClientResult {
+        self.check_open()?;
+        if policies.locked_accounts != LockedPolicy::AcceptDeposits {
+            self.check_unlocked()?;
         }
+        let funds = match policies.deposit_hold {
+            Some(_) => LedgerAccount::Pending(self.client),
+            None => LedgerAccount::Available(self.client),
+        };
+        self.transfer(LedgerAccount::HouseCash, funds, amount);
+        Ok(())
+    }
+
+    // Release the funds of a deposit on hold, making them available
+    // It should not release more than the pending funds
+    pub fn release(&mut self, amount: Decimal) -> ClientResult {
+        if self.pending < amount {
+            return Err(ClientError::InsufficientFunds);
+        }
+        self.transfer(
+            LedgerAccount::Pending(self.client),
+            LedgerAccount::Available(self.client),
+            amount,
+        );
         Ok(())
     }
 
     // Make a withdrawal in the client's account
-    // It should not withdrawal if the account is locked or
-    // if it doesn't have the necessary funds
-    pub fn withdrawal(&mut self, amount: Decimal) -> ClientResult {
-        if !self.locked && self.available - amount >= Decimal::new(0, 4) {
-            self.available = self.available - amount;
-            self.update_total();
+    // It should not withdrawal if the account is locked, frozen or closed,
+    // or if it doesn't have the necessary funds, up to the overdraft limit
+    pub fn withdrawal(&mut self, amount: Decimal, policies: &Policies) -> ClientResult {
+        self.check_outgoing()?;
+        self.check_available(amount, policies.overdraft_limit)?;
+        self.transfer(
+            LedgerAccount::Available(self.client),
+            LedgerAccount::HouseCash,
+            amount,
+        );
+        Ok(())
+    }
+
+    // Return part of a deposit to its payer
+    // It should not refund if the account is locked, frozen or closed, or if
+    // it doesn't have the necessary funds, pending ones when the deposit is
+    // still on hold
+    pub fn refund(&mut self, amount: Decimal, on_hold: bool) -> ClientResult {
+        self.check_outgoing()?;
+        let funds = if on_hold {
+            if self.pending < amount {
+                return Err(ClientError::InsufficientFunds);
+            }
+            LedgerAccount::Pending(self.client)
+        } else {
+            self.check_available(amount, Decimal::ZERO)?;
+            LedgerAccount::Available(self.client)
+        };
+        self.transfer(funds, LedgerAccount::HouseCash, amount);
+        Ok(())
+    }
+
+    // Apply an approved manual adjustment to the available funds,
+    // a credit when positive and a debit when negative
+    // It applies to locked and frozen accounts too, not to closed ones,
+    // and a debit should not take more than the available funds
+    pub fn adjust(&mut self, amount: Decimal) -> ClientResult {
+        self.check_open()?;
+        let available = LedgerAccount::Available(self.client);
+        if amount.is_sign_negative() {
+            self.check_available(-amount, Decimal::ZERO)?;
+            self.transfer(available, LedgerAccount::Adjustments, -amount);
+        } else {
+            self.transfer(LedgerAccount::Adjustments, available, amount);
         }
         Ok(())
     }
 
+    // Reserve funds for a card authorization
+    // It should not authorize if the account is locked, frozen or closed,
+    // or if it doesn't have the necessary funds
+    pub fn authorize(&mut self, amount: Decimal) -> ClientResult {
+        self.check_outgoing()?;
+        self.check_available(amount, Decimal::ZERO)?;
+        self.transfer(
+            LedgerAccount::Available(self.client),
+            LedgerAccount::Authorized(self.client),
+            amount,
+        );
+        Ok(())
+    }
+
+    // Capture part or all of an authorization, the rest is made available
+    // It should not capture more than the authorized funds
+    pub fn capture(&mut self, authorized: Decimal, captured: Decimal) -> ClientResult {
+        self.check_authorized(authorized)?;
+        let reserved = LedgerAccount::Authorized(self.client);
+        self.transfer(reserved, LedgerAccount::HouseCash, captured);
+        self.transfer(
+            reserved,
+            LedgerAccount::Available(self.client),
+            authorized - captured,
+        );
+        Ok(())
+    }
+
+    // Release the funds of an authorization that was voided or expired
+    pub fn void(&mut self, authorized: Decimal) -> ClientResult {
+        self.check_authorized(authorized)?;
+        self.transfer(
+            LedgerAccount::Authorized(self.client),
+            LedgerAccount::Available(self.client),
+            authorized,
+        );
+        Ok(())
+    }
+
     // Start a dispute in the client's account
     // It should not dispute if the account is locked or
-    // if it doesn't have the necessary funds
-    pub fn dispute(&mut self, amount: Decimal) -> ClientResult {
-        if !self.locked && self.available - amount >= Decimal::new(0, 4) {
-            self.available = self.available - amount;
-            self.held = self.held + amount;
-            self.update_total();
+    // if it doesn't have the necessary funds, when the policies require them
+    pub fn dispute(&mut self, amount: Decimal, policies: &Policies) -> ClientResult {
+        self.check_unlocked()?;
+        if policies.dispute_requires_available {
+            self.check_available(amount, Decimal::ZERO)?;
         }
+        self.transfer(
+            LedgerAccount::Available(self.client),
+            LedgerAccount::Held(self.client),
+            amount,
+        );
         Ok(())
     }
 
@@ -78,11 +461,13 @@ impl ClientAccount {
     // It should not resolve if the account is locked or
     // if it doesn't have the necessary funds
     pub fn resolve(&mut self, amount: Decimal) -> ClientResult {
-        if !self.locked && self.held - amount >= Decimal::new(0, 4) {
-            self.available = self.available + amount;
-            self.held = self.held - amount;
-            self.update_total();
-        }
+        self.check_unlocked()?;
+        self.check_held(amount)?;
+        self.transfer(
+            LedgerAccount::Held(self.client),
+            LedgerAccount::Available(self.client),
+            amount,
+        );
         Ok(())
     }
 
@@ -90,10 +475,106 @@ impl ClientAccount {
     // It should not Chargeback if the account is locked or
     // if it doesn't have the necessary funds
     pub fn chargeback(&mut self, amount: Decimal) -> ClientResult {
-        if !self.locked && self.held - amount >= Decimal::new(0, 4) {
-            self.held = self.held - amount;
-            self.update_total();
-            self.locked = true;
+        self.check_unlocked()?;
+        self.check_held(amount)?;
+        // The held funds go back to the card network
+        self.transfer(
+            LedgerAccount::Held(self.client),
+            LedgerAccount::ChargebackLoss,
+            amount,
+        );
+        self.status = AccountStatus::Locked;
+        self.status_reason = Some("chargeback".to_string());
+        Ok(())
+    }
+
+    // Locks the account, like a chargeback does, without moving any funds
+    // It should not lock a closed account
+    pub fn lock(&mut self, reason: &str) -> ClientResult {
+        self.check_open()?;
+        self.status = AccountStatus::Locked;
+        self.status_reason = Some(reason.to_string());
+        Ok(())
+    }
+
+    // Unlocks or unfreezes the account after a review
+    // It should only unlock a locked or frozen account
+    pub fn unlock(&mut self) -> ClientResult {
+        self.check_open()?;
+        if self.status == AccountStatus::Active {
+            return Err(ClientError::AccountNotLocked);
+        }
+        self.status = AccountStatus::Active;
+        self.status_reason = None;
+        Ok(())
+    }
+
+    // Freezes the account, blocking the funds going out of it
+    // It should not freeze a locked or closed account
+    pub fn freeze(&mut self, reason: &str) -> ClientResult {
+        self.check_unlocked()?;
+        self.status = AccountStatus::Frozen;
+        self.status_reason = Some(reason.to_string());
+        Ok(())
+    }
+
+    // Closes the account for good
+    // It should not close an account already closed or holding any funds,
+    // available, held, pending or authorized
+    pub fn close(&mut self, reason: &str) -> ClientResult {
+        self.check_open()?;
+        let funds = [self.available, self.held, self.pending, self.authorized];
+        if funds.iter().any(|funds| !funds.is_zero()) {
+            return Err(ClientError::NonZeroBalance);
+        }
+        self.status = AccountStatus::Closed;
+        self.status_reason = Some(reason.to_string());
+        Ok(())
+    }
+
+    fn check_authorized(&self, amount: Decimal) -> ClientResult {
+        if self.authorized < amount {
+            return Err(ClientError::InsufficientAuthorizedFunds);
+        }
+        Ok(())
+    }
+
+    fn check_open(&self) -> ClientResult {
+        if self.status == AccountStatus::Closed {
+            return Err(ClientError::AccountClosed);
+        }
+        Ok(())
+    }
+
+    fn check_unlocked(&self) -> ClientResult {
+        self.check_open()?;
+        if self.status == AccountStatus::Locked {
+            return Err(ClientError::AccountLocked);
+        }
+        Ok(())
+    }
+
+    fn check_outgoing(&self) -> ClientResult {
+        self.check_unlocked()?;
+        if self.status == AccountStatus::Frozen {
+            return Err(ClientError::AccountFrozen);
+        }
+        Ok(())
+    }
+
+    fn check_available(&self, amount: Decimal, overdraft: Decimal) -> ClientResult {
+        if self.available - amount < -overdraft {
+            if self.available + self.pending - amount >= -overdraft {
+                return Err(ClientError::FundsOnHold);
+            }
+            return Err(ClientError::InsufficientFunds);
+        }
+        Ok(())
+    }
+
+    fn check_held(&self, amount: Decimal) -> ClientResult {
+        if self.held - amount < Decimal::new(0, 4) {
+            return Err(ClientError::InsufficientHeldFunds);
         }
         Ok(())
     }
@@ -103,6 +584,7 @@ impl ClientAccount {
 mod tests {
 
     use super::*;
+    use crate::structs::journal::Journal;
 
     #[test]
     fn test_update_total() {
@@ -110,8 +592,15 @@ mod tests {
             client: 0,
             available: Decimal::from_f32(15.45).unwrap().round_dp(4),
             held: Decimal::from_f32(14.55).unwrap().round_dp(4),
+            pending: Decimal::new(0, 4),
+            authorized: Decimal::new(0, 4),
             total: Decimal::new(0, 4),
-            locked: false,
+            status: AccountStatus::Active,
+            status_reason: None,
+            tier: None,
+            opened: None,
+            usage: WithdrawalUsage::default(),
+            postings: Vec::new(),
         };
         ca.update_total();
         assert_eq!(ca.total, Decimal::new(30, 0));
@@ -123,10 +612,18 @@ mod tests {
             client: 0,
             available: Decimal::from_f32(15.45).unwrap().round_dp(4),
             held: Decimal::from_f32(14.55).unwrap().round_dp(4),
+            pending: Decimal::new(0, 4),
+            authorized: Decimal::new(0, 4),
             total: Decimal::new(0, 4),
-            locked: false,
+            status: AccountStatus::Active,
+            status_reason: None,
+            tier: None,
+            opened: None,
+            usage: WithdrawalUsage::default(),
+            postings: Vec::new(),
         };
-        ca.deposit(Decimal::new(50, 0)).unwrap();
+        ca.deposit(Decimal::new(50, 0), &Policies::default())
+            .unwrap();
         assert_eq!(ca.available, Decimal::from_f32(65.45).unwrap().round_dp(4));
         assert_eq!(ca.total, Decimal::new(80, 0));
     }
@@ -137,10 +634,20 @@ mod tests {
             client: 0,
             available: Decimal::from_f32(15.45).unwrap().round_dp(4),
             held: Decimal::from_f32(14.55).unwrap().round_dp(4),
+            pending: Decimal::new(0, 4),
+            authorized: Decimal::new(0, 4),
             total: Decimal::new(0, 4),
-            locked: true,
+            status: AccountStatus::Locked,
+            status_reason: None,
+            tier: None,
+            opened: None,
+            usage: WithdrawalUsage::default(),
+            postings: Vec::new(),
         };
-        ca.deposit(Decimal::new(50, 0)).unwrap();
+        assert_eq!(
+            ca.deposit(Decimal::new(50, 0), &Policies::default()),
+            Err(ClientError::AccountLocked)
+        );
         assert_eq!(ca.available, Decimal::from_f32(15.45).unwrap().round_dp(4));
         assert_eq!(ca.total, Decimal::new(0, 0));
     }
@@ -151,10 +658,18 @@ mod tests {
             client: 0,
             available: Decimal::from_f32(15.45).unwrap().round_dp(4),
             held: Decimal::from_f32(14.55).unwrap().round_dp(4),
+            pending: Decimal::new(0, 4),
+            authorized: Decimal::new(0, 4),
             total: Decimal::new(0, 4),
-            locked: false,
+            status: AccountStatus::Active,
+            status_reason: None,
+            tier: None,
+            opened: None,
+            usage: WithdrawalUsage::default(),
+            postings: Vec::new(),
         };
-        ca.withdrawal(Decimal::new(15, 0)).unwrap();
+        ca.withdrawal(Decimal::new(15, 0), &Policies::default())
+            .unwrap();
         assert_eq!(ca.available, Decimal::new(4500, 4));
         assert_eq!(ca.total, Decimal::new(15, 0));
     }
@@ -165,10 +680,20 @@ mod tests {
             client: 0,
             available: Decimal::from_f32(15.45).unwrap().round_dp(4),
             held: Decimal::from_f32(14.55).unwrap().round_dp(4),
+            pending: Decimal::new(0, 4),
+            authorized: Decimal::new(0, 4),
             total: Decimal::new(0, 4),
-            locked: true,
+            status: AccountStatus::Locked,
+            status_reason: None,
+            tier: None,
+            opened: None,
+            usage: WithdrawalUsage::default(),
+            postings: Vec::new(),
         };
-        ca.withdrawal(Decimal::new(15, 0)).unwrap();
+        assert_eq!(
+            ca.withdrawal(Decimal::new(15, 0), &Policies::default()),
+            Err(ClientError::AccountLocked)
+        );
         assert_eq!(ca.available, Decimal::new(1545, 2));
         assert_eq!(ca.total, Decimal::new(0, 0));
     }
@@ -179,10 +704,20 @@ mod tests {
             client: 0,
             available: Decimal::from_f32(15.45).unwrap().round_dp(4),
             held: Decimal::from_f32(14.55).unwrap().round_dp(4),
+            pending: Decimal::new(0, 4),
+            authorized: Decimal::new(0, 4),
             total: Decimal::new(0, 4),
-            locked: false,
+            status: AccountStatus::Active,
+            status_reason: None,
+            tier: None,
+            opened: None,
+            usage: WithdrawalUsage::default(),
+            postings: Vec::new(),
         };
-        ca.withdrawal(Decimal::new(80, 0)).unwrap();
+        assert_eq!(
+            ca.withdrawal(Decimal::new(80, 0), &Policies::default()),
+            Err(ClientError::InsufficientFunds)
+        );
         assert_eq!(ca.available, Decimal::new(1545, 2));
         assert_eq!(ca.total, Decimal::new(0, 0));
     }
@@ -193,11 +728,19 @@ mod tests {
             client: 0,
             available: Decimal::from_f32(15.45).unwrap().round_dp(4),
             held: Decimal::from_f32(14.55).unwrap().round_dp(4),
+            pending: Decimal::new(0, 4),
+            authorized: Decimal::new(0, 4),
             total: Decimal::new(0, 4),
-            locked: false,
+            status: AccountStatus::Active,
+            status_reason: None,
+            tier: None,
+            opened: None,
+            usage: WithdrawalUsage::default(),
+            postings: Vec::new(),
         };
-        ca.dispute(Decimal::new(10, 0)).unwrap();
-        assert_eq!(ca.available, Decimal::new(0545, 2));
+        ca.dispute(Decimal::new(10, 0), &Policies::default())
+            .unwrap();
+        assert_eq!(ca.available, Decimal::new(545, 2));
         assert_eq!(ca.held, Decimal::new(2455, 2));
         assert_eq!(ca.total, Decimal::new(30, 0));
     }
@@ -208,10 +751,20 @@ mod tests {
             client: 0,
             available: Decimal::from_f32(15.45).unwrap().round_dp(4),
             held: Decimal::from_f32(14.55).unwrap().round_dp(4),
+            pending: Decimal::new(0, 4),
+            authorized: Decimal::new(0, 4),
             total: Decimal::new(0, 4),
-            locked: true,
+            status: AccountStatus::Locked,
+            status_reason: None,
+            tier: None,
+            opened: None,
+            usage: WithdrawalUsage::default(),
+            postings: Vec::new(),
         };
-        ca.dispute(Decimal::new(80, 0)).unwrap();
+        assert_eq!(
+            ca.dispute(Decimal::new(80, 0), &Policies::default()),
+            Err(ClientError::AccountLocked)
+        );
         assert_eq!(ca.available, Decimal::new(1545, 2));
         assert_eq!(ca.held, Decimal::new(1455, 2));
         assert_eq!(ca.total, Decimal::new(0, 0));
@@ -223,10 +776,20 @@ mod tests {
             client: 0,
             available: Decimal::from_f32(15.45).unwrap().round_dp(4),
             held: Decimal::from_f32(14.55).unwrap().round_dp(4),
+            pending: Decimal::new(0, 4),
+            authorized: Decimal::new(0, 4),
             total: Decimal::new(0, 4),
-            locked: false,
+            status: AccountStatus::Active,
+            status_reason: None,
+            tier: None,
+            opened: None,
+            usage: WithdrawalUsage::default(),
+            postings: Vec::new(),
         };
-        ca.dispute(Decimal::new(80, 0)).unwrap();
+        assert_eq!(
+            ca.dispute(Decimal::new(80, 0), &Policies::default()),
+            Err(ClientError::InsufficientFunds)
+        );
         assert_eq!(ca.available, Decimal::new(1545, 2));
         assert_eq!(ca.held, Decimal::new(1455, 2));
         assert_eq!(ca.total, Decimal::new(0, 0));
@@ -238,12 +801,19 @@ mod tests {
             client: 0,
             available: Decimal::from_f32(15.45).unwrap().round_dp(4),
             held: Decimal::from_f32(14.55).unwrap().round_dp(4),
+            pending: Decimal::new(0, 4),
+            authorized: Decimal::new(0, 4),
             total: Decimal::new(0, 4),
-            locked: false,
+            status: AccountStatus::Active,
+            status_reason: None,
+            tier: None,
+            opened: None,
+            usage: WithdrawalUsage::default(),
+            postings: Vec::new(),
         };
         ca.resolve(Decimal::new(10, 0)).unwrap();
         assert_eq!(ca.available, Decimal::new(2545, 2));
-        assert_eq!(ca.held, Decimal::new(0455, 2));
+        assert_eq!(ca.held, Decimal::new(455, 2));
         assert_eq!(ca.total, Decimal::new(30, 0));
     }
 
@@ -253,10 +823,20 @@ mod tests {
             client: 0,
             available: Decimal::from_f32(15.45).unwrap().round_dp(4),
             held: Decimal::from_f32(14.55).unwrap().round_dp(4),
+            pending: Decimal::new(0, 4),
+            authorized: Decimal::new(0, 4),
             total: Decimal::new(0, 4),
-            locked: true,
+            status: AccountStatus::Locked,
+            status_reason: None,
+            tier: None,
+            opened: None,
+            usage: WithdrawalUsage::default(),
+            postings: Vec::new(),
         };
-        ca.resolve(Decimal::new(80, 0)).unwrap();
+        assert_eq!(
+            ca.resolve(Decimal::new(80, 0)),
+            Err(ClientError::AccountLocked)
+        );
         assert_eq!(ca.available, Decimal::new(1545, 2));
         assert_eq!(ca.held, Decimal::new(1455, 2));
         assert_eq!(ca.total, Decimal::new(0, 0));
@@ -268,10 +848,20 @@ mod tests {
             client: 0,
             available: Decimal::from_f32(15.45).unwrap().round_dp(4),
             held: Decimal::from_f32(14.55).unwrap().round_dp(4),
+            pending: Decimal::new(0, 4),
+            authorized: Decimal::new(0, 4),
             total: Decimal::new(0, 4),
-            locked: false,
+            status: AccountStatus::Active,
+            status_reason: None,
+            tier: None,
+            opened: None,
+            usage: WithdrawalUsage::default(),
+            postings: Vec::new(),
         };
-        ca.resolve(Decimal::new(80, 0)).unwrap();
+        assert_eq!(
+            ca.resolve(Decimal::new(80, 0)),
+            Err(ClientError::InsufficientHeldFunds)
+        );
         assert_eq!(ca.available, Decimal::new(1545, 2));
         assert_eq!(ca.held, Decimal::new(1455, 2));
         assert_eq!(ca.total, Decimal::new(0, 0));
@@ -283,14 +873,21 @@ mod tests {
             client: 0,
             available: Decimal::from_f32(15.45).unwrap().round_dp(4),
             held: Decimal::from_f32(14.55).unwrap().round_dp(4),
+            pending: Decimal::new(0, 4),
+            authorized: Decimal::new(0, 4),
             total: Decimal::new(0, 4),
-            locked: false,
+            status: AccountStatus::Active,
+            status_reason: None,
+            tier: None,
+            opened: None,
+            usage: WithdrawalUsage::default(),
+            postings: Vec::new(),
         };
         ca.chargeback(Decimal::new(10, 0)).unwrap();
         assert_eq!(ca.available, Decimal::new(1545, 2));
-        assert_eq!(ca.held, Decimal::new(0455, 2));
+        assert_eq!(ca.held, Decimal::new(455, 2));
         assert_eq!(ca.total, Decimal::new(20, 0));
-        assert_eq!(ca.locked, true);
+        assert_eq!(ca.status, AccountStatus::Locked);
     }
 
     #[test]
@@ -299,10 +896,20 @@ mod tests {
             client: 0,
             available: Decimal::from_f32(15.45).unwrap().round_dp(4),
             held: Decimal::from_f32(14.55).unwrap().round_dp(4),
+            pending: Decimal::new(0, 4),
+            authorized: Decimal::new(0, 4),
             total: Decimal::new(0, 4),
-            locked: true,
+            status: AccountStatus::Locked,
+            status_reason: None,
+            tier: None,
+            opened: None,
+            usage: WithdrawalUsage::default(),
+            postings: Vec::new(),
         };
-        ca.chargeback(Decimal::new(80, 0)).unwrap();
+        assert_eq!(
+            ca.chargeback(Decimal::new(80, 0)),
+            Err(ClientError::AccountLocked)
+        );
         assert_eq!(ca.available, Decimal::new(1545, 2));
         assert_eq!(ca.held, Decimal::new(1455, 2));
         assert_eq!(ca.total, Decimal::new(0, 0));
@@ -314,12 +921,195 @@ mod tests {
             client: 0,
             available: Decimal::from_f32(15.45).unwrap().round_dp(4),
             held: Decimal::from_f32(14.55).unwrap().round_dp(4),
+            pending: Decimal::new(0, 4),
+            authorized: Decimal::new(0, 4),
             total: Decimal::new(0, 4),
-            locked: false,
+            status: AccountStatus::Active,
+            status_reason: None,
+            tier: None,
+            opened: None,
+            usage: WithdrawalUsage::default(),
+            postings: Vec::new(),
         };
-        ca.chargeback(Decimal::new(80, 0)).unwrap();
+        assert_eq!(
+            ca.chargeback(Decimal::new(80, 0)),
+            Err(ClientError::InsufficientHeldFunds)
+        );
         assert_eq!(ca.available, Decimal::new(1545, 2));
         assert_eq!(ca.held, Decimal::new(1455, 2));
         assert_eq!(ca.total, Decimal::new(0, 0));
     }
+
+    #[test]
+    fn test_policies() {
+        let policies = Policies {
+            locked_accounts: LockedPolicy::AcceptDeposits,
+            overdraft_limit: Decimal::new(10, 0),
+            dispute_requires_available: false,
+            ..Policies::default()
+        };
+        let mut ca = ClientAccount::new(0);
+        ca.withdrawal(Decimal::new(10, 0), &policies).unwrap();
+        assert_eq!(
+            ca.withdrawal(Decimal::new(1, 0), &policies),
+            Err(ClientError::InsufficientFunds)
+        );
+        ca.dispute(Decimal::new(5, 0), &policies).unwrap();
+        assert_eq!(ca.available, Decimal::new(-15, 0));
+        ca.status = AccountStatus::Locked;
+        ca.deposit(Decimal::new(20, 0), &policies).unwrap();
+        assert_eq!(ca.total, Decimal::new(10, 0));
+    }
+
+    #[test]
+    fn test_deposit_hold() {
+        let policies = Policies {
+            deposit_hold: Some(10),
+            ..Policies::default()
+        };
+        let mut ca = ClientAccount::new(0);
+        ca.deposit(Decimal::new(10, 0), &policies).unwrap();
+        assert_eq!(ca.pending, Decimal::new(10, 0));
+        assert_eq!(ca.total, Decimal::new(10, 0));
+        assert_eq!(
+            ca.withdrawal(Decimal::new(5, 0), &policies),
+            Err(ClientError::FundsOnHold)
+        );
+        ca.release(Decimal::new(10, 0)).unwrap();
+        ca.withdrawal(Decimal::new(5, 0), &policies).unwrap();
+        assert_eq!(ca.pending, Decimal::ZERO);
+        assert_eq!(ca.total, Decimal::new(5, 0));
+        assert!(ca.release(Decimal::new(1, 0)).is_err());
+    }
+
+    #[test]
+    fn test_refund() {
+        let policies = Policies {
+            deposit_hold: Some(10),
+            ..Policies::default()
+        };
+        let mut ca = ClientAccount::new(0);
+        ca.deposit(Decimal::new(10, 0), &policies).unwrap();
+        ca.refund(Decimal::new(4, 0), true).unwrap();
+        assert_eq!(ca.pending, Decimal::new(6, 0));
+        assert_eq!(
+            ca.refund(Decimal::new(4, 0), false),
+            Err(ClientError::FundsOnHold)
+        );
+        ca.release(Decimal::new(6, 0)).unwrap();
+        ca.refund(Decimal::new(6, 0), false).unwrap();
+        assert_eq!(ca.total, Decimal::ZERO);
+    }
+
+    #[test]
+    fn test_authorization() {
+        let mut ca = ClientAccount::new(0);
+        ca.deposit(Decimal::new(10, 0), &Policies::default())
+            .unwrap();
+        assert_eq!(
+            ca.authorize(Decimal::new(11, 0)),
+            Err(ClientError::InsufficientFunds)
+        );
+        ca.authorize(Decimal::new(8, 0)).unwrap();
+        assert_eq!(ca.available, Decimal::new(2, 0));
+        assert_eq!(ca.total, Decimal::new(10, 0));
+        ca.capture(Decimal::new(8, 0), Decimal::new(5, 0)).unwrap();
+        assert_eq!(ca.available, Decimal::new(5, 0));
+        assert_eq!(ca.authorized, Decimal::ZERO);
+        assert_eq!(ca.total, Decimal::new(5, 0));
+        ca.authorize(Decimal::new(3, 0)).unwrap();
+        ca.void(Decimal::new(3, 0)).unwrap();
+        assert_eq!(ca.available, Decimal::new(5, 0));
+        assert_eq!(
+            ca.void(Decimal::new(3, 0)),
+            Err(ClientError::InsufficientAuthorizedFunds)
+        );
+    }
+
+    #[test]
+    fn test_adjust() {
+        let mut ca = ClientAccount::new(0);
+        ca.freeze("review").unwrap();
+        ca.adjust(Decimal::new(10, 0)).unwrap();
+        assert_eq!(ca.total, Decimal::new(10, 0));
+        assert_eq!(
+            ca.adjust(Decimal::new(-11, 0)),
+            Err(ClientError::InsufficientFunds)
+        );
+        ca.adjust(Decimal::new(-4, 0)).unwrap();
+        assert_eq!(ca.available, Decimal::new(6, 0));
+    }
+
+    #[test]
+    fn test_status() {
+        let policies = Policies::default();
+        let mut ca = ClientAccount::new(0);
+        assert_eq!(ca.unlock(), Err(ClientError::AccountNotLocked));
+        ca.freeze("kyc").unwrap();
+        ca.deposit(Decimal::new(10, 0), &policies).unwrap();
+        assert_eq!(
+            ca.withdrawal(Decimal::new(1, 0), &policies),
+            Err(ClientError::AccountFrozen)
+        );
+        ca.lock("fraud").unwrap();
+        assert_eq!(ca.freeze("kyc"), Err(ClientError::AccountLocked));
+        assert_eq!(ca.status_reason(), Some("fraud"));
+        ca.unlock().unwrap();
+        assert_eq!(ca.close("request"), Err(ClientError::NonZeroBalance));
+        ca.withdrawal(Decimal::new(10, 0), &policies).unwrap();
+        ca.close("request").unwrap();
+        assert_eq!(ca.status(), AccountStatus::Closed);
+        assert_eq!(
+            ca.deposit(Decimal::new(1, 0), &policies),
+            Err(ClientError::AccountClosed)
+        );
+        assert_eq!(ca.unlock(), Err(ClientError::AccountClosed));
+    }
+
+    #[test]
+    fn test_legacy_locked() {
+        let ca: ClientAccount = serde_json::from_str(
+            r#"{"client":1,"available":"0","held":"0","total":"0","locked":true}"#,
+        )
+        .unwrap();
+        assert_eq!(ca.status(), AccountStatus::Locked);
+    }
+
+    #[test]
+    fn test_withdrawn() {
+        let mut ca = ClientAccount::new(0);
+        ca.record_withdrawal(0, 0, Decimal::new(5, 0));
+        ca.record_withdrawal(0, 0, Decimal::new(2, 0));
+        assert_eq!(ca.withdrawn(0, 0), (Decimal::new(7, 0), Decimal::new(7, 0)));
+        ca.record_withdrawal(1, 0, Decimal::new(1, 0));
+        assert_eq!(ca.withdrawn(1, 0), (Decimal::new(1, 0), Decimal::new(8, 0)));
+        assert_eq!(ca.withdrawn(2, 1), (Decimal::ZERO, Decimal::ZERO));
+    }
+
+    #[test]
+    fn test_postings() {
+        let mut ca = ClientAccount::new(1);
+        let policies = Policies::default();
+        ca.deposit(Decimal::new(20, 0), &policies).unwrap();
+        ca.dispute(Decimal::new(20, 0), &policies).unwrap();
+        ca.chargeback(Decimal::new(20, 0)).unwrap();
+        assert_eq!(
+            ca.withdrawal(Decimal::new(1, 0), &policies),
+            Err(ClientError::AccountLocked)
+        );
+        let mut journal = Journal::default();
+        journal.keep_entries();
+        journal.post(0, 1, "deposit", ca.take_postings());
+        assert_eq!(journal.entries().len(), 3);
+        assert!(ca.take_postings().is_empty());
+        let balances = journal.balances();
+        assert_eq!(balances[&LedgerAccount::Available(1)], Decimal::ZERO);
+        assert_eq!(balances[&LedgerAccount::Held(1)], Decimal::ZERO);
+        assert_eq!(balances[&LedgerAccount::HouseCash], Decimal::new(-20, 0));
+        assert_eq!(
+            balances[&LedgerAccount::ChargebackLoss],
+            Decimal::new(20, 0)
+        );
+        assert_eq!(balances.values().sum::<Decimal>(), Decimal::ZERO);
+    }
 }
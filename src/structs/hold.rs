@@ -0,0 +1,27 @@
+use rust_decimal::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// Funds held by a hold
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HoldKind {
+    // Pending funds of a deposit
+    #[default]
+    Deposit,
+    // Funds reserved by a card authorization
+    Authorization,
+}
+
+// Hold struct
+// The funds of a deposit stay pending, and those of an authorization
+// reserved, until the transaction at the `release` position of the
+// processing order
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hold {
+    pub tx: u32,
+    pub client: u16,
+    pub amount: Decimal,
+    pub release: u64,
+    #[serde(default)]
+    pub kind: HoldKind,
+}
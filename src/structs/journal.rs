@@ -0,0 +1,268 @@
+use rust_decimal::prelude::*;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+// Account of the general ledger. The funds of every client are split in
+// their own accounts, the house ones are the counterparts of the money
+// entering or leaving the engine
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LedgerAccount {
+    Available(u16),
+    Held(u16),
+    Pending(u16),
+    Authorized(u16),
+    // Money deposited by the clients and paid out to them
+    HouseCash,
+    // Losses of the chargebacks, the held funds paid back to the card networks
+    ChargebackLoss,
+    // Fees charged to the clients, the manual adjustments were booked to it
+    // before they had their own account
+    Fees,
+    // Counterpart of the manual adjustments
+    Adjustments,
+}
+
+impl LedgerAccount {
+    // Client owning the account, `None` for the house accounts
+    pub fn client(self) -> Option<u16> {
+        match self {
+            LedgerAccount::Available(client)
+            | LedgerAccount::Held(client)
+            | LedgerAccount::Pending(client)
+            | LedgerAccount::Authorized(client) => Some(client),
+            _ => None,
+        }
+    }
+}
+
+// Written as `client:<id>:<funds>` or `house:<name>`
+impl fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerAccount::Available(client) => write!(f, "client:{client}:available"),
+            LedgerAccount::Held(client) => write!(f, "client:{client}:held"),
+            LedgerAccount::Pending(client) => write!(f, "client:{client}:pending"),
+            LedgerAccount::Authorized(client) => write!(f, "client:{client}:authorized"),
+            LedgerAccount::HouseCash => f.write_str("house:cash"),
+            LedgerAccount::ChargebackLoss => f.write_str("house:chargeback_loss"),
+            LedgerAccount::Fees => f.write_str("house:fees"),
+            LedgerAccount::Adjustments => f.write_str("house:adjustments"),
+        }
+    }
+}
+
+impl FromStr for LedgerAccount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts[..] {
+            ["house", "cash"] => Ok(LedgerAccount::HouseCash),
+            ["house", "chargeback_loss"] => Ok(LedgerAccount::ChargebackLoss),
+            ["house", "fees"] => Ok(LedgerAccount::Fees),
+            ["house", "adjustments"] => Ok(LedgerAccount::Adjustments),
+            ["client", client, funds] => {
+                let client = client
+                    .parse()
+                    .map_err(|_| format!("invalid client in ledger account `{s}`"))?;
+                match funds {
+                    "available" => Ok(LedgerAccount::Available(client)),
+                    "held" => Ok(LedgerAccount::Held(client)),
+                    "pending" => Ok(LedgerAccount::Pending(client)),
+                    "authorized" => Ok(LedgerAccount::Authorized(client)),
+                    _ => Err(format!("unknown ledger account `{s}`")),
+                }
+            }
+            _ => Err(format!("unknown ledger account `{s}`")),
+        }
+    }
+}
+
+impl Serialize for LedgerAccount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for LedgerAccount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+// Posting struct
+// Moves an amount from the debited account to the credited one, so every
+// posting is balanced. The balance of an account is what was credited to
+// it minus what was debited from it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Posting {
+    pub debit: LedgerAccount,
+    pub credit: LedgerAccount,
+    pub amount: Decimal,
+}
+
+// Journal entry struct
+// A posting along with the transaction that made it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub seq: u64,
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub tx_type: String,
+    pub debit: LedgerAccount,
+    pub credit: LedgerAccount,
+    pub amount: Decimal,
+}
+
+// Journal struct
+// Balances of the ledger accounts, updated by every movement of funds.
+// The entries themselves are only kept when they are exported
+#[derive(Clone, Debug, Default)]
+pub struct Journal {
+    // Entries in processing order, `None` when they are not kept
+    entries: Option<Vec<JournalEntry>>,
+    balances: BTreeMap<LedgerAccount, Decimal>,
+}
+
+impl Journal {
+    /// Returns a journal starting from the given balances, without entries
+    ///
+    /// # Arguments
+    ///
+    /// * `balances` - Balances of the ledger accounts
+    pub fn with_balances(balances: BTreeMap<LedgerAccount, Decimal>) -> Journal {
+        Journal {
+            entries: None,
+            balances,
+        }
+    }
+
+    /// Keeps the entries posted from now on, so they can be exported
+    pub fn keep_entries(&mut self) {
+        self.entries.get_or_insert_with(Vec::new);
+    }
+
+    /// Records the postings of a transaction
+    ///
+    /// # Arguments
+    ///
+    /// * `seq` - Position of the transaction in the processing order
+    /// * `tx` - Transaction id
+    /// * `tx_type` - Type of the transaction, or of the hold released
+    /// * `postings` - Postings made by the transaction
+    pub fn post(&mut self, seq: u64, tx: u32, tx_type: &str, postings: Vec<Posting>) {
+        for posting in &postings {
+            *self.balances.entry(posting.credit).or_default() += posting.amount;
+            *self.balances.entry(posting.debit).or_default() -= posting.amount;
+        }
+        if let Some(entries) = &mut self.entries {
+            entries.extend(postings.into_iter().map(|posting| JournalEntry {
+                seq,
+                tx,
+                tx_type: tx_type.to_string(),
+                debit: posting.debit,
+                credit: posting.credit,
+                amount: posting.amount,
+            }));
+        }
+    }
+
+    /// Entries posted since they are kept, in processing order
+    pub fn entries(&self) -> &[JournalEntry] {
+        self.entries.as_deref().unwrap_or_default()
+    }
+
+    /// Balances of every account the journal moved funds in or out of
+    pub fn balances(&self) -> &BTreeMap<LedgerAccount, Decimal> {
+        &self.balances
+    }
+
+    /// Balance of an account, credited minus debited
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - Ledger account
+    pub fn balance(&self, account: LedgerAccount) -> Decimal {
+        self.balances.get(&account).copied().unwrap_or_default()
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_balances() {
+        let mut journal = Journal::default();
+        journal.keep_entries();
+        let ten = Decimal::new(10, 0);
+        journal.post(
+            0,
+            1,
+            "deposit",
+            vec![Posting {
+                debit: LedgerAccount::HouseCash,
+                credit: LedgerAccount::Available(1),
+                amount: ten,
+            }],
+        );
+        journal.post(
+            1,
+            1,
+            "dispute",
+            vec![Posting {
+                debit: LedgerAccount::Available(1),
+                credit: LedgerAccount::Held(1),
+                amount: Decimal::new(4, 0),
+            }],
+        );
+        assert_eq!(journal.entries().len(), 2);
+        assert_eq!(
+            journal.balance(LedgerAccount::Available(1)),
+            Decimal::new(6, 0)
+        );
+        assert_eq!(journal.balance(LedgerAccount::Held(1)), Decimal::new(4, 0));
+        assert_eq!(journal.balance(LedgerAccount::HouseCash), -ten);
+        assert_eq!(journal.balance(LedgerAccount::Fees), Decimal::ZERO);
+        assert_eq!(journal.balances().values().sum::<Decimal>(), Decimal::ZERO);
+
+        // Without the entries, only the balances are kept
+        let mut balances = Journal::with_balances(journal.balances().clone());
+        balances.post(
+            2,
+            2,
+            "deposit",
+            journal.entries()[..1]
+                .iter()
+                .map(|entry| Posting {
+                    debit: entry.debit,
+                    credit: entry.credit,
+                    amount: entry.amount,
+                })
+                .collect(),
+        );
+        assert!(balances.entries().is_empty());
+        assert_eq!(
+            balances.balance(LedgerAccount::Available(1)),
+            Decimal::new(16, 0)
+        );
+        assert_eq!(
+            "client:1:held".parse::<LedgerAccount>(),
+            Ok(LedgerAccount::Held(1))
+        );
+        assert_eq!(
+            LedgerAccount::ChargebackLoss.to_string(),
+            "house:chargeback_loss"
+        );
+        assert_eq!(
+            "house:adjustments".parse::<LedgerAccount>(),
+            Ok(LedgerAccount::Adjustments)
+        );
+    }
+}
@@ -1,2 +1,7 @@
+pub mod adjustment;
 pub mod clients;
+pub mod hold;
+pub mod journal;
+pub mod registry;
+pub mod statement;
 pub mod transaction;
@@ -0,0 +1,69 @@
+use serde::{Deserialize, Deserializer};
+
+use crate::structs::clients::AccountStatus;
+
+// Registered client struct
+// A row of the client registry, the accounts opened ahead of their
+// transactions. Only the client id is required
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RegisteredClient {
+    pub client: u16,
+    #[serde(default)]
+    pub status: AccountStatus,
+    // Tier of the client, the limits of the tier apply to its account
+    #[serde(default)]
+    pub tier: Option<String>,
+    // Opening date, as YYYY-MM-DD
+    #[serde(default, deserialize_with = "date")]
+    pub opened: Option<String>,
+}
+
+// Reads an optional YYYY-MM-DD date, rejecting any other format
+fn date<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let date = Option::<String>::deserialize(deserializer)?.filter(|date| !date.is_empty());
+    match &date {
+        Some(value) if !valid_date(value) => Err(serde::de::Error::custom(format!(
+            "invalid date `{value}`, expected YYYY-MM-DD"
+        ))),
+        _ => Ok(date),
+    }
+}
+
+// Whether a date is a YYYY-MM-DD calendar date
+fn valid_date(date: &str) -> bool {
+    let parts: Vec<&str> = date.split('-').collect();
+    let [year, month, day] = parts[..] else {
+        return false;
+    };
+    if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return false;
+    }
+    let (Ok(year), Ok(month), Ok(day)) = (year.parse::<u32>(), month.parse(), day.parse()) else {
+        return false;
+    };
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return false,
+    };
+    (1..=days).contains(&day)
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_valid_date() {
+        assert!(valid_date("2024-02-29"));
+        assert!(!valid_date("2023-02-29"));
+        assert!(!valid_date("2024-13-01"));
+        assert!(!valid_date("2024-1-01"));
+        assert!(!valid_date("01/02/2024"));
+    }
+}
@@ -0,0 +1,154 @@
+use rust_decimal::prelude::*;
+use serde::Serialize;
+
+use crate::structs::{
+    clients::{AccountStatus, ClientAccount},
+    transaction::Transaction,
+};
+
+// Outcome of a transaction in a statement
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryStatus {
+    Applied,
+    Rejected,
+}
+
+// Statement entry struct
+// One entry is recorded for every processed transaction, with the
+// client's balances right after it was applied or rejected
+#[derive(Serialize, Clone, Debug)]
+pub struct StatementEntry {
+    seq: u64,
+    client: u16,
+    tx: u32,
+    #[serde(rename = "type")]
+    tx_type: String,
+    amount: Option<Decimal>,
+    status: EntryStatus,
+    reason: Option<String>,
+    available: Decimal,
+    held: Decimal,
+    pending: Decimal,
+    authorized: Decimal,
+    total: Decimal,
+    account_status: AccountStatus,
+    file: Option<String>,
+    line: Option<u64>,
+}
+
+// Statement entry implementation
+impl StatementEntry {
+    /// Returns a new StatementEntry with the running balances of the account
+    ///
+    /// # Arguments
+    ///
+    /// * `seq` - Position of the transaction in the processing order
+    /// * `transaction` - Transaction processed
+    /// * `amount` - Amount of the transaction, if any
+    /// * `reason` - Rejection reason, `None` when the transaction was applied
+    /// * `account` - Client's account after the transaction
+    pub fn new(
+        seq: u64,
+        transaction: &Transaction,
+        amount: Option<Decimal>,
+        reason: Option<String>,
+        account: &ClientAccount,
+    ) -> StatementEntry {
+        let provenance = transaction.provenance();
+        StatementEntry {
+            seq,
+            client: account.client(),
+            tx: transaction.tx(),
+            tx_type: transaction.clone().tx_type(),
+            amount,
+            status: match reason {
+                Some(_) => EntryStatus::Rejected,
+                None => EntryStatus::Applied,
+            },
+            reason,
+            available: account.available(),
+            held: account.held(),
+            pending: account.pending(),
+            authorized: account.authorized(),
+            total: account.total(),
+            account_status: account.status(),
+            file: provenance.map(|p| p.file.to_string()),
+            line: provenance.map(|p| p.line),
+        }
+    }
+
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    pub fn tx(&self) -> u32 {
+        self.tx
+    }
+
+    pub fn tx_type(&self) -> &str {
+        &self.tx_type
+    }
+
+    pub fn amount(&self) -> Option<Decimal> {
+        self.amount
+    }
+
+    pub fn status(&self) -> EntryStatus {
+        self.status
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    pub fn line(&self) -> Option<u64> {
+        self.line
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config::Policies;
+    use crate::structs::transaction::Provenance;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_new() {
+        let mut account = ClientAccount::new(7);
+        account
+            .deposit(Decimal::new(10, 0), &Policies::default())
+            .unwrap();
+        let mut transaction: Transaction =
+            csv::Reader::from_reader("type,client,tx,amount\nwithdrawal,7,3,20.0".as_bytes())
+                .deserialize()
+                .next()
+                .unwrap()
+                .unwrap();
+        transaction.set_provenance(Provenance {
+            file: Arc::from("day1.csv"),
+            line: 2,
+        });
+        let entry = StatementEntry::new(
+            1,
+            &transaction,
+            transaction.amount(),
+            Some("insufficient available funds".to_string()),
+            &account,
+        );
+        assert_eq!(entry.client(), 7);
+        assert_eq!(entry.tx, 3);
+        assert_eq!(entry.status, EntryStatus::Rejected);
+        assert_eq!(entry.available, Decimal::new(10, 0));
+        assert_eq!(entry.total, Decimal::new(10, 0));
+        assert_eq!(entry.file.as_deref(), Some("day1.csv"));
+        assert_eq!(entry.line, Some(2));
+    }
+}
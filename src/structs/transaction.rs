@@ -1,5 +1,13 @@
 use rust_decimal::prelude::*;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+// Where a transaction was read from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Provenance {
+    pub file: Arc<str>,
+    pub line: u64,
+}
 
 // Transaction struct
 #[derive(Clone, Debug, Deserialize)]
@@ -9,7 +17,15 @@ pub struct Transaction {
     client: u16,
     tx: u32,
     #[serde(deserialize_with = "csv::invalid_option")]
-    amount: Option<f32>,
+    amount: Option<Decimal>,
+    // Reason code and operator id of the manual adjustments and approvals,
+    // optional columns of the input
+    #[serde(default)]
+    reason_code: Option<String>,
+    #[serde(default)]
+    operator: Option<String>,
+    #[serde(skip)]
+    provenance: Option<Provenance>,
 }
 
 // Transaction implementation
@@ -26,23 +42,144 @@ impl Transaction {
         self.tx_type
     }
 
-    pub fn amount(&self) -> Option<f32> {
+    pub fn amount(&self) -> Option<Decimal> {
         self.amount
     }
+
+    pub fn reason_code(&self) -> Option<&str> {
+        self.reason_code.as_deref()
+    }
+
+    pub fn operator(&self) -> Option<&str> {
+        self.operator.as_deref()
+    }
+
+    pub fn provenance(&self) -> Option<&Provenance> {
+        self.provenance.as_ref()
+    }
+
+    pub fn set_provenance(&mut self, provenance: Provenance) {
+        self.provenance = Some(provenance);
+    }
 }
 
 // Transaction record struct
 // This struct is for internal storage and calculations
 // Transaction should be parsed into this stuct for use
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct TransactionRecord {
     amount: Decimal,
     client: u16,
     disputed: bool,
+    // Position of the transaction in the processing order
+    #[serde(default)]
+    seq: u64,
+    // State of a card authorization, `None` for deposits and withdrawals
+    #[serde(default)]
+    authorization: Option<AuthorizationState>,
+    // Whether the funds came in or went out of the account
+    #[serde(default)]
+    kind: RecordKind,
+    // Amount of a deposit already returned by refunds
+    #[serde(default)]
+    refunded: Decimal,
+    // Whether the transaction was charged back, it can't be disputed,
+    // refunded or charged back again
+    #[serde(default)]
+    charged_back: bool,
+    // Whether the transaction was applied, once it was processed
+    #[serde(default)]
+    status: RecordStatus,
+}
+
+// Outcome of the transaction of a record. Records are stored ahead of the
+// processing, only the applied ones can be referenced by other transactions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordStatus {
+    // Not processed yet
+    Pending,
+    // The records written before the status existed are applied ones
+    #[default]
+    Applied,
+    Rejected,
+}
+
+// Direction of the funds of a record
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordKind {
+    #[default]
+    Deposit,
+    // Withdrawals and card authorizations
+    Withdrawal,
+}
+
+// Lifecycle of a card authorization
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthorizationState {
+    // Funds reserved, waiting for the capture or void
+    Open,
+    // Finalized, the record amount is the captured one
+    Captured,
+    // Released by a void
+    Voided,
+    // Released once the expiry was reached
+    Expired,
 }
 
 // Transaction record implementation
 impl TransactionRecord {
+    /// Returns a new TransactionRecord
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - Id of the client owning the transaction
+    /// * `amount` - Rounded amount of the transaction
+    /// * `seq` - Position of the transaction in the processing order
+    pub fn new(client: u16, amount: Decimal, seq: u64) -> TransactionRecord {
+        TransactionRecord {
+            amount,
+            client,
+            disputed: false,
+            seq,
+            authorization: None,
+            kind: RecordKind::Deposit,
+            refunded: Decimal::ZERO,
+            charged_back: false,
+            status: RecordStatus::Pending,
+        }
+    }
+
+    /// Returns a new TransactionRecord for a withdrawal
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - Id of the client owning the transaction
+    /// * `amount` - Rounded amount of the transaction
+    /// * `seq` - Position of the transaction in the processing order
+    pub fn withdrawal(client: u16, amount: Decimal, seq: u64) -> TransactionRecord {
+        TransactionRecord {
+            kind: RecordKind::Withdrawal,
+            ..TransactionRecord::new(client, amount, seq)
+        }
+    }
+
+    /// Returns a new TransactionRecord for an open card authorization
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - Id of the client owning the transaction
+    /// * `amount` - Rounded amount reserved
+    /// * `seq` - Position of the transaction in the processing order
+    pub fn authorization(client: u16, amount: Decimal, seq: u64) -> TransactionRecord {
+        TransactionRecord {
+            authorization: Some(AuthorizationState::Open),
+            ..TransactionRecord::withdrawal(client, amount, seq)
+        }
+    }
+
     pub fn amount(self) -> Decimal {
         self.amount
     }
@@ -55,6 +192,59 @@ impl TransactionRecord {
         self.disputed
     }
 
+    pub fn seq(self) -> u64 {
+        self.seq
+    }
+
+    pub fn authorization_state(self) -> Option<AuthorizationState> {
+        self.authorization
+    }
+
+    pub fn kind(self) -> RecordKind {
+        self.kind
+    }
+
+    pub fn refunded(self) -> Decimal {
+        self.refunded
+    }
+
+    pub fn charged_back(self) -> bool {
+        self.charged_back
+    }
+
+    pub fn status(self) -> RecordStatus {
+        self.status
+    }
+
+    // Sets the outcome of the transaction once it was processed
+    pub fn settle(&mut self, applied: bool) {
+        self.status = match applied {
+            true => RecordStatus::Applied,
+            false => RecordStatus::Rejected,
+        };
+    }
+
+    // Amount a dispute holds, what wasn't refunded yet
+    pub fn disputable(self) -> Decimal {
+        self.amount - self.refunded
+    }
+
+    // Returns part of a deposit
+    pub fn refund(&mut self, amount: Decimal) {
+        self.refunded += amount;
+    }
+
+    // Finalizes an authorization, only the captured amount can be disputed
+    pub fn capture(&mut self, amount: Decimal) {
+        self.amount = amount;
+        self.authorization = Some(AuthorizationState::Captured);
+    }
+
+    // Closes an authorization without capturing it, voided or expired
+    pub fn release(&mut self, state: AuthorizationState) {
+        self.authorization = Some(state);
+    }
+
     pub fn dispute(&mut self) {
         self.disputed = true;
     }
@@ -62,19 +252,22 @@ impl TransactionRecord {
     pub fn resolve(&mut self) {
         self.disputed = false;
     }
+
+    // Closes a dispute with a chargeback, for good
+    pub fn charge_back(&mut self) {
+        self.disputed = false;
+        self.charged_back = true;
+    }
 }
 
 // From Trait implementation, to correct parse from Transaction
 impl From<&Transaction> for TransactionRecord {
     fn from(t: &Transaction) -> Self {
-        let am: f32 = t.amount().unwrap_or(0.0000);
-        TransactionRecord {
-            client: t.client,
-            disputed: false,
-            amount: Decimal::from_f32(am)
-                .unwrap_or(Decimal::new(0, 4))
-                .round_dp(4),
-        }
+        TransactionRecord::new(
+            t.client,
+            t.amount().unwrap_or(Decimal::new(0, 4)).round_dp(4),
+            0,
+        )
     }
 }
 
@@ -90,10 +283,44 @@ mod tests {
             client: 1,
             tx: 2,
             tx_type: "deposit".to_string(),
-            amount: Some(42.00),
+            amount: Some(Decimal::new(4200, 2)),
+            reason_code: None,
+            operator: None,
+            provenance: None,
         });
         assert_eq!(tr.client, tr.client());
-        assert_eq!(tr.disputed, false);
-        assert_eq!(tr.amount(), tr.amount());
+        assert!(!tr.disputed);
+        assert_eq!(tr.amount(), Decimal::new(42, 0));
+    }
+
+    #[test]
+    fn test_charge_back() {
+        let mut tr = TransactionRecord::new(1, Decimal::new(10, 0), 0);
+        tr.dispute();
+        tr.charge_back();
+        assert!(!tr.disputed());
+        assert!(tr.charged_back());
+        let json = serde_json::to_string(&tr).unwrap();
+        let restored: TransactionRecord = serde_json::from_str(&json).unwrap();
+        assert!(restored.charged_back());
+        // Records written before the charged back field existed read as not charged back
+        let legacy: TransactionRecord =
+            serde_json::from_str(r#"{"amount":"10","client":1,"disputed":false}"#).unwrap();
+        assert!(!legacy.charged_back());
+    }
+
+    #[test]
+    fn test_settle() {
+        let mut tr = TransactionRecord::new(1, Decimal::new(10, 0), 0);
+        assert_eq!(tr.status(), RecordStatus::Pending);
+        tr.settle(false);
+        assert_eq!(tr.status(), RecordStatus::Rejected);
+        let json = serde_json::to_string(&tr).unwrap();
+        let restored: TransactionRecord = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.status(), RecordStatus::Rejected);
+        // Records written before the status are applied ones
+        let legacy: TransactionRecord =
+            serde_json::from_str(r#"{"amount":"10","client":1,"disputed":false}"#).unwrap();
+        assert_eq!(legacy.status(), RecordStatus::Applied);
     }
 }